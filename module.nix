@@ -7,18 +7,23 @@ self:
 { pkgs, lib, config, ... }:
 let
   cfg = config.services.update-daemon;
-  repos = lib.concatLists [ (processGitLabRepos cfg.repos.gitlab) (processGitHubRepos cfg.repos.github) ];
+  repos = lib.concatLists [ (processGitLabRepos cfg.repos.gitlab) (processGitHubRepos cfg.repos.github) (processGiteaRepos cfg.repos.gitea) ];
   processGitHubRepos = repos: lib.concatLists (lib.mapAttrsToList (owner: lib.mapAttrsToList (repo: settings: {
     type = "github";
     inherit owner repo;
-  } // (extractUrls settings))) repos);
+  } // (extractHandleFields settings))) repos);
+  processGiteaRepos = repos: lib.concatLists (lib.mapAttrsToList (owner: lib.mapAttrsToList (repo: settings: {
+    type = "gitea";
+    inherit owner repo;
+  } // (extractHandleFields settings))) repos);
   processGitLabRepos = lib.mapAttrsToList (project: settings: {
     type = "gitlab";
     inherit project;
-  } // (extractUrls settings));
-  extractUrls = settings: { settings = builtins.removeAttrs settings [ "base_url" "ssh_url" ]; } //
+  } // (extractHandleFields settings));
+  extractHandleFields = settings: { settings = builtins.removeAttrs settings [ "base_url" "ssh_url" "enabled" ]; } //
     (lib.optionalAttrs (settings ? base_url) {inherit (settings) base_url; } ) //
-    (lib.optionalAttrs (settings ? ssh_url) {inherit (settings) ssh_url; } );
+    (lib.optionalAttrs (settings ? ssh_url) {inherit (settings) ssh_url; } ) //
+    (lib.optionalAttrs (settings ? enabled) {inherit (settings) enabled; } );
 in {
   options.services.update-daemon = with lib;
     with types; {
@@ -33,6 +38,7 @@ in {
         description = ''
           A file containing secrets:
           - GITHUB_TOKEN
+          - GITLAB_TOKEN and GITEA_TOKEN, for GitLab and Gitea/Forgejo repositories
           You can also set additional secrets to use them in agentSetup.
         '';
       };
@@ -51,7 +57,7 @@ in {
       repos = {
         github = mkOption {
           type = attrsOf (attrsOf (attrs));
-          description = "Github Repositories to update";
+          description = "Github Repositories to update. In all of these, set enabled = false on a repository to skip it while keeping its settings";
           default = { };
           example = { serokell.update-daemon = { }; };
         };
@@ -60,34 +66,41 @@ in {
           description = "Gitlab Repositories to update";
           default = { };
         };
+        gitea = mkOption {
+          type = attrsOf (attrsOf (attrs));
+          description = "Gitea and Forgejo repositories to update, each with the base_url of its instance";
+          default = { };
+          example = { serokell.update-daemon = { base_url = "https://codeberg.org"; }; };
+        };
       };
       extraRepos = mkOption {
         type = listOf attrs;
-        description = "Other repositories to update";
+        description = "Other repositories to update, and GitHub organizations to update all the repositories of";
         default = [  ];
+        example = [ { type = "github-org"; owner = "serokell"; include = [ "nix-*" ]; } ];
       };
       settings = {
         author = {
           name = mkOption {
             type = str;
-            description = "Name to use in commits";
+            description = "Name to use in commits. The pull requests are submitted by the account of the API token, which doesn't have to match the author";
             default = "Flake Update Bot";
           };
           email = mkOption {
             type = str;
-            description = "Email to use in commits";
+            description = "Email to use in commits. Commits on the update branch by other authors are considered human ones, and the update fails instead of overwriting them";
           };
         };
         update_branch = mkOption {
           type = str;
-          description = "The branch to push the updates to";
+          description = "The branch to push the updates to. The placeholders {{date}} (YYYY-MM-DD) and {{short_date}} (YYYYMMDD) are replaced with the day the run started, for a new branch and pull request every day (e.g. \"flake-update/{{date}}\")";
           default = "automatic-update";
         };
         default_branch = mkOption {
-          type = str;
+          type = nullOr str;
           description =
-            "The branch to base the update on and submit the pull request for";
-          default = "master";
+            "The branch to base the update on and submit the pull request for. If it isn't set, the branch the remote HEAD points to is used, or master if that can't be found out";
+          default = null;
         };
         title = mkOption {
           type = str;
@@ -100,7 +113,7 @@ in {
           default = "";
         };
         cooldown = mkOption {
-          type = int;
+          type = ints.positive;
           description = "Cooldown duration between updating pull requests (in milliseconds)";
           default = 100;
         };
@@ -117,13 +130,424 @@ in {
         };
         sign_commits = mkOption {
           type = bool;
-          description = "Whether to sign commits, the signing key must be available in gpg-agent under the root user";
+          description = "Whether to sign commits, see signing_method. An unsigned update commit is never pushed while this is set";
           default = false;
         };
         signing_key = mkOption {
           type = nullOr str;
-          description = "Signing key ID or fingerprint, if not set, the default key will be used";
+          description = "With gpg signing, the signing key ID or fingerprint, if not set, the default key will be used. With ssh signing, the path of the private key, or of the public key if the private one is in ssh-agent; ssh_key_path if not set";
+          default = null;
+        };
+        git_concurrency = mkOption {
+          type = nullOr int;
+          description = "Maximum number of repositories cloned, fetched or pushed at the same time, unlimited if not set";
+          default = null;
+        };
+        allowed_hosts = mkOption {
+          type = nullOr (listOf str);
+          description = "The only hosts update-daemon may contact, both with git and through the GitHub/GitLab API (e.g. [ \"github.com\" \"api.github.com\" ]). Repositories on other hosts fail without being contacted, and Discord and webhook notifications to other hosts are skipped. Any host may be contacted if not set";
+          default = null;
+        };
+        overall_timeout = mkOption {
+          type = nullOr int;
+          description = "Time in seconds after which the repositories still being updated are cancelled (on top of flake_check_timeout and flake_update_timeout), and update-daemon exits with 75 after listing them. Should be shorter than the time between updateDates; no limit if not set";
+          default = null;
+        };
+        gc_cache = mkOption {
+          type = nullOr bool;
+          description = "Before updating, remove the clones of repositories that aren't configured any more (and those older than cache_max_age_days) from the cache, as update-daemon gc does";
+          default = null;
+        };
+        cache_max_age_days = mkOption {
+          type = nullOr int;
+          description = "Days after which a clone that wasn't fetched is removed from the cache by gc_cache and update-daemon gc";
+          default = null;
+        };
+        cache_dir = mkOption {
+          type = nullOr str;
+          description = "Directory to keep the clones in, created if missing. The XDG cache directory (/root/.cache/update-daemon) if not set";
+          default = null;
+        };
+        schedule = mkOption {
+          type = nullOr str;
+          description = "Keep update-daemon running and update the repositories again on this schedule: either an interval after the end of each run (a number followed by s, m, h or d, like 6h), or a cron expression in UTC (like \"0 4 * * *\"). The service is then a long-running one and updateDates is ignored; runs once if not set";
+          default = null;
+          example = "6h";
+        };
+        shutdown_grace_period = mkOption {
+          type = nullOr int;
+          description = "Time in seconds the updates in progress are given to finish when update-daemon is stopped (with SIGTERM or SIGINT), after which they are cancelled. No more updates are started, and those that haven't committed yet stop before doing so. 60 if not set; the service's TimeoutStopSec is 30 seconds more";
+          default = null;
+        };
+        incremental_diff = mkOption {
+          type = bool;
+          description = "Whether to also show the changes since the previous update of the pull request. A snapshot of the reported lock is kept in a hidden comment in the pull request body";
+          default = false;
+        };
+        env = mkOption {
+          type = attrsOf (either str (submodule {
+            options.from_env = mkOption {
+              type = str;
+              description = "Name of the update-daemon's environment variable (e.g. one set in secretFile) to take the value from";
+            };
+          }));
+          description = "Environment variables to set for nix commands, in addition to the update-daemon's own environment";
+          default = { };
+          example = { NIX_CONFIG = "extra-substituters = https://cache.example.com"; PRIVATE_TOKEN = { from_env = "PRIVATE_TOKEN"; }; };
+        };
+        comment_only = mkOption {
+          type = bool;
+          description = "Instead of managing the update branch, only comment the flake.lock diff on an existing (e.g. manually opened) pull request. The pull request is the one with comment_label if it is set, or the one from update_branch otherwise";
+          default = false;
+        };
+        comment_label = mkOption {
+          type = nullOr str;
+          description = "Label of the pull request to comment on in comment_only mode";
+          default = null;
+        };
+        rev_display_length = mkOption {
+          type = ints.unsigned;
+          description = "Number of characters of revs and hashes to show in diffs, 0 to show them in full";
+          default = 10;
+        };
+        discord_webhook_env_var = mkOption {
+          type = nullOr str;
+          description = "Environment variable (e.g. set in secretFile) holding a Discord webhook URL to post updates and errors to";
+          default = null;
+        };
+        sequential_inputs = mkOption {
+          type = listOf str;
+          description = "Inputs to update one at a time, in this order: a single pull request updates one of them, and the next one with an update only gets its own once that pull request is merged or closed (as found through the forge API). The inputs take turns, starting after the input of the previous pull request and wrapping around to the first one, so an input that updates often doesn't hold back the others. The input of the last pull request is remembered in the clone of the repository";
+          default = [];
+          example = [ "nixpkgs" "haskell-nix" ];
+        };
+        pre_pr_delay = mkOption {
+          type = ints.unsigned;
+          description = "Delay between pushing the update branch and submitting the pull request (in seconds), e.g. for required status checks to register on the branch";
+          default = 0;
+        };
+        lock_filename = mkOption {
+          type = str;
+          description = "Name of the lock file to update, relative to the flake directory (the repository root, or flake_subdir)";
+          default = "flake.lock";
+        };
+        secondary_rate_limit_delay = mkOption {
+          type = ints.unsigned;
+          description = "Delay before retrying a GitHub request that hit a rate limit (in seconds). It doubles with every next retry, and up to a half of it is added at random";
+          default = 60;
+        };
+        rate_limit_retries = mkOption {
+          type = ints.unsigned;
+          description = "How many times to retry a GitHub request that hit a rate limit";
+          default = 2;
+        };
+        managed_pr_number = mkOption {
+          type = nullOr ints.unsigned;
+          description = "Number of an existing pull request to always update (along with the branch it is submitted from), instead of searching for or submitting one. Only makes sense per repository";
+          default = null;
+        };
+        eval_attr = mkOption {
+          type = nullOr str;
+          description = "Flake attribute to evaluate with nix eval after the update, reporting whether it still evaluates in the pull request body. The pull request is submitted either way";
+          default = null;
+          example = "packages.x86_64-linux.default";
+        };
+        search_head_prefix = mkOption {
+          type = bool;
+          description = "Whether to look for the existing pull request among all branches starting with update_branch, not just update_branch itself";
+          default = false;
+        };
+        search_any_base = mkOption {
+          type = bool;
+          description = "Whether to look for the existing pull request regardless of its base branch, e.g. when the default branch was renamed";
+          default = false;
+        };
+        extra_body_file = mkOption {
+          type = nullOr path;
+          description = "File with more text to add to the pull request body after extra_body, read on every update";
+          default = null;
+        };
+        sandbox_command = mkOption {
+          type = listOf str;
+          description = "Command to run nix in, e.g. a container or a bubblewrap sandbox. The nix command line is appended to it, and it is run in the repository's checkout, which the sandbox must give access to. Nix runs directly if empty";
+          default = [ ];
+        };
+        report_transitive_changes = mkOption {
+          type = bool;
+          description = "Whether to submit updates that change the lock file, but none of the root inputs (i.e. only update the inputs of the inputs). By default, these are treated as nothing to update";
+          default = false;
+        };
+        own_commit_emails = mkOption {
+          type = listOf str;
+          description = "Emails of commit authors other than author.email whose commits on the update branch may be overwritten, e.g. the previous author.email or the email the account of the API token commits with. When the update branch has commits by anyone else, update-daemon leaves it alone, without reporting an error";
+          default = [ ];
+        };
+        discussion_category = mkOption {
+          type = nullOr str;
+          description = "GitHub only: instead of submitting a pull request, start a discussion titled after title in this category, or update the open one. The update branch is still pushed";
+          default = null;
+          example = "Dependencies";
+        };
+        include_transitive = mkOption {
+          type = bool;
+          description = "Whether to also list the changes of the inputs of the inputs, named by their path from the root (e.g. foo/nixpkgs). Inputs shared via follows are listed once. The changes since the last update (with incremental_diff) only cover the root inputs";
+          default = false;
+        };
+        commit_trailer = mkOption {
+          type = nullOr str;
+          description = "Key of the trailers added to update commits, one per changed input (e.g. \"Bump: nixpkgs c601d56e19 -> d4ba2f8a12\"), for tooling that reads them with git interpret-trailers. No trailers are added if not set";
+          default = null;
+          example = "Bump";
+        };
+        release_inputs = mkOption {
+          type = listOf str;
+          description = "GitHub inputs that are only updated when a release was published since their locked revision, and the new revision includes it. Their updates between releases are held back";
+          default = [ ];
+        };
+        run_flake_check = mkOption {
+          type = bool;
+          description = "Whether to run nix flake check after the update, reporting the result (and the end of the output if it fails) in the pull request body. The pull request is submitted either way";
+          default = false;
+        };
+        flake_check_timeout = mkOption {
+          type = int;
+          description = "Time in seconds after which nix flake check is stopped and considered failed";
+          default = 3600;
+        };
+        flake_check_args = mkOption {
+          type = listOf str;
+          description = "Extra arguments of nix flake check";
+          default = [ ];
+          example = [ "--all-systems" ];
+        };
+        draft_on_failed_check = mkOption {
+          type = bool;
+          description = "Whether to submit the pull request as a draft when nix flake check fails. On GitHub, only new pull requests are made drafts; GitLab and Gitea merge/pull requests are marked as drafts by their title, and become ready again once the check passes";
+          default = false;
+        };
+        flake_update_timeout = mkOption {
+          type = int;
+          description = "Time in seconds after which nix flake update (with all the processes it started) is killed, failing the update. The output it has written until then is logged";
+          default = 3600;
+        };
+        default_ref = mkOption {
+          type = nullOr str;
+          description = "Tag or other ref (e.g. v1.2 or refs/pull/1/head) to base the update on instead of default_branch, which stays the branch the pull request is submitted for";
+          default = null;
+        };
+        extra_nix_args = mkOption {
+          type = listOf str;
+          description = "Extra arguments of every nix command, passed right after nix, so the arguments update-daemon adds (like --no-warn-dirty) take precedence. They are shown by --explain-commands, so pass secrets such as access-tokens with env.NIX_CONFIG.from_env instead";
+          default = [ ];
+          example = [ "--extra-experimental-features" "nix-command flakes" ];
+        };
+        min_lock_age_days = mkOption {
+          type = nullOr int;
+          description = "Only update repositories whose flake.lock has no root input modified (by its lastModified) within this many days, skipping the others for this pass. This spreads the updates over the passes of the daemon";
+          default = null;
+        };
+        nix_update_syntax = mkOption {
+          type = enum [ "new" "old" ];
+          description = "How the inputs to update are passed to nix: new for nix flake update <input>... (Nix 2.19 and later), old for nix flake lock --update-input <input>... (earlier versions)";
+          default = "new";
+        };
+        nix_options = mkOption {
+          type = attrsOf str;
+          description = "Nix options of every nix command, passed as --option <name> <value> (before extra_nix_args, which take precedence)";
+          default = { };
+          example = { extra-substituters = "https://cache.example.com"; extra-trusted-public-keys = "cache.example.com-1:AAAA..."; };
+        };
+        skip_if_no_lock = mkOption {
+          type = bool;
+          description = "Whether to skip repositories without a lock file (e.g. ones that aren't flakes) instead of failing and reporting the error. Lock files that can't be read or parsed are still reported";
+          default = false;
+        };
+        flake_subdir = mkOption {
+          type = nullOr str;
+          description = "Directory of the flake relative to the repository root, if it isn't the root itself. Nix commands run in it";
+          default = null;
+          example = "nix";
+        };
+        webhook_url = mkOption {
+          type = nullOr str;
+          description = "URL to POST a JSON payload to after each update and error: repo, outcome (updated or error), changes (as printed by diff-locks --format json, or null), url (of the pull request, or null), error (or null) and timestamp. Failures to post are only logged";
+          default = null;
+        };
+        webhook_headers = mkOption {
+          type = attrsOf (either str (submodule {
+            options.from_env = mkOption {
+              type = str;
+              description = "Name of the update-daemon's environment variable (e.g. one set in secretFile) to take the value from";
+            };
+          }));
+          description = "Headers of the webhook_url requests";
+          default = { };
+          example = { Authorization = { from_env = "WEBHOOK_AUTHORIZATION"; }; };
+        };
+        git_auth = mkOption {
+          type = nullOr (enum [ "ssh" "https" "https-token" ]);
+          description = "How git fetches and pushes: ssh uses the ssh agent; https (or its alias https-token) uses the HTTP(S) URL of the repository (derived from base_url on forges), authenticating with the API token for both fetching and pushing, without ssh. By default, forges are reached over ssh and git+none URLs with their own protocol";
+          default = null;
+        };
+        max_repo_size = mkOption {
+          type = nullOr ints.unsigned;
+          description = "Size in bytes of the data a clone of the repository may receive. Larger clones are aborted and cleaned up, failing the update";
+          default = null;
+        };
+        ssh_key_path = mkOption {
+          type = nullOr str;
+          description = "Private ssh key to authenticate with, instead of the keys of the ssh agent. The public key is taken from the file with .pub appended, if it exists";
+          default = null;
+        };
+        ssh_key_passphrase_file = mkOption {
+          type = nullOr str;
+          description = "File containing the passphrase of ssh_key_path, if it is encrypted";
+          default = null;
+        };
+        remote_name = mkOption {
+          type = str;
+          description = "Name of the git remote in the local clone, which is fetched from and pushed to";
+          default = "origin";
+        };
+        fork = mkOption {
+          type = nullOr (submodule {
+            options = {
+              owner = mkOption {
+                type = str;
+                description = "Owner of the fork";
+              };
+              repo = mkOption {
+                type = nullOr str;
+                description = "Name of the fork, the name of the updated repository by default";
+                default = null;
+              };
+              ssh_url = mkOption {
+                type = nullOr str;
+                description = "ssh_url of the fork, the one of the updated repository by default";
+                default = null;
+              };
+            };
+          });
+          description = "GitHub fork to push the update branch to, submitting the pull request from it (as owner:update_branch) to the updated repository. The update branch is based on the default branch of the updated repository, so the fork's own branches don't need to be kept in sync with it";
+          default = null;
+        };
+        labels = mkOption {
+          type = listOf str;
+          description = "Labels to add to the pull request when submitting or updating it, keeping the ones it already has. GitLab creates the missing labels; on GitHub and Gitea, failing to add them is only a warning";
+          default = [];
+        };
+        multiple_requests = mkOption {
+          type = enum [ "error" "update_first" "update_all" ];
+          description = "What to do when several open pull requests match the update one: fail with an error, update only one of them, or update all of them. Comments and error reports always go to a single one";
+          default = "update_first";
+        };
+        reviewers = mkOption {
+          type = listOf str;
+          description = "Usernames to request reviews from on newly submitted pull requests. Users that can't be requested are left out with a warning";
+          default = [];
+        };
+        assignees = mkOption {
+          type = listOf str;
+          description = "Usernames to assign newly submitted pull requests to. Users that can't be assigned are left out with a warning";
+          default = [];
+        };
+        draft = mkOption {
+          type = bool;
+          description = "Whether to submit new pull requests as drafts (on GitLab and Gitea, by prefixing their title). Existing pull requests stay drafts until someone marks them as ready, which updates don't undo. With run_flake_check and draft_on_failed_check, a failed check still makes existing GitLab and Gitea pull requests drafts as well";
+          default = false;
+        };
+        report_only_inputs = mkOption {
+          type = listOf str;
+          description = "Inputs whose updates are only logged, and reverted from the lock file instead of being proposed";
+          default = [];
+        };
+        close_when_empty = mkOption {
+          type = bool;
+          description = "Close the open update pull requests once the default branch has all the updates (e.g. after they were merged some other way). The pull request of managed_pr_number is never closed";
+          default = false;
+        };
+        delete_branch_when_empty = mkOption {
+          type = bool;
+          description = "Delete the update branch from the remote once the default branch has all the updates, independently of close_when_empty. The branch of managed_pr_number is never deleted";
+          default = false;
+        };
+        auto_merge = mkOption {
+          type = nullOr (enum [ "merge" "squash" "rebase" ]);
+          description = "Enable auto-merge, with this merge method, on newly submitted GitHub pull requests that aren't drafts. If the repository doesn't allow auto-merge, only a warning is logged";
+          default = null;
+        };
+        body_template = mkOption {
+          type = nullOr str;
+          description = "Template of the pull request body, with the placeholders {{diff_table}} (the changes), {{checks}} (the eval_attr and run_flake_check reports), {{timestamp}}, {{extra_body}} (with the contents of extra_body_file) and {{repo}}. Defaults to the changes, the reports, the timestamp and extra_body in this order";
+          default = null;
+        };
+        commit_message_template = mkOption {
+          type = nullOr str;
+          description = "Template of the update commit message, with the placeholder {{diff}} (the changes). The pull request title is still title. Defaults to title followed by the changes";
+          default = null;
+        };
+        signing_method = mkOption {
+          type = enum [ "gpg" "ssh" ];
+          description = "How to sign commits with sign_commits: with gpg, the signing key must be available in gpg-agent under the root user; with ssh, the key is used by ssh-keygen (from PATH), and must not need a passphrase unless it is in ssh-agent";
+          default = "gpg";
+        };
+        signing_passphrase_file = mkOption {
+          type = nullOr str;
+          description = "With gpg signing, a file containing the passphrase of the signing key, given to gpg-agent through loopback pinentry (which needs allow-loopback-pinentry, the default since GnuPG 2.1.12). If not set, gpg-agent must be able to sign without asking";
+          default = null;
+        };
+        report_errors = mkOption {
+          type = bool;
+          description = "Whether to report the failed updates with a comment on the pull request or an issue. If not, they are only logged (and sent to the webhooks)";
+          default = true;
+        };
+        report_errors_after = mkOption {
+          type = int;
+          description = "Number of runs in a row an update has to fail for before it is reported, to leave out transient failures. The failures are counted in the cache directory";
+          default = 1;
+        };
+        rebase_on_human_commits = mkOption {
+          type = bool;
+          description = "Whether to keep the commits pushed to the update branch by others (not by author or own_commit_emails), replaying them on top of the default branch if needed and committing the update on top of them. The update branch is left alone if they conflict with the default branch. Otherwise, the update branch is left alone as soon as its last commit is by someone else";
+          default = false;
+        };
+        min_input_age = mkOption {
+          type = nullOr int;
+          description = "Number of days: keep the old revision (and the nodes it depends on) of the inputs whose new one was modified (by its lastModified) more recently than that, while the other inputs are updated. This applies after inputs: with a non-empty inputs, only the listed inputs are updated, and those among them with too recent a revision are then held back, so an input listed in inputs may still stay pinned until one of its revisions is old enough. Inputs without lastModified are never held back. Unlike min_lock_age_days, this applies to each input separately";
+          default = null;
+        };
+        diff_display_filter = mkOption {
+          type = nullOr (submodule {
+            options = {
+              mode = mkOption {
+                type = enum [ "allow" "deny" ];
+                description = "Whether to show only the listed inputs (allow), or all but them (deny)";
+              };
+              inputs = mkOption {
+                type = listOf str;
+                description = "Names of the inputs";
+              };
+            };
+          });
+          description = "Which inputs to show in the diff table of pull requests (and comments and the summary file). The others are still updated and committed, and are listed in a collapsed table below it. The commit message lists all of them";
+          default = null;
+          example = { mode = "deny"; inputs = [ "flake-utils" "flake-compat" ]; };
+        };
+        diff_collapse_threshold = mkOption {
+          type = int;
+          description = "Number of inputs above which the diff table of pull requests (and comments and the summary file) is collapsed behind a summary line with their number. 0 to never collapse it. The commit message always lists all of them";
+          default = 10;
+        };
+        squash_history = mkOption {
+          type = bool;
+          description = "Whether to squash the update-daemon commits accumulated on the update branch into a single commit on top of the default branch, even when there is nothing new to update";
+          default = false;
+        };
+        input_label_template = mkOption {
+          type = nullOr str;
+          description = "With sequential_inputs, template of a label to add to the pull request in addition to labels, with the placeholder {{input}} (the input it updates)";
           default = null;
+          example = "dep:{{input}}";
         };
       };
     };
@@ -139,9 +563,12 @@ in {
     systemd.services.update-daemon = {
       description = "A daemon to update nix flakes";
       serviceConfig = {
-        Type = "oneshot";
+        Type = if cfg.settings.schedule == null then "oneshot" else "simple";
         EnvironmentFile = cfg.secretFile;
         User = "update-daemon";
+        TimeoutStopSec = (lib.defaultTo 60 cfg.settings.shutdown_grace_period) + 30;
+      } // lib.optionalAttrs (cfg.settings.schedule != null) {
+        Restart = "on-failure";
       };
       path = [ cfg.package ];
       script = ''
@@ -159,7 +586,8 @@ in {
           (builtins.toJSON (cfg.settings // { repos = repos ++ cfg.extraRepos; }))
         }
       '';
-      startAt = cfg.updateDates;
+      startAt = lib.mkIf (cfg.settings.schedule == null) cfg.updateDates;
+      wantedBy = lib.mkIf (cfg.settings.schedule != null) [ "multi-user.target" ];
     };
   };
 }
@@ -0,0 +1,288 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::info;
+use merge::Merge;
+use serde::{Deserialize, Deserializer};
+use std::path::PathBuf;
+use thiserror::Error;
+
+use super::request::{self, RequestError};
+use super::schedule::Schedule;
+use super::types::*;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub settings: UpdateSettingsOptional,
+    /// The repositories, and GitHub organizations to update all the repositories of
+    #[serde(rename = "repos")]
+    pub sources: Vec<RepoSource>,
+    /// The repositories, with those of the GitHub organizations once they are expanded
+    #[serde(skip)]
+    pub repos: Vec<Repo>,
+    /// Maximum number of simultaneous git clones, fetches and pushes
+    pub git_concurrency: Option<usize>,
+    /// Hosts that may be contacted, with git, the forge API or notifications; any host if unset
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Seconds after which the repositories that are still being updated are given up on
+    pub overall_timeout: Option<u64>,
+    /// Remove the stale clones from the cache before updating, as with the `gc` subcommand
+    pub gc_cache: Option<bool>,
+    /// Days after which a clone that hasn't been fetched is stale
+    pub cache_max_age_days: Option<u64>,
+    /// Directory to keep the clones in, instead of the XDG cache directory
+    pub cache_dir: Option<PathBuf>,
+    /// When to update the repositories again, staying alive in between; once if unset
+    pub schedule: Option<Schedule>,
+    /// Seconds the updates in progress are given to finish on SIGTERM or SIGINT
+    pub shutdown_grace_period: Option<u64>,
+}
+
+/// An entry of `repos`: a repository, or a GitHub organization
+#[derive(Debug, Clone)]
+pub enum RepoSource {
+    Repo(Repo),
+    GitHubOrg(GitHubOrg),
+}
+
+impl<'de> Deserialize<'de> for RepoSource {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Telling them apart by type keeps the errors about repositories precise,
+        // which an untagged enum wouldn't
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let source = if value.get("type").and_then(|t| t.as_str()) == Some("github-org") {
+            GitHubOrg::deserialize(value).map(RepoSource::GitHubOrg)
+        } else {
+            Repo::deserialize(value).map(RepoSource::Repo)
+        };
+        source.map_err(serde::de::Error::custom)
+    }
+}
+
+/// A GitHub organization, all the repositories of which are updated
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitHubOrg {
+    pub base_url: Option<String>,
+    pub ssh_url: Option<String>,
+    pub token_env_var: Option<String>,
+    /// File to read the API token from, instead of the environment variable
+    pub token_file: Option<PathBuf>,
+    pub owner: String,
+    /// Globs (with `*` and `?`) the names of the repositories must match, any name if empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs the names of the repositories must not match
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Whether to skip the archived repositories, true if not set
+    pub skip_archived: Option<bool>,
+    /// Settings of all the repositories. `skip_if_no_lock` is true unless set here.
+    pub settings: Option<UpdateSettingsOptional>,
+    pub enabled: Option<bool>,
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any characters and `?` any one
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last `*`, with it matching one more character
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl GitHubOrg {
+    /// Whether the repository named `name` is included by `include` and `exclude`
+    pub fn includes(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob_matches(glob, name)))
+            && !self.exclude.iter().any(|glob| glob_matches(glob, name))
+    }
+
+    /// The repository named `name` in the organization
+    fn repo(&self, name: String) -> Repo {
+        let mut settings = self.settings.clone().unwrap_or_default();
+        settings.skip_if_no_lock.get_or_insert(true);
+        Repo {
+            settings: Some(settings),
+            handle: RepoHandle::GitHub {
+                base_url: self.base_url.clone(),
+                ssh_url: self.ssh_url.clone(),
+                token_env_var: self.token_env_var.clone(),
+                token_file: self.token_file.clone(),
+                owner: self.owner.clone(),
+                repo: name,
+            },
+            enabled: self.enabled,
+        }
+    }
+}
+
+impl Config {
+    /// Add the repositories of the GitHub organizations in `sources` to `repos`, except the
+    /// forks, the archived ones with `skip_archived` and those listed on their own
+    pub async fn expand_github_orgs(&mut self) -> Result<(), ExpandOrgsError> {
+        for source in &self.sources {
+            let RepoSource::GitHubOrg(org) = source else {
+                continue;
+            };
+            check_host(
+                self.allowed_hosts.as_deref(),
+                url_host(org.base_url.as_deref().unwrap_or("https://api.github.com")),
+            )
+            .map_err(|e| ExpandOrgsError::HostNotAllowed(org.owner.clone(), e))?;
+            let mut added = 0;
+            let repos = request::github_org_repositories(
+                org.base_url.clone(),
+                &org.owner,
+                org.token_env_var.clone(),
+                org.token_file.clone(),
+            )
+            .await?;
+            for repo in repos {
+                let listed = self.repos.iter().any(|listed| {
+                    matches!(&listed.handle, RepoHandle::GitHub { owner, repo: name, .. }
+                        if owner.eq_ignore_ascii_case(&org.owner) && name.eq_ignore_ascii_case(&repo.name))
+                });
+                if repo.fork
+                    || (repo.archived && org.skip_archived.unwrap_or(true))
+                    || !org.includes(&repo.name)
+                    || listed
+                {
+                    continue;
+                }
+                self.repos.push(org.repo(repo.name));
+                added += 1;
+            }
+            info!(
+                "Found {} repositories to update in the GitHub organization {}",
+                added, org.owner
+            );
+        }
+        Ok(())
+    }
+
+    /// Settings of `repo`, with the missing ones taken from the top level
+    pub fn repo_settings(&self, repo: &Repo) -> UpdateSettingsOptional {
+        let mut settings = repo.settings.clone().unwrap_or_default();
+        settings.merge(self.settings.clone());
+        settings
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ExpandOrgsError {
+    #[error("Not listing the repositories of {0}: {1}")]
+    HostNotAllowed(String, HostNotAllowed),
+    #[error("{0}")]
+    Request(#[from] RequestError),
+}
+
+#[derive(Debug, Error)]
+pub enum ReadConfigError {
+    #[error("Error reading the configuration file: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Error fetching the configuration: {0}")]
+    Fetch(#[from] reqwest::Error),
+    #[error("Couldn't get the authorization header from env var: {0}")]
+    AuthHeader(#[from] std::env::VarError),
+    #[error("Host {0} is not given with --config-allowed-host")]
+    HostNotAllowed(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ParseConfigError {
+    #[error(
+        "Unknown configuration format of {0}, supported formats are .json, .toml, .yaml and .yml"
+    )]
+    UnknownFormat(String),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("{0}")]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+/// Names of the configuration file looked for in the XDG config directories, in order
+pub const CONFIG_FILE_NAMES: [&str; 4] = [
+    "update-daemon/config.json",
+    "update-daemon/config.toml",
+    "update-daemon/config.yaml",
+    "update-daemon/config.yml",
+];
+
+/// Parse the configuration read from `source`, in the format given by its extension
+pub fn parse_config(source: &str, contents: &str) -> Result<Config, ParseConfigError> {
+    // Ignore the query and fragment of URLs
+    let path = source.split(['?', '#']).next().unwrap_or(source);
+    match std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some("json") => Ok(serde_json::from_str(contents)?),
+        Some("toml") => Ok(toml::from_str(contents)?),
+        Some("yaml" | "yml") => Ok(serde_yaml::from_str(contents)?),
+        _ => Err(ParseConfigError::UnknownFormat(source.to_string())),
+    }
+    .map(|mut config: Config| {
+        config.repos = config
+            .sources
+            .iter()
+            .filter_map(|source| match source {
+                RepoSource::Repo(repo) => Some(repo.clone()),
+                RepoSource::GitHubOrg(_) => None,
+            })
+            .collect();
+        config
+    })
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Read the configuration from a local file or, if `source` is an http(s) URL, fetch it.
+/// When fetching, the value of `auth_env_var` (if set) is sent as the `Authorization` header,
+/// and the host must be one of `allowed_hosts` (if set).
+pub async fn read_config(
+    source: &str,
+    auth_env_var: Option<String>,
+    allowed_hosts: Option<&[String]>,
+) -> Result<String, ReadConfigError> {
+    if is_url(source) {
+        check_host(allowed_hosts, url_host(source))
+            .map_err(|HostNotAllowed(host)| ReadConfigError::HostNotAllowed(host))?;
+        let mut request = reqwest::Client::new().get(source);
+        if let Some(var) = auth_env_var {
+            request = request.header(reqwest::header::AUTHORIZATION, std::env::var(var)?);
+        }
+        Ok(request.send().await?.error_for_status()?.text().await?)
+    } else {
+        Ok(std::fs::read_to_string(source)?)
+    }
+}
@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+#[test]
+fn globs_match_names() {
+    assert!(glob_matches("nix-*", "nix-tools"));
+    assert!(glob_matches("*", ""));
+    assert!(glob_matches("*-*-bot", "update-daemon-ci-bot"));
+    assert!(glob_matches("repo?", "repo1"));
+    assert!(!glob_matches("repo?", "repo"));
+    assert!(!glob_matches("nix-*", "tools-nix"));
+    assert!(!glob_matches("*.nix", "flake.lock"));
+}
+
+#[test]
+fn github_org_entries_are_kept_apart_from_repos() {
+    let config = parse_config(
+        "config.json",
+        r#"{
+            "repos": [
+                { "type": "github", "owner": "serokell", "repo": "update-daemon" },
+                {
+                    "type": "github-org",
+                    "owner": "serokell",
+                    "include": ["nix-*"],
+                    "exclude": ["nix-old"],
+                    "settings": { "update_branch": "bump" }
+                }
+            ]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(config.sources.len(), 2);
+    assert_eq!(config.repos.len(), 1);
+
+    let org = match &config.sources[1] {
+        RepoSource::GitHubOrg(org) => org,
+        RepoSource::Repo(repo) => panic!("{} isn't an organization", repo.handle),
+    };
+    assert!(org.includes("nix-tools"));
+    assert!(!org.includes("nix-old"));
+    assert!(!org.includes("update-daemon"));
+
+    let settings = org.repo("nix-tools".to_string()).settings.unwrap();
+    assert_eq!(settings.skip_if_no_lock, Some(true));
+    assert_eq!(settings.update_branch.as_deref(), Some("bump"));
+}
+
+#[test]
+fn invalid_github_org_entries_are_reported() {
+    let error = parse_config(
+        "config.json",
+        r#"{ "repos": [{ "type": "github-org", "include": [] }] }"#,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("owner"), "{}", error);
+}
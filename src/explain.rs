@@ -0,0 +1,59 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Rendering of the operations update-daemon performs as equivalent shell commands,
+//! for `--explain-commands`.
+
+use std::collections::HashMap;
+use std::process::Command;
+
+use log::info;
+
+use super::types::EnvValue;
+
+/// Quote an argument for a POSIX shell, if needed
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=+@%,".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Render a command the way it would be typed in a shell
+pub fn render_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Render environment variable assignments to prefix a command with,
+/// without revealing the values taken from update-daemon's environment
+pub fn render_env(env: &HashMap<String, EnvValue>) -> String {
+    let mut assignments = env
+        .iter()
+        .map(|(name, value)| match value {
+            EnvValue::Value(value) => format!("{}={} ", name, shell_quote(value)),
+            EnvValue::FromEnv { from_env } => format!("{}=\"${}\" ", name, from_env),
+        })
+        .collect::<Vec<_>>();
+    assignments.sort();
+    assignments.concat()
+}
+
+/// Log an operation (a `$ command` or an API call description) that is performed
+/// (or, if `skipped`, would have been performed) for `name`
+pub fn explain(name: &str, operation: &str, skipped: bool) {
+    if skipped {
+        info!("{}: (dry run, skipped) {}", name, operation);
+    } else {
+        info!("{}: {}", name, operation);
+    }
+}
@@ -3,7 +3,9 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use indexmap::map::IndexMap;
-use serde::{Deserialize, Serialize};
+use log::warn;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 use thiserror::Error;
@@ -45,9 +47,33 @@ pub enum Locked {
         r#type: String,
         owner: Option<String>,
         repo: Option<String>,
+        /// Host of self-hosted forges, such as Gitea
+        #[serde(skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+        /// URL of generic `git` inputs
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
         rev: String,
         nar_hash: String,
         last_modified: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        submodules: Option<bool>,
+    },
+    /// `path` inputs. Relative paths are locked without a narHash.
+    #[serde(rename_all = "camelCase")]
+    Path {
+        path: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nar_hash: Option<String>,
+        last_modified: Option<i64>,
+    },
+    /// `indirect` inputs, which are normally resolved through the registry before locking
+    #[serde(rename_all = "camelCase")]
+    Indirect {
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nar_hash: Option<String>,
+        last_modified: Option<i64>,
     },
     #[serde(rename_all = "camelCase")]
     Other {
@@ -57,12 +83,67 @@ pub enum Locked {
 }
 
 impl Locked {
+    fn submodules(&self) -> bool {
+        match self {
+            Locked::Git { submodules, .. } => submodules.unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Owner, repo and rev of GitHub inputs
+    pub fn github_rev(&self) -> Option<(&str, &str, &str)> {
+        match self {
+            Locked::Git {
+                r#type,
+                owner: Some(owner),
+                repo: Some(repo),
+                rev,
+                ..
+            } if r#type == "github" => Some((owner, repo, rev)),
+            _ => None,
+        }
+    }
+
+    pub fn last_modified(&self) -> Option<i64> {
+        match self {
+            Locked::Git { last_modified, .. } => *last_modified,
+            Locked::Path { last_modified, .. } => *last_modified,
+            Locked::Indirect { last_modified, .. } => *last_modified,
+            Locked::Other { last_modified, .. } => *last_modified,
+        }
+    }
+
+    /// The narHash, or what identifies the contents of inputs locked without one
     fn get_hash(self) -> String {
         match self {
             Locked::Git { nar_hash, .. } => nar_hash,
+            Locked::Path { nar_hash, path, .. } => nar_hash.unwrap_or(path),
+            Locked::Indirect { nar_hash, id, .. } => nar_hash.unwrap_or(id),
             Locked::Other { nar_hash, .. } => nar_hash,
         }
     }
+
+    fn nar_hash(&self) -> Option<&str> {
+        match self {
+            Locked::Git { nar_hash, .. } => Some(nar_hash),
+            Locked::Path { nar_hash, .. } => nar_hash.as_deref(),
+            Locked::Indirect { nar_hash, .. } => nar_hash.as_deref(),
+            Locked::Other { nar_hash, .. } => Some(nar_hash),
+        }
+    }
+
+    fn rev(&self) -> Option<&str> {
+        match self {
+            Locked::Git { rev, .. } => Some(rev),
+            _ => None,
+        }
+    }
+
+    /// Whether `self` and `other` lock the same contents at the same revision.
+    /// A changed rev counts even if the narHash stays the same.
+    fn is_same_as(&self, other: &Locked) -> bool {
+        self.clone().get_hash() == other.clone().get_hash() && self.rev() == other.rev()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -119,6 +200,142 @@ impl Lock {
         self.get_dep(self.root_deps()?.get(&name)?.clone())
     }
 
+    /// The most recent `lastModified` of the root inputs, if any of them has it
+    pub fn newest_last_modified(&self) -> Option<i64> {
+        self.root_deps()?
+            .into_values()
+            .filter_map(|input| self.get_dep(input)?.last_modified())
+            .max()
+    }
+
+    /// A minimal lock containing only the root node and the nodes of its direct inputs.
+    /// It is much smaller than the full lock, but diffs against it are the same.
+    pub fn root_snapshot(&self) -> Option<Lock> {
+        let mut nodes = IndexMap::new();
+        let mut root_inputs = IndexMap::new();
+        for (name, input) in self.root_deps()? {
+            if let Some(node) = self.resolve_input(input).and_then(|n| self.nodes.get(&n)) {
+                root_inputs.insert(name.clone(), Input::Simple(name.clone()));
+                nodes.insert(
+                    name,
+                    Node {
+                        flake: node.flake,
+                        locked: node.locked.clone(),
+                        inputs: None,
+                    },
+                );
+            }
+        }
+        nodes.insert(
+            self.root.clone(),
+            Node {
+                flake: None,
+                locked: None,
+                inputs: Some(root_inputs),
+            },
+        );
+        Some(Lock {
+            nodes,
+            version: self.version,
+            root: self.root.clone(),
+        })
+    }
+
+    /// Every node reachable from the root, keyed by the path of inputs leading to it
+    /// (e.g. `foo/nixpkgs`). Nodes shared via `follows` are only listed once, under
+    /// their shortest path.
+    fn reachable_nodes(&self) -> Result<IndexMap<String, String>, LockDiffError> {
+        let mut reachable = IndexMap::new();
+        let mut seen = std::collections::HashSet::from([self.root.clone()]);
+        let mut queue = std::collections::VecDeque::from([(Vec::new(), self.root.clone())]);
+        while let Some((path, name)) = queue.pop_front() {
+            let node = self
+                .nodes
+                .get(&name)
+                .ok_or(LockDiffError::MissingRootNode)?;
+            for (input_name, input) in node.inputs.iter().flatten() {
+                let target = self.resolve_input(input.clone()).ok_or_else(|| {
+                    LockDiffError::MissingNodeError(input_name.clone(), name.clone())
+                })?;
+                if !seen.insert(target.clone()) {
+                    continue;
+                }
+                let mut input_path: Vec<String> = path.clone();
+                input_path.push(input_name.clone());
+                reachable.insert(input_path.join("/"), target.clone());
+                queue.push_back((input_path, target));
+            }
+        }
+        Ok(reachable)
+    }
+
+    /// The locked node at `path` of inputs from the root, e.g. `foo/nixpkgs`
+    fn get_dep_by_path(&self, path: &str) -> Option<Locked> {
+        let path = path.split('/').map(str::to_string).collect();
+        self.nodes
+            .get(&self.get_input_by_path(self.root.clone(), path)?)?
+            .locked
+            .clone()
+    }
+
+    /// Like `diff`, but also reports the changes of the inputs of the inputs,
+    /// keyed by their path from the root (e.g. `foo/nixpkgs`)
+    pub fn diff_all(&self, new: &Self) -> Result<LockDiff, LockDiffError> {
+        let mut diff: IndexMap<String, InputChange> = IndexMap::new();
+
+        for (path, name) in new.reachable_nodes()? {
+            // Nodes without a locked source (e.g. the root) have nothing to compare
+            let value_a = match new.nodes.get(&name).and_then(|node| node.locked.clone()) {
+                Some(value_a) => value_a,
+                None => continue,
+            };
+            match self.get_dep_by_path(&path) {
+                Some(value_b) => {
+                    if !value_a.is_same_as(&value_b) {
+                        diff.insert(
+                            path,
+                            InputChange::Update {
+                                old: value_b,
+                                new: value_a,
+                            },
+                        );
+                    }
+                }
+                None => {
+                    diff.insert(path, InputChange::Add(value_a));
+                }
+            }
+        }
+        for (path, _) in self.reachable_nodes()? {
+            if new.get_dep_by_path(&path).is_none() {
+                diff.insert(path, InputChange::Delete);
+            }
+        }
+        Ok(LockDiff(diff))
+    }
+
+    /// Check that the root node and every node referenced in `inputs` are in the lockfile
+    pub fn validate(&self) -> Result<(), LockDiffError> {
+        if !self.nodes.contains_key(&self.root) {
+            return Err(LockDiffError::MissingRootNode);
+        }
+        for (name, node) in &self.nodes {
+            for input in node.inputs.iter().flat_map(|inputs| inputs.values()) {
+                let missing = match input {
+                    Input::Simple(target) => target.clone(),
+                    Input::Follows(path) => path.join("/"),
+                };
+                if !self
+                    .resolve_input(input.clone())
+                    .is_some_and(|target| self.nodes.contains_key(&target))
+                {
+                    return Err(LockDiffError::MissingNodeError(missing, name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn diff(&self, new: &Self) -> Result<LockDiff, LockDiffError> {
         let mut diff: IndexMap<String, InputChange> = IndexMap::new();
 
@@ -129,7 +346,7 @@ impl Lock {
 
             match self.get_root_dep(key.clone()) {
                 Some(value_b) => {
-                    if value_a.clone().get_hash() != value_b.clone().get_hash() {
+                    if !value_a.is_same_as(&value_b) {
                         diff.insert(
                             key,
                             InputChange::Update {
@@ -166,20 +383,224 @@ pub enum GetLockError {
     IOError(#[from] std::io::Error),
     #[error("Failed to parse flake.lock: {0}")]
     ParseError(#[from] serde_json::Error),
+    #[error("Failed to parse flake.lock of version {0}, only versions 5 to 7 are supported: {1}")]
+    UnsupportedVersion(u64, serde_json::Error),
+    #[error("Invalid flake.lock: {0}")]
+    Invalid(#[from] LockDiffError),
+}
+
+/// The versions of the lockfile format that are known to parse. They only differ in
+/// what Nix accepts in them, not in their layout.
+const SUPPORTED_VERSIONS: std::ops::RangeInclusive<u64> = 5..=7;
+
+/// The version of the lockfile `contents`, even if it doesn't parse as a `Lock`
+fn lock_version(contents: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(contents)
+        .ok()?
+        .get("version")?
+        .as_u64()
+}
+
+/// Parse and validate the contents of a lockfile. Unknown versions are parsed
+/// with the layout of the supported ones, if possible.
+pub fn parse_lock(contents: &str) -> Result<Lock, GetLockError> {
+    let version = lock_version(contents);
+    let lock: Lock = serde_json::from_str(contents).map_err(|e| match version {
+        Some(version) if !SUPPORTED_VERSIONS.contains(&version) => {
+            GetLockError::UnsupportedVersion(version, e)
+        }
+        _ => GetLockError::ParseError(e),
+    })?;
+    if !SUPPORTED_VERSIONS.contains(&(lock.version as u64)) {
+        warn!(
+            "flake.lock has version {}, which update-daemon doesn't know (it knows 5 to 7), the changes may be misreported",
+            lock.version
+        );
+    }
+    lock.validate()?;
+    Ok(lock)
+}
+
+/// Read and validate the lockfile at `path`
+pub fn read_lock(path: &std::path::Path) -> Result<Lock, GetLockError> {
+    parse_lock(std::fs::read_to_string(path)?.as_str())
 }
 
-pub fn get_lock(repo: &std::path::Path) -> Result<Lock, GetLockError> {
-    let mut repo = repo.to_path_buf();
-    repo.push("flake.lock");
-    Ok(serde_json::from_str(
-        std::fs::read_to_string(repo)?.as_str(),
-    )?)
+type JsonNodes = serde_json::Map<String, serde_json::Value>;
+
+/// Names of the nodes referenced by `node` directly, as opposed to following a path
+fn direct_inputs(node: &serde_json::Value) -> Vec<String> {
+    node.get("inputs")
+        .and_then(|inputs| inputs.as_object())
+        .map(|inputs| {
+            inputs
+                .values()
+                .filter_map(|input| input.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Remove the nodes that can't be reached from `root`
+fn remove_unreachable(nodes: &mut JsonNodes, root: &str) {
+    let mut reachable = std::collections::HashSet::new();
+    let mut queue = vec![root.to_string()];
+    while let Some(name) = queue.pop() {
+        if let Some(node) = nodes.get(&name) {
+            if reachable.insert(name) {
+                queue.extend(direct_inputs(node));
+            }
+        }
+    }
+    nodes.retain(|name, _| reachable.contains(name));
+}
+
+/// Copy the node `name` of `from`, and the nodes it references, to `to`. A node is renamed
+/// if `to` has a different node of the same name. Returns its name in `to`.
+fn copy_node(
+    from: &JsonNodes,
+    to: &mut JsonNodes,
+    name: &str,
+    parent: &str,
+    copied: &mut HashMap<String, String>,
+) -> Result<String, LockDiffError> {
+    if let Some(new_name) = copied.get(name) {
+        return Ok(new_name.clone());
+    }
+    let mut node = from
+        .get(name)
+        .cloned()
+        .ok_or_else(|| LockDiffError::MissingNodeError(name.to_string(), parent.to_string()))?;
+    if let Some(inputs) = node
+        .get_mut("inputs")
+        .and_then(|inputs| inputs.as_object_mut())
+    {
+        for input in inputs.values_mut() {
+            if let Some(target) = input.as_str().map(String::from) {
+                *input = copy_node(from, to, &target, name, copied)?.into();
+            }
+        }
+    }
+    let mut new_name = name.to_string();
+    let mut suffix = 2;
+    while to.get(&new_name).is_some_and(|existing| *existing != node) {
+        new_name = format!("{}_{}", name, suffix);
+        suffix += 1;
+    }
+    to.insert(new_name.clone(), node);
+    copied.insert(name.to_string(), new_name.clone());
+    Ok(new_name)
+}
+
+/// Lock the root inputs `inputs` of the lockfile `after` as in the lockfile `before` again,
+/// together with the nodes they reference, leaving the other inputs as they are.
+/// Returns the contents of the resulting lockfile.
+pub fn revert_inputs(before: &str, after: &str, inputs: &[String]) -> Result<String, GetLockError> {
+    let before: serde_json::Value = serde_json::from_str(before)?;
+    let mut after: serde_json::Value = serde_json::from_str(after)?;
+    let root_of = |lock: &serde_json::Value| {
+        lock.get("root")
+            .and_then(|root| root.as_str())
+            .map(String::from)
+            .ok_or(LockDiffError::MissingRootNode)
+    };
+    let (before_root, after_root) = (root_of(&before)?, root_of(&after)?);
+    let before_nodes = before
+        .get("nodes")
+        .and_then(|nodes| nodes.as_object())
+        .ok_or(LockDiffError::MissingRootNode)?;
+    let after_nodes = after
+        .get_mut("nodes")
+        .and_then(|nodes| nodes.as_object_mut())
+        .ok_or(LockDiffError::MissingRootNode)?;
+    let before_inputs = before_nodes
+        .get(&before_root)
+        .and_then(|root| root.get("inputs"))
+        .and_then(|inputs| inputs.as_object())
+        .ok_or(LockDiffError::MissingRootNode)?;
+
+    // Drop the updated nodes first, so that the reverted ones can keep their names
+    let mut reverted = Vec::new();
+    for input in inputs {
+        let Some(old) = before_inputs.get(input) else {
+            continue;
+        };
+        let root_inputs = after_nodes
+            .get_mut(&after_root)
+            .and_then(|root| root.get_mut("inputs"))
+            .and_then(|inputs| inputs.as_object_mut())
+            .ok_or(LockDiffError::MissingRootNode)?;
+        root_inputs.remove(input);
+        reverted.push((input, old.clone()));
+    }
+    remove_unreachable(after_nodes, &after_root);
+
+    let mut copied = HashMap::new();
+    for (input, mut old) in reverted {
+        if let Some(target) = old.as_str().map(String::from) {
+            old = copy_node(
+                before_nodes,
+                after_nodes,
+                &target,
+                &before_root,
+                &mut copied,
+            )?
+            .into();
+        }
+        // Follows are paths from the root, which stay valid
+        if let Some(root_inputs) = after_nodes
+            .get_mut(&after_root)
+            .and_then(|root| root.get_mut("inputs"))
+            .and_then(|inputs| inputs.as_object_mut())
+        {
+            root_inputs.insert(input.clone(), old);
+        }
+    }
+    Ok(serde_json::to_string_pretty(&after)? + "\n")
 }
 
 impl LockDiff {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// The change of `input`, if it changed
+    pub fn get(&self, input: &str) -> Option<&InputChange> {
+        self.0.get(input)
+    }
+
+    /// Names of the changed inputs
+    pub fn inputs(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    /// The changes of `inputs` only
+    pub fn only(&self, inputs: &[String]) -> LockDiff {
+        LockDiff(
+            self.0
+                .iter()
+                .filter(|(input, _)| inputs.contains(input))
+                .map(|(input, change)| (input.clone(), change.clone()))
+                .collect(),
+        )
+    }
+
+    /// The updates to revisions last modified after `timestamp`. Those without
+    /// `lastModified` are left out.
+    pub fn updated_after(&self, timestamp: i64) -> LockDiff {
+        LockDiff(
+            self.0
+                .iter()
+                .filter(|(_, change)| match change {
+                    InputChange::Update { new, .. } => new
+                        .last_modified()
+                        .is_some_and(|modified| modified > timestamp),
+                    _ => false,
+                })
+                .map(|(input, change)| (input.clone(), change.clone()))
+                .collect(),
+        )
+    }
 }
 
 fn format_date(date: i64) -> String {
@@ -191,35 +612,201 @@ fn format_date(date: i64) -> String {
     datetime.format("%Y-%m-%d").to_string()
 }
 
-fn show_hash_and_date(
-    f: &mut Formatter,
-    hash: &str,
-    last_modified: &Option<i64>,
-) -> Result<(), std::fmt::Error> {
+/// How locked inputs are displayed in diffs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayOptions {
+    /// Number of characters of revs and hashes to show, 0 to show them in full
+    pub rev_length: usize,
+    /// The inputs shown in markdown tables, the others are collapsed below them
+    pub filter: Option<DiffDisplayFilter>,
+    /// Number of rows above which markdown tables are collapsed, 0 to never collapse them
+    pub collapse_threshold: usize,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        DisplayOptions {
+            rev_length: 10,
+            filter: None,
+            collapse_threshold: 0,
+        }
+    }
+}
+
+/// Whether to show only `inputs`, or all but them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffFilterMode {
+    Allow,
+    Deny,
+}
+
+/// Inputs to show in the markdown tables of diffs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DiffDisplayFilter {
+    pub mode: DiffFilterMode,
+    pub inputs: Vec<String>,
+}
+
+impl DiffDisplayFilter {
+    pub fn shows(&self, input: &str) -> bool {
+        let listed = self.inputs.iter().any(|listed| listed == input);
+        match self.mode {
+            DiffFilterMode::Allow => listed,
+            DiffFilterMode::Deny => !listed,
+        }
+    }
+}
+
+/// The ways a `LockDiff` can be rendered as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffRendering {
+    /// A markdown table, see `LockDiff::markdown`
+    Markdown,
+    /// Aligned lines, see `LockDiff::spaced`
+    Spaced,
+    /// An ASCII box table, see `LockDiff::table`
+    Table,
+}
+
+fn show_hash_and_date(hash: &str, last_modified: &Option<i64>, options: &DisplayOptions) -> String {
+    let hash = match options.rev_length {
+        0 => hash,
+        len => hash.get(..len).unwrap_or(hash),
+    };
     match last_modified {
-        Some(last_modified) => write!(
-            f,
-            "{} ({})",
-            hash.get(..10).unwrap(),
-            format_date(*last_modified)
-        )?,
-        None => write!(f, "{}", hash.get(..10).unwrap())?,
+        Some(last_modified) => format!("{} ({})", hash, format_date(*last_modified)),
+        None => hash.to_string(),
     }
-    Ok(())
 }
 
-impl Display for Locked {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+impl Locked {
+    pub fn show(&self, options: &DisplayOptions) -> String {
         match self {
             Locked::Git {
                 rev, last_modified, ..
-            } => show_hash_and_date(f, rev, last_modified)?,
-            Locked::Other {
+            } => show_hash_and_date(rev, last_modified, options),
+            // Paths and ids are shown in full, unlike hashes
+            Locked::Path {
+                path,
+                nar_hash: None,
+                last_modified,
+            } => show_hash_and_date(
+                path,
+                last_modified,
+                &DisplayOptions {
+                    rev_length: 0,
+                    ..options.clone()
+                },
+            ),
+            Locked::Indirect {
+                id,
+                nar_hash: None,
+                last_modified,
+            } => show_hash_and_date(
+                id,
+                last_modified,
+                &DisplayOptions {
+                    rev_length: 0,
+                    ..options.clone()
+                },
+            ),
+            Locked::Path {
+                nar_hash: Some(nar_hash),
+                last_modified,
+                ..
+            }
+            | Locked::Indirect {
+                nar_hash: Some(nar_hash),
+                last_modified,
+                ..
+            }
+            | Locked::Other {
                 nar_hash,
                 last_modified,
-            } => show_hash_and_date(f, nar_hash, last_modified)?,
+            } => show_hash_and_date(nar_hash, last_modified, options),
+        }
+    }
+}
+
+impl Display for Locked {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.show(&DisplayOptions::default()))
+    }
+}
+
+/// The web page of a repository fetched over http(s) from `url`, if it has one.
+/// Most forges serve it at the clone URL without `.git`.
+fn web_url(url: &str) -> Option<String> {
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return None;
+    }
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    let url = url.trim_end_matches('/');
+    Some(url.strip_suffix(".git").unwrap_or(url).to_string())
+}
+
+/// A locked input in the JSON diff
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LockedJson<'a> {
+    rev: Option<&'a str>,
+    nar_hash: Option<&'a str>,
+    last_modified: Option<i64>,
+    /// `last_modified` as YYYY-MM-DD
+    date: Option<String>,
+}
+
+impl<'a> From<&'a Locked> for LockedJson<'a> {
+    fn from(locked: &'a Locked) -> Self {
+        let last_modified = locked.last_modified();
+        LockedJson {
+            rev: locked.rev(),
+            nar_hash: locked.nar_hash(),
+            last_modified,
+            date: last_modified.map(format_date),
+        }
+    }
+}
+
+/// A change in the JSON diff. All the fields are always present, `null` if unknown.
+#[derive(Serialize)]
+struct InputChangeJson<'a> {
+    /// `add`, `update` or `delete`
+    change: &'static str,
+    old: Option<LockedJson<'a>>,
+    new: Option<LockedJson<'a>>,
+    link: Option<String>,
+}
+
+impl Serialize for InputChange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (change, old, new) = match self {
+            InputChange::Add(new) => ("add", None, Some(new.into())),
+            InputChange::Update { old, new } => ("update", Some(old.into()), Some(new.into())),
+            InputChange::Delete => ("delete", None, None),
         };
-        Ok(())
+        InputChangeJson {
+            change,
+            old,
+            new,
+            link: self.link(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The diff is serialized as a list of the changes, each with the name of its input
+impl Serialize for LockDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            input: &'a str,
+            #[serde(flatten)]
+            change: &'a InputChange,
+        }
+        serializer.collect_seq(self.0.iter().map(|(input, change)| Entry { input, change }))
     }
 }
 
@@ -232,6 +819,7 @@ impl InputChange {
                         r#type: type_old,
                         owner: Some(owner_old),
                         repo: Some(repo_old),
+                        host: host_old,
                         rev: rev_old,
                         ..
                     },
@@ -240,12 +828,15 @@ impl InputChange {
                         r#type: type_new,
                         owner: Some(owner_new),
                         repo: Some(repo_new),
+                        host,
                         rev: rev_new,
                         ..
                     },
             } if type_new == type_old
                 && owner_new.to_lowercase() == owner_old.to_lowercase()
-                && repo_new.to_lowercase() == repo_old.to_lowercase() =>
+                && repo_new.to_lowercase() == repo_old.to_lowercase()
+                && host.as_ref().map(|h| h.to_lowercase())
+                    == host_old.as_ref().map(|h| h.to_lowercase()) =>
             {
                 match type_new.as_str() {
                     "github" => Some(format!(
@@ -256,14 +847,55 @@ impl InputChange {
                         "https://gitlab.com/{}/{}/compare/{}...{}",
                         owner_new, repo_new, rev_old, rev_new
                     )),
+                    // Gitea and Forgejo have always supported three dots between the revs,
+                    // two dots only since Gitea 1.17, so don't bother with the version
+                    "gitea" => host.as_ref().map(|host| {
+                        format!(
+                            "https://{}/{}/{}/compare/{}...{}",
+                            host, owner_new, repo_new, rev_old, rev_new
+                        )
+                    }),
+                    // sourcehut has no compare view, link the log up to the new rev
+                    "sourcehut" => Some(format!(
+                        "https://{}/{}/{}/log/{}",
+                        host.as_deref().unwrap_or("git.sr.ht"),
+                        owner_new,
+                        repo_new,
+                        rev_new
+                    )),
                     _ => None,
                 }
             }
 
+            InputChange::Update {
+                old:
+                    Locked::Git {
+                        r#type: type_old,
+                        url: Some(url_old),
+                        rev: rev_old,
+                        ..
+                    },
+                new:
+                    Locked::Git {
+                        r#type: type_new,
+                        url: Some(url_new),
+                        rev: rev_new,
+                        ..
+                    },
+            } if type_new == "git" && type_old == "git" => {
+                let web_new = web_url(url_new)?;
+                if web_url(url_old).as_ref() == Some(&web_new) {
+                    Some(format!("{}/compare/{}...{}", web_new, rev_old, rev_new))
+                } else {
+                    Some(format!("{}/commit/{}", web_new, rev_new))
+                }
+            }
+
             InputChange::Add(Locked::Git {
                 r#type,
                 owner: Some(owner),
                 repo: Some(repo),
+                host,
                 rev,
                 ..
             }) => match r#type.as_str() {
@@ -275,16 +907,107 @@ impl InputChange {
                     "https://gitlab.com/{}/{}/-/tree/{}",
                     owner, repo, rev
                 )),
+                "gitea" => host
+                    .as_ref()
+                    .map(|host| format!("https://{}/{}/{}/src/commit/{}", host, owner, repo, rev)),
+                "sourcehut" => Some(format!(
+                    "https://{}/{}/{}/tree/{}",
+                    host.as_deref().unwrap_or("git.sr.ht"),
+                    owner,
+                    repo,
+                    rev
+                )),
                 _ => None,
             },
+
+            InputChange::Add(Locked::Git {
+                r#type,
+                url: Some(url),
+                rev,
+                ..
+            }) if r#type == "git" => Some(format!("{}/commit/{}", web_url(url)?, rev)),
             _ => None,
         }
     }
 
-    pub fn markdown(&self) -> String {
-        let change = match self.clone() {
-            InputChange::Add(l) => format!("(new) | `{}`", l),
-            InputChange::Update { old, new } => format!("`{}` | `{}`", old, new),
+    /// Note on changes that aren't visible from the revs
+    fn note(&self) -> Option<&'static str> {
+        match self {
+            InputChange::Update { old, new } if old.submodules() != new.submodules() => {
+                Some(if new.submodules() {
+                    "submodules enabled"
+                } else {
+                    "submodules disabled"
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Why the change deserves a closer look, if it does: its revision changed with
+    /// the same narHash, or it is older than the previous one
+    pub fn warning(&self) -> Option<&'static str> {
+        let InputChange::Update { old, new } = self else {
+            return None;
+        };
+        if old.clone().get_hash() == new.clone().get_hash() && old.rev() != new.rev() {
+            return Some("rev changed with the same narHash");
+        }
+        match (old.last_modified(), new.last_modified()) {
+            (Some(old), Some(new)) if new < old => Some("downgrade"),
+            _ => None,
+        }
+    }
+
+    /// How much newer the new revision is than the old one, by their `lastModified`
+    pub fn age_delta(&self) -> Option<String> {
+        let InputChange::Update { old, new } = self else {
+            return None;
+        };
+        let delta = new.last_modified()? - old.last_modified()?;
+        let days = match delta.abs() / (24 * 60 * 60) {
+            0 => "<1 day".to_string(),
+            1 => "1 day".to_string(),
+            days => format!("{} days", days),
+        };
+        match delta {
+            0 => None,
+            delta if delta > 0 => Some(format!("+{}", days)),
+            _ => Some(format!("-{}", days)),
+        }
+    }
+
+    /// The warning, the age delta and the note of the change in parentheses, if any.
+    /// In `markdown`, the warning stands out with an emoji.
+    fn notes(&self, markdown: bool) -> String {
+        let warning = self.warning().map(|warning| {
+            if markdown {
+                format!("⚠️ **{}**", warning)
+            } else {
+                warning.to_string()
+            }
+        });
+        let notes: Vec<String> = warning
+            .into_iter()
+            .chain(self.age_delta())
+            .chain(self.note().map(String::from))
+            .collect();
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join(", "))
+        }
+    }
+
+    pub fn markdown(&self, options: &DisplayOptions) -> String {
+        let change = match self {
+            InputChange::Add(l) => format!("(new) | `{}`", l.show(options)),
+            InputChange::Update { old, new } => format!(
+                "`{}` | `{}`{}",
+                old.show(options),
+                new.show(options),
+                self.notes(true)
+            ),
             InputChange::Delete => "(deleted) | (deleted)".to_string(),
         };
         format!(
@@ -296,35 +1019,189 @@ impl InputChange {
         )
     }
 
-    pub fn spaced(&self) -> String {
+    /// The value of the commit trailer of the change
+    fn trailer(&self, options: &DisplayOptions) -> String {
+        match self {
+            InputChange::Add(l) => format!("(new) -> {}", l.show(options)),
+            InputChange::Update { old, new } => {
+                format!("{} -> {}", old.show(options), new.show(options))
+            }
+            InputChange::Delete => "(deleted)".to_string(),
+        }
+    }
+
+    /// The old column of `spaced`
+    fn spaced_old(&self, options: &DisplayOptions) -> String {
+        match self {
+            InputChange::Add(_) => "(new)".to_string(),
+            InputChange::Update { old, .. } => old.show(options),
+            InputChange::Delete => "(deleted)".to_string(),
+        }
+    }
+
+    /// The old, new and diff columns of `table`
+    fn table_row(&self, options: &DisplayOptions) -> [String; 3] {
+        let new = match self {
+            InputChange::Add(l) => l.show(options),
+            InputChange::Update { new, .. } => {
+                format!("{}{}", new.show(options), self.notes(false))
+            }
+            InputChange::Delete => "(deleted)".to_string(),
+        };
+        [
+            self.spaced_old(options),
+            new,
+            self.link().unwrap_or_default(),
+        ]
+    }
+
+    /// Show the change with the old column padded to `width`
+    pub fn spaced(&self, options: &DisplayOptions, width: usize) -> String {
+        let old = self.spaced_old(options);
         match self {
-            InputChange::Add(l) => format!("{:<23}    {}", "(new)", l),
-            InputChange::Update { old, new } => format!("{:<23} -> {}", old, new),
-            InputChange::Delete => format!("{0:<23}    {0}", "(deleted)"),
+            InputChange::Add(l) => format!("{:<width$}    {}", old, l.show(options)),
+            InputChange::Update { new, .. } => {
+                format!(
+                    "{:<width$} -> {}{}",
+                    old,
+                    new.show(options),
+                    self.notes(false)
+                )
+            }
+            InputChange::Delete => format!("{0:<width$}    {0}", old),
         }
     }
 }
 
 impl LockDiff {
-    pub fn markdown(&self) -> String {
+    /// A markdown table of the changes. The inputs hidden by the filter of `options`
+    /// are in a table of their own, in a collapsed `<details>` block.
+    /// Tables longer than the `collapse_threshold` of `options` are collapsed as well.
+    pub fn markdown(&self, options: &DisplayOptions) -> String {
+        let Some(filter) = &options.filter else {
+            return self.collapsible_table(options);
+        };
+        let (shown, hidden): (Vec<_>, Vec<_>) = self
+            .0
+            .iter()
+            .map(|(name, change)| (name.clone(), change.clone()))
+            .partition(|(name, _)| filter.shows(name));
+        let (shown, hidden) = (
+            LockDiff(shown.into_iter().collect()),
+            LockDiff(hidden.into_iter().collect()),
+        );
+        let mut s = String::new();
+        if shown.len() > 0 {
+            s.push_str(&shown.collapsible_table(options));
+        }
+        if hidden.len() > 0 {
+            if shown.len() > 0 {
+                s.push('\n');
+            }
+            s.push_str(&format!(
+                "<details><summary>Other inputs ({})</summary>\n\n{}\n</details>\n",
+                hidden.len(),
+                hidden.markdown_table(options)
+            ));
+        }
+        s
+    }
+
+    /// `markdown_table`, in a collapsed `<details>` block if it's longer than the
+    /// `collapse_threshold` of `options`
+    fn collapsible_table(&self, options: &DisplayOptions) -> String {
+        let table = self.markdown_table(options);
+        if options.collapse_threshold == 0 || self.len() <= options.collapse_threshold {
+            return table;
+        }
+        let notable = match self.get("nixpkgs") {
+            Some(_) => ", including nixpkgs",
+            None => "",
+        };
+        format!(
+            "<details><summary>{} inputs updated{}</summary>\n\n{}\n</details>\n",
+            self.len(),
+            notable,
+            table
+        )
+    }
+
+    fn markdown_table(&self, options: &DisplayOptions) -> String {
         let mut s = String::new();
         s.push_str("| input | old | new | diff |\n");
         s.push_str("|-------|-----|-----|------|\n");
         for (name, change) in self.0.clone() {
-            s.push_str(format!("| {} | {} |\n", name, change.markdown()).as_str());
+            s.push_str(format!("| {} | {} |\n", name, change.markdown(options)).as_str());
         }
         s
     }
 
-    pub fn spaced(&self) -> String {
+    /// A `key: input change` trailer line for every changed input,
+    /// as understood by `git interpret-trailers`
+    pub fn trailers(&self, key: &str, options: &DisplayOptions) -> String {
+        let mut s = String::new();
+        for (name, change) in &self.0 {
+            s.push_str(&format!("{}: {} {}\n", key, name, change.trailer(options)));
+        }
+        s
+    }
+
+    /// A fixed-width table drawn with ASCII, for destinations that only show plain text
+    pub fn table(&self, options: &DisplayOptions) -> String {
+        let mut rows = vec![["input", "old", "new", "diff"].map(String::from)];
+        for (name, change) in &self.0 {
+            let [old, new, link] = change.table_row(options);
+            rows.push([name.clone(), old, new, link]);
+        }
+        let mut widths = [0; 4];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let separator = widths
+            .iter()
+            .map(|width| format!("+{}", "-".repeat(width + 2)))
+            .collect::<String>()
+            + "+\n";
+        let mut s = separator.clone();
+        for (i, row) in rows.iter().enumerate() {
+            for (width, cell) in widths.iter().zip(row) {
+                s.push_str(&format!("| {:<width$} ", cell, width = width));
+            }
+            s.push_str("|\n");
+            if i == 0 {
+                s.push_str(&separator);
+            }
+        }
+        s.push_str(&separator);
+        s
+    }
+
+    pub fn render(&self, rendering: DiffRendering, options: &DisplayOptions) -> String {
+        match rendering {
+            DiffRendering::Markdown => self.markdown(options),
+            DiffRendering::Spaced => self.spaced(options),
+            DiffRendering::Table => self.table(options),
+        }
+    }
+
+    pub fn spaced(&self, options: &DisplayOptions) -> String {
         let max = self.0.clone().keys().map(|l| l.len()).max().unwrap_or(0);
+        let width = self
+            .0
+            .values()
+            .map(|change| change.spaced_old(options).len())
+            .max()
+            .unwrap_or(0);
         let mut s = String::new();
         for (name, change) in self.0.clone() {
             let mut name = name.clone();
             while name.len() < max {
                 name.push(' ');
             }
-            s.push_str(format!("{} {}\n", name, change.spaced()).as_str());
+            s.push_str(format!("{} {}\n", name, change.spaced(options, width)).as_str());
         }
         s
     }
@@ -5,7 +5,7 @@
 use super::*;
 
 // If you add a new valid flake.lock to resources, also add it here
-const ALL_RESOURCES: &[&str] = &["simple_old", "simple_new"];
+const ALL_RESOURCES: &[&str] = &["simple_old", "simple_new", "submodules"];
 
 use std::path::PathBuf;
 
@@ -15,6 +15,10 @@ fn get_resources(test: &'static str) -> PathBuf {
     d
 }
 
+fn get_lock(dir: &std::path::Path) -> Result<Lock, GetLockError> {
+    read_lock(&dir.join("flake.lock"))
+}
+
 #[test]
 fn parses_locks_correctly() {
     for res in ALL_RESOURCES {
@@ -82,3 +86,598 @@ fn link_github() {
 
     assert_eq!(link, expected);
 }
+
+#[test]
+fn root_snapshot_diffs_like_full_lock() {
+    for res1 in ALL_RESOURCES {
+        let lock1 = get_lock(get_resources(res1).as_path()).unwrap();
+
+        let snapshot: Lock =
+            serde_json::from_str(&serde_json::to_string(&lock1.root_snapshot().unwrap()).unwrap())
+                .unwrap();
+
+        for res2 in ALL_RESOURCES {
+            let lock2 = get_lock(get_resources(res2).as_path()).unwrap();
+
+            assert_eq!(snapshot.diff(&lock2).unwrap(), lock1.diff(&lock2).unwrap());
+        }
+    }
+}
+
+#[test]
+fn diffs_json_correct() {
+    for res1 in ALL_RESOURCES {
+        let lock1 = get_lock(get_resources(res1).as_path()).unwrap();
+
+        for res2 in ALL_RESOURCES {
+            let lock2 = get_lock(get_resources(res2).as_path()).unwrap();
+
+            let mut expected_path = get_resources(res1);
+            expected_path.push(format!("{}.json.expected", res2));
+
+            let expected = std::fs::read_to_string(expected_path).unwrap();
+
+            assert_eq!(
+                serde_json::to_string_pretty(&lock1.diff(&lock2).unwrap()).unwrap(),
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn shows_revs_of_any_length() {
+    let locked = Locked::Git {
+        r#type: "github".to_string(),
+        owner: None,
+        repo: None,
+        host: None,
+        url: None,
+        rev: "c601d56".to_string(),
+        nar_hash: "sha256-AAAA".to_string(),
+        last_modified: None,
+        submodules: None,
+    };
+
+    for (rev_length, expected) in [(0, "c601d56"), (4, "c601"), (7, "c601d56"), (10, "c601d56")] {
+        assert_eq!(
+            locked.show(&DisplayOptions {
+                rev_length,
+                ..Default::default()
+            }),
+            expected
+        );
+    }
+}
+
+#[test]
+fn notes_submodules_change() {
+    let lock = get_lock(get_resources("submodules").as_path()).unwrap();
+    let new = lock.nodes.get("vendored").unwrap().locked.clone().unwrap();
+    let old = match new.clone() {
+        Locked::Git {
+            r#type,
+            owner,
+            repo,
+            host,
+            url,
+            rev,
+            nar_hash,
+            last_modified,
+            ..
+        } => Locked::Git {
+            r#type,
+            owner,
+            repo,
+            host,
+            url,
+            rev,
+            nar_hash,
+            last_modified,
+            submodules: None,
+        },
+        other => other,
+    };
+
+    let change = InputChange::Update { old, new };
+
+    assert!(change
+        .markdown(&DisplayOptions::default())
+        .contains("(submodules enabled)"));
+    assert!(change
+        .spaced(&DisplayOptions::default(), 0)
+        .ends_with("(submodules enabled)"));
+}
+
+#[test]
+fn links_gitea_compare_on_its_host() {
+    let locked_on = |host: &str, rev: &str| Locked::Git {
+        r#type: "gitea".to_string(),
+        owner: Some("serokell".to_string()),
+        repo: Some("update-daemon".to_string()),
+        host: Some(host.to_string()),
+        url: None,
+        rev: rev.to_string(),
+        nar_hash: "sha256-AAAA".to_string(),
+        last_modified: None,
+        submodules: None,
+    };
+    let locked = |rev: &str| locked_on("codeberg.org", rev);
+
+    let change = InputChange::Update {
+        old: locked("c601d56"),
+        new: locked("d4ba2f8"),
+    };
+
+    assert_eq!(
+        change.link().as_deref(),
+        Some("https://codeberg.org/serokell/update-daemon/compare/c601d56...d4ba2f8")
+    );
+    assert_eq!(
+        InputChange::Add(locked("d4ba2f8")).link().as_deref(),
+        Some("https://codeberg.org/serokell/update-daemon/src/commit/d4ba2f8")
+    );
+
+    // The old rev isn't on the new host, so there is nothing to compare
+    let moved = InputChange::Update {
+        old: locked_on("git.example.com", "c601d56"),
+        new: locked("d4ba2f8"),
+    };
+    assert_eq!(moved.link(), None);
+}
+
+#[test]
+fn rejects_dangling_inputs() {
+    let lock: Lock = r#"{
+        "nodes": {
+            "root": { "inputs": { "nixpkgs": "nixpkgs", "utils": "flake-utils" } },
+            "nixpkgs": { "locked": { "narHash": "sha256-AAAA" } }
+        },
+        "version": 7,
+        "root": "root"
+    }"#
+    .parse()
+    .unwrap();
+
+    assert_eq!(
+        lock.validate(),
+        Err(LockDiffError::MissingNodeError(
+            "flake-utils".to_string(),
+            "root".to_string()
+        ))
+    );
+}
+
+#[test]
+fn links_sourcehut_log() {
+    let locked = |rev: &str| Locked::Git {
+        r#type: "sourcehut".to_string(),
+        owner: Some("~sircmpwn".to_string()),
+        repo: Some("hare".to_string()),
+        host: None,
+        url: None,
+        rev: rev.to_string(),
+        nar_hash: "sha256-AAAA".to_string(),
+        last_modified: None,
+        submodules: None,
+    };
+
+    assert_eq!(
+        InputChange::Update {
+            old: locked("c601d56"),
+            new: locked("d4ba2f8"),
+        }
+        .link()
+        .as_deref(),
+        Some("https://git.sr.ht/~sircmpwn/hare/log/d4ba2f8")
+    );
+    assert_eq!(
+        InputChange::Add(locked("d4ba2f8")).link().as_deref(),
+        Some("https://git.sr.ht/~sircmpwn/hare/tree/d4ba2f8")
+    );
+}
+
+#[test]
+fn links_git_over_http() {
+    let locked = |url: &str, rev: &str| Locked::Git {
+        r#type: "git".to_string(),
+        owner: None,
+        repo: None,
+        host: None,
+        url: Some(url.to_string()),
+        rev: rev.to_string(),
+        nar_hash: "sha256-AAAA".to_string(),
+        last_modified: None,
+        submodules: None,
+    };
+
+    assert_eq!(
+        InputChange::Update {
+            old: locked("https://example.com/repo.git", "c601d56"),
+            new: locked("https://example.com/repo.git", "d4ba2f8"),
+        }
+        .link()
+        .as_deref(),
+        Some("https://example.com/repo/compare/c601d56...d4ba2f8")
+    );
+    assert_eq!(
+        InputChange::Update {
+            old: locked("https://example.com/old.git", "c601d56"),
+            new: locked("https://example.com/repo?ref=main", "d4ba2f8"),
+        }
+        .link()
+        .as_deref(),
+        Some("https://example.com/repo/commit/d4ba2f8")
+    );
+    assert_eq!(
+        InputChange::Add(locked("ssh://git@example.com/repo.git", "d4ba2f8")).link(),
+        None
+    );
+}
+
+/// A lock where `foo` has its own nixpkgs and another input following the root's nixpkgs
+fn transitive_lock(nixpkgs_hash: &str, foo_nixpkgs_hash: &str) -> Lock {
+    transitive_lock_contents(nixpkgs_hash, foo_nixpkgs_hash)
+        .parse()
+        .unwrap()
+}
+
+fn transitive_lock_contents(nixpkgs_hash: &str, foo_nixpkgs_hash: &str) -> String {
+    format!(
+        r#"{{
+            "nodes": {{
+                "root": {{ "inputs": {{ "nixpkgs": "nixpkgs", "foo": "foo" }} }},
+                "nixpkgs": {{ "locked": {{ "narHash": "{}" }} }},
+                "foo": {{
+                    "locked": {{ "narHash": "sha256-FOO" }},
+                    "inputs": {{ "nixpkgs": "nixpkgs_2", "shared": ["nixpkgs"] }}
+                }},
+                "nixpkgs_2": {{ "locked": {{ "narHash": "{}" }} }}
+            }},
+            "version": 7,
+            "root": "root"
+        }}"#,
+        nixpkgs_hash, foo_nixpkgs_hash
+    )
+}
+
+#[test]
+fn diffs_transitive_inputs_once() {
+    let old = transitive_lock("sha256-A", "sha256-B");
+    let new = transitive_lock("sha256-C", "sha256-D");
+
+    assert_eq!(
+        old.diff(&new).unwrap().0.keys().collect::<Vec<_>>(),
+        ["nixpkgs"]
+    );
+    // foo/shared follows the root's nixpkgs, so it isn't reported again
+    assert_eq!(
+        old.diff_all(&new).unwrap().0.keys().collect::<Vec<_>>(),
+        ["nixpkgs", "foo/nixpkgs"]
+    );
+    assert_eq!(
+        transitive_lock("sha256-A", "sha256-B")
+            .diff_all(&transitive_lock("sha256-A", "sha256-D"))
+            .unwrap()
+            .0
+            .keys()
+            .collect::<Vec<_>>(),
+        ["foo/nixpkgs"]
+    );
+}
+
+#[test]
+fn formats_commit_trailers() {
+    let old = transitive_lock("sha256-AAAA", "sha256-BBBB");
+    let new = transitive_lock("sha256-CCCC", "sha256-BBBB");
+
+    assert_eq!(
+        old.diff(&new).unwrap().trailers(
+            "Bump",
+            &DisplayOptions {
+                rev_length: 8,
+                ..Default::default()
+            }
+        ),
+        "Bump: nixpkgs sha256-A -> sha256-C\n"
+    );
+}
+
+#[test]
+fn renders_box_table() {
+    let old = transitive_lock("sha256-AAAA", "sha256-BBBB");
+    let new = transitive_lock("sha256-CCCC", "sha256-DDDD");
+
+    assert_eq!(
+        old.diff_all(&new).unwrap().render(
+            DiffRendering::Table,
+            &DisplayOptions {
+                rev_length: 0,
+                ..Default::default()
+            }
+        ),
+        "\
++-------------+-------------+-------------+------+
+| input       | old         | new         | diff |
++-------------+-------------+-------------+------+
+| nixpkgs     | sha256-AAAA | sha256-CCCC |      |
+| foo/nixpkgs | sha256-BBBB | sha256-DDDD |      |
++-------------+-------------+-------------+------+
+"
+    );
+}
+
+#[test]
+fn finds_newest_last_modified() {
+    let lock = get_lock(get_resources("submodules").as_path()).unwrap();
+    assert_eq!(lock.newest_last_modified(), Some(1624377671));
+
+    let lock = transitive_lock("sha256-A", "sha256-B");
+    assert_eq!(lock.newest_last_modified(), None);
+}
+
+#[test]
+fn finds_updates_after() {
+    let old = get_lock(get_resources("simple_old").as_path()).unwrap();
+    let new = get_lock(get_resources("simple_new").as_path()).unwrap();
+    let diff = old.diff(&new).unwrap();
+
+    assert_eq!(diff.updated_after(1624377670).len(), 1);
+    assert_eq!(diff.updated_after(1624377671).len(), 0);
+    // Back to the old revision
+    assert_eq!(new.diff(&old).unwrap().updated_after(1601171649).len(), 0);
+}
+
+#[test]
+fn collapses_filtered_inputs() {
+    let old = transitive_lock("sha256-AAAA", "sha256-BBBB");
+    let new = transitive_lock("sha256-CCCC", "sha256-DDDD");
+    let diff = old.diff_all(&new).unwrap();
+    let options = |mode| DisplayOptions {
+        rev_length: 0,
+        filter: Some(DiffDisplayFilter {
+            mode,
+            inputs: vec!["foo/nixpkgs".to_string()],
+        }),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        diff.markdown(&options(DiffFilterMode::Deny)),
+        "\
+| input | old | new | diff |
+|-------|-----|-----|------|
+| nixpkgs | `sha256-AAAA` | `sha256-CCCC` | _none_ |
+
+<details><summary>Other inputs (1)</summary>
+
+| input | old | new | diff |
+|-------|-----|-----|------|
+| foo/nixpkgs | `sha256-BBBB` | `sha256-DDDD` | _none_ |
+
+</details>
+"
+    );
+    assert!(diff.markdown(&options(DiffFilterMode::Allow)).starts_with(
+        "| input | old | new | diff |\n|-------|-----|-----|------|\n| foo/nixpkgs |"
+    ));
+    // The commit message still has all of them
+    assert_eq!(
+        diff.spaced(&options(DiffFilterMode::Deny)).lines().count(),
+        2
+    );
+}
+
+#[test]
+fn collapses_long_tables() {
+    let old = transitive_lock("sha256-AAAA", "sha256-BBBB");
+    let new = transitive_lock("sha256-CCCC", "sha256-DDDD");
+    let diff = old.diff_all(&new).unwrap();
+    let options = |collapse_threshold| DisplayOptions {
+        rev_length: 0,
+        collapse_threshold,
+        ..Default::default()
+    };
+    let table = "\
+| input | old | new | diff |
+|-------|-----|-----|------|
+| nixpkgs | `sha256-AAAA` | `sha256-CCCC` | _none_ |
+| foo/nixpkgs | `sha256-BBBB` | `sha256-DDDD` | _none_ |
+";
+
+    assert_eq!(diff.markdown(&options(2)), table);
+    assert_eq!(diff.markdown(&options(0)), table);
+    assert_eq!(
+        diff.markdown(&options(1)),
+        format!(
+            "<details><summary>2 inputs updated, including nixpkgs</summary>\n\n{}\n</details>\n",
+            table
+        )
+    );
+    // The commit message isn't collapsed
+    assert_eq!(diff.spaced(&options(1)), diff.spaced(&options(0)));
+}
+
+#[test]
+fn shows_age_delta() {
+    let old = get_lock(get_resources("simple_old").as_path()).unwrap();
+    let new = get_lock(get_resources("simple_new").as_path()).unwrap();
+    let options = DisplayOptions::default();
+
+    let update = old.diff(&new).unwrap();
+    let change = update.get("nixpkgs").unwrap();
+    assert_eq!(change.age_delta().as_deref(), Some("+268 days"));
+    assert!(change
+        .markdown(&options)
+        .starts_with("`84d74ae9c9 (2020-09-27)` | `c601d56e19 (2021-06-22)` (+268 days) |"));
+    assert!(update.spaced(&options).ends_with(" (+268 days)\n"));
+
+    let downgrade = new.diff(&old).unwrap();
+    assert_eq!(
+        downgrade.get("nixpkgs").unwrap().age_delta().as_deref(),
+        Some("-268 days")
+    );
+
+    // Without lastModified, there's nothing to compare
+    let diff = transitive_lock("sha256-A", "sha256-B")
+        .diff(&transitive_lock("sha256-C", "sha256-B"))
+        .unwrap();
+    assert_eq!(diff.get("nixpkgs").unwrap().age_delta(), None);
+}
+
+#[test]
+fn flags_suspicious_updates() {
+    let old = get_lock(get_resources("simple_old").as_path()).unwrap();
+    let new = get_lock(get_resources("simple_new").as_path()).unwrap();
+    let options = DisplayOptions::default();
+
+    assert_eq!(
+        old.diff(&new).unwrap().get("nixpkgs").unwrap().warning(),
+        None
+    );
+
+    let downgrade = new.diff(&old).unwrap();
+    let change = downgrade.get("nixpkgs").unwrap();
+    assert_eq!(change.warning(), Some("downgrade"));
+    assert!(change
+        .markdown(&options)
+        .contains("`84d74ae9c9 (2020-09-27)` (⚠️ **downgrade**, -268 days) |"));
+    assert!(downgrade
+        .spaced(&options)
+        .ends_with("84d74ae9c9 (2020-09-27) (downgrade, -268 days)\n"));
+
+    // Only the rev changes, which used to go unnoticed
+    let mut forced = get_lock(get_resources("simple_new").as_path()).unwrap();
+    if let Some(Node {
+        locked: Some(Locked::Git { rev, .. }),
+        ..
+    }) = forced.nodes.get_mut("nixpkgs")
+    {
+        *rev = "0000000000000000000000000000000000000000".to_string();
+    }
+    let diff = new.diff(&forced).unwrap();
+    assert_eq!(
+        diff.get("nixpkgs").unwrap().warning(),
+        Some("rev changed with the same narHash")
+    );
+}
+
+#[test]
+fn parses_supported_versions() {
+    let new = get_lock(get_resources("simple_new").as_path()).unwrap();
+    for (res, inputs) in [
+        ("version_5", ["flake-utils", "nixpkgs"]),
+        ("version_6", ["foo", "nixpkgs"]),
+    ] {
+        let lock = get_lock(get_resources(res).as_path()).unwrap();
+        let mut deps: Vec<_> = lock.root_deps().unwrap().into_keys().collect();
+        deps.sort();
+        assert_eq!(deps, inputs);
+        assert!(lock.diff(&new).is_ok());
+    }
+}
+
+#[test]
+fn names_unsupported_versions() {
+    // Version 4 nests the inputs instead of listing the nodes
+    let version_4 = r#"{
+        "inputs": {
+            "nixpkgs": {
+                "inputs": {},
+                "narHash": "sha256-G3RUAi2DUq6r3ntASLS+LZC/Eamot55W1+xmBOgEh3M=",
+                "originalUrl": "nixpkgs",
+                "url": "github:nixos/nixpkgs/84d74ae9c9cbed73274b8e4e00be14688ffc93fe"
+            }
+        },
+        "version": 4
+    }"#;
+    assert!(matches!(
+        parse_lock(version_4),
+        Err(GetLockError::UnsupportedVersion(4, _))
+    ));
+
+    // A newer version with the same layout is still read
+    let contents = std::fs::read_to_string(get_resources("simple_new").join("flake.lock")).unwrap();
+    assert!(parse_lock(&contents.replace("\"version\": 7", "\"version\": 8")).is_ok());
+    assert!(matches!(parse_lock("{}"), Err(GetLockError::ParseError(_))));
+}
+
+#[test]
+fn handles_path_inputs() {
+    let lock = get_lock(get_resources("path_input").as_path()).unwrap();
+    let new = get_lock(get_resources("simple_new").as_path()).unwrap();
+
+    let locked = |input: &str| lock.nodes.get(input).unwrap().locked.clone().unwrap();
+    assert!(matches!(
+        locked("local"),
+        Locked::Path {
+            nar_hash: Some(_),
+            ..
+        }
+    ));
+    assert!(matches!(locked("sub"), Locked::Path { nar_hash: None, .. }));
+    assert_eq!(locked("sub").to_string(), "./sub");
+
+    assert_eq!(lock.diff(&lock).unwrap().len(), 0);
+    let reparsed: Lock = serde_json::to_string(&lock).unwrap().parse().unwrap();
+    assert_eq!(reparsed, lock);
+
+    let diff = new.diff(&lock).unwrap();
+    assert_eq!(diff.len(), 2);
+    assert_eq!(
+        diff.get("sub")
+            .unwrap()
+            .markdown(&DisplayOptions::default()),
+        "(new) | `./sub` | _none_"
+    );
+    assert_eq!(
+        lock.diff(&new).unwrap().get("sub"),
+        Some(&InputChange::Delete)
+    );
+
+    let indirect: Locked =
+        serde_json::from_str(r#"{ "id": "nixpkgs", "type": "indirect" }"#).unwrap();
+    assert_eq!(indirect.to_string(), "nixpkgs");
+}
+
+#[test]
+fn reverts_inputs_with_their_nodes() {
+    let before = transitive_lock_contents("sha256-A", "sha256-B");
+    let after = transitive_lock_contents("sha256-C", "sha256-D");
+    let revert = |inputs: &[&str]| -> Lock {
+        let inputs: Vec<String> = inputs.iter().map(|input| input.to_string()).collect();
+        parse_lock(&revert_inputs(&before, &after, &inputs).unwrap()).unwrap()
+    };
+
+    // foo gets its own nixpkgs back, the root's nixpkgs stays updated
+    let reverted = revert(&["foo"]);
+    assert_eq!(reverted, transitive_lock("sha256-C", "sha256-B"));
+    assert_eq!(reverted.nodes.len(), 4);
+
+    let reverted = revert(&["nixpkgs"]);
+    assert_eq!(reverted, transitive_lock("sha256-A", "sha256-D"));
+
+    assert_eq!(revert(&[]), transitive_lock("sha256-C", "sha256-D"));
+    assert_eq!(
+        revert(&["nixpkgs", "foo"]),
+        transitive_lock("sha256-A", "sha256-B")
+    );
+}
+
+#[test]
+fn renames_reverted_nodes_taken_by_others() {
+    let before = transitive_lock_contents("sha256-A", "sha256-B");
+    // foo also uses the updated nixpkgs directly, so it's kept
+    let after = transitive_lock_contents("sha256-C", "sha256-D").replace(
+        r#""shared": ["nixpkgs"]"#,
+        r#""shared": ["nixpkgs"], "other": "nixpkgs""#,
+    );
+    let reverted = revert_inputs(&before, &after, &["nixpkgs".to_string()]).unwrap();
+    let reverted: serde_json::Value = serde_json::from_str(&reverted).unwrap();
+    let hash = |node: &str| reverted["nodes"][node]["locked"]["narHash"].clone();
+
+    assert_eq!(reverted["nodes"]["root"]["inputs"]["nixpkgs"], "nixpkgs_3");
+    assert_eq!(hash("nixpkgs_3"), "sha256-A");
+    assert_eq!(hash("nixpkgs"), "sha256-C");
+    assert_eq!(hash("nixpkgs_2"), "sha256-D");
+}
@@ -3,21 +3,31 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use git2::RemoteCallbacks;
-use git2::{BranchType, FetchOptions, PushOptions, Repository, ResetType, Signature};
-use gpgme::{Context, Protocol};
+use git2::{BranchType, FetchOptions, PushOptions, Repository, ResetType, Signature, Sort};
+use gpgme::{Context, PassphraseRequest, PinentryMode, Protocol};
 use ssh2::{CheckResult, Session};
 use ssh2_config::{Field, SshConfig};
+use std::cell::Cell;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::fs::{create_dir, remove_dir_all};
 use std::hash::{Hash, Hasher};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::str;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 use log::*;
 
+use super::explain::{explain, shell_quote};
+use super::template::render;
 use super::types::*;
 
+#[cfg(test)]
+mod tests;
+
 /// Calculate a hash.
 /// Must be identical for identical URLs and different for different URLs.
 fn calculate_hash<H: Hash>(url: H) -> String {
@@ -26,8 +36,50 @@ fn calculate_hash<H: Hash>(url: H) -> String {
     format!("{}", hasher.finish())
 }
 
+/// Name of the directory in `cache_dir` the repository is cloned to
+pub fn cache_dir_name(settings: &UpdateSettings, handle: &RepoHandle) -> String {
+    calculate_hash(handle.git_url(settings.git_auth))
+}
+
+/// When the clone in `dir` was last fetched, or created if it never was
+fn last_used(dir: &Path) -> std::io::Result<SystemTime> {
+    std::fs::metadata(dir.join(".git").join("FETCH_HEAD"))
+        .or_else(|_| std::fs::metadata(dir))?
+        .modified()
+}
+
+/// The clones in `cache_dir` that aren't named in `keep`, or that haven't been used
+/// for `max_age`. Anything not named like a clone is left alone.
+pub fn stale_clones(
+    cache_dir: &Path,
+    keep: &HashSet<String>,
+    max_age: Option<Duration>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut stale = Vec::new();
+    for entry in std::fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || !name.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let expired = match max_age {
+            Some(max_age) => last_used(&entry.path())?
+                .elapsed()
+                .is_ok_and(|age| age > max_age),
+            None => false,
+        };
+        if !keep.contains(&name) || expired {
+            stale.push(entry.path());
+        }
+    }
+    Ok(stale)
+}
+
 pub struct UDRepo {
     repo: Repository,
+    name: String,
+    explain_commands: bool,
+    credentials: Option<GitCredentials>,
 }
 
 impl UDRepo {
@@ -35,12 +87,23 @@ impl UDRepo {
         state: &UpdateState,
         settings: &UpdateSettings,
         handle: &RepoHandle,
+        credentials: Option<GitCredentials>,
     ) -> Result<UDRepo, InitError> {
         Ok(UDRepo {
-            repo: init_repo(state, settings, handle)?,
+            repo: init_repo(state, settings, handle, credentials.as_ref())?,
+            name: handle.to_string(),
+            explain_commands: state.explain_commands,
+            credentials,
         })
     }
 
+    /// Log the git command equivalent to an operation, if requested
+    fn explain(&self, command: String) {
+        if self.explain_commands {
+            explain(&self.name, &format!("$ {}", command), false);
+        }
+    }
+
     pub fn path(&self) -> Option<&Path> {
         self.repo.workdir()
     }
@@ -49,30 +112,150 @@ impl UDRepo {
         &self,
         settings: &UpdateSettings,
     ) -> Result<(), SetupUpdateBranchError> {
-        setup_update_branch(settings, &self.repo)
+        let base = setup_update_branch(settings, &self.repo)?;
+        self.explain(format!(
+            "git checkout -B {} {}",
+            shell_quote(&settings.update_branch),
+            shell_quote(&base)
+        ));
+        Ok(())
     }
 
     pub fn commit(&self, settings: &UpdateSettings, diff: String) -> Result<(), CommitError> {
+        let (config, sign) = match (settings.sign_commits, settings.signing_method) {
+            (false, _) => ("", ""),
+            (true, SigningMethod::Gpg) => ("", " -S"),
+            (true, SigningMethod::Ssh) => (" -c gpg.format=ssh", " -S"),
+        };
+        self.explain(format!(
+            "git add -A && git{} commit{} -m {}",
+            config,
+            sign,
+            shell_quote(&commit_message(settings, &diff))
+        ));
         commit(settings, &self.repo, diff)
     }
 
     pub fn push(&self, state: &UpdateState, settings: &UpdateSettings) -> Result<(), PushError> {
-        push(state, settings, &self.repo)
+        let command = format!(
+            "$ git push {} {}",
+            shell_quote(update_remote(settings)),
+            shell_quote(&format!(
+                "+refs/heads/{0}:refs/heads/{0}",
+                settings.update_branch
+            ))
+        );
+        if state.dry_run {
+            explain(&self.name, &command, true);
+            return Ok(());
+        }
+        if self.explain_commands {
+            explain(&self.name, &command, false);
+        }
+        push(state, settings, &self.repo, self.credentials.as_ref())
+    }
+
+    /// Delete the update branch from the remote it is pushed to, if it is there.
+    /// Returns whether it was.
+    pub fn delete_update_branch(
+        &self,
+        state: &UpdateState,
+        settings: &UpdateSettings,
+    ) -> Result<bool, PushError> {
+        if !has_remote_update_branch(settings, &self.repo) {
+            return Ok(false);
+        }
+        let command = format!(
+            "$ git push {} {}",
+            shell_quote(update_remote(settings)),
+            shell_quote(&format!(":refs/heads/{}", settings.update_branch))
+        );
+        if state.dry_run {
+            explain(&self.name, &command, true);
+            return Ok(true);
+        }
+        if self.explain_commands {
+            explain(&self.name, &command, false);
+        }
+        delete_update_branch(state, settings, &self.repo, self.credentials.as_ref())?;
+        Ok(true)
     }
 
     pub fn soft_reset_to_default(&self, settings: &UpdateSettings) -> Result<(), ResetError> {
+        self.explain(format!(
+            "git reset --soft {}",
+            shell_quote(&update_base_name(settings, &self.repo))
+        ));
         soft_reset_to_default(settings, &self.repo)
     }
+
+    pub fn commits_ahead_of_default(
+        &self,
+        settings: &UpdateSettings,
+    ) -> Result<usize, CountAheadError> {
+        commits_ahead_of_default(settings, &self.repo)
+    }
+
+    /// Fetch a branch and check it out, returning its commit
+    pub fn checkout_remote_branch(
+        &self,
+        state: &UpdateState,
+        settings: &UpdateSettings,
+        branch: &str,
+    ) -> Result<git2::Oid, CheckoutRemoteBranchError> {
+        self.explain(format!(
+            "git fetch {} {} && git checkout -B {1} {}",
+            shell_quote(&settings.remote_name),
+            shell_quote(branch),
+            shell_quote(&remote_branch(settings, branch))
+        ));
+        checkout_remote_branch(
+            state,
+            settings,
+            &self.repo,
+            branch,
+            self.credentials.as_ref(),
+        )
+    }
+
+    /// Whether the update branch keeps human commits, with `rebase_on_human_commits`
+    pub fn keeps_human_commits(&self) -> bool {
+        self.repo.find_reference(UPDATE_BASE_REF).is_ok()
+    }
+
+    /// The git directory, where update-daemon can keep its own files
+    pub fn git_dir(&self) -> &Path {
+        self.repo.path()
+    }
+
+    pub fn update_branch_up_to_date(
+        &self,
+        settings: &UpdateSettings,
+    ) -> Result<bool, CompareError> {
+        self.explain(format!(
+            "git diff --quiet HEAD {}",
+            shell_quote(&remote_update_branch(settings))
+        ));
+        update_branch_up_to_date(settings, &self.repo)
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum InitError {
+    #[error("Refusing to fetch: {0}")]
+    HostNotAllowed(#[from] HostNotAllowed),
     #[error("Error in git opening existing repository: {0}")]
     OpenRepository(git2::Error),
     #[error("Error in git setting remote URL for existing repository: {0}")]
     SetRemoteUrl(git2::Error),
     #[error("Error finding remote for existing repository: {0}")]
     FindRemote(git2::Error),
+    #[error("Error adding the remote to existing repository: {0}")]
+    CreateRemote(git2::Error),
+    #[error("Pushing to a fork is only supported on GitHub, not for {0}")]
+    ForkUnsupported(String),
+    #[error("Error fetching the update branch from the fork: {0}")]
+    FetchFork(git2::Error),
     #[error("Error connecting to the remote: {0}")]
     ConnectRemote(git2::Error),
     #[error("Error pruning: {0}")]
@@ -83,24 +266,70 @@ pub enum InitError {
     FetchDefault(git2::Error),
     #[error("Error fetching update branch for existing repository: {0}")]
     FetchUpdate(git2::Error),
+    #[error("Error fetching default_ref: {0}")]
+    FetchDefaultRef(git2::Error),
     #[error("Error creating directory for cloning: {0}")]
     CreateCloneDir(std::io::Error),
     #[error("Error cleaning up after failed clone: {0}")]
     CleanFailedClone(std::io::Error),
     #[error("Error cloning repository: {0}")]
     Clone(git2::Error),
+    #[error("Aborted cloning the repository, it is larger than max_repo_size ({0} bytes)")]
+    TooLarge(u64),
     #[error("Error finding default branch on repository: {0}")]
     FindDefaultBranch(git2::Error),
     #[error("Error force-checking out the default branch: {0}")]
     ForceCheckoutDefaultBranch(#[from] ForceCheckoutBranchError),
 }
 
+/// Credentials of the ssh private key at `path`, with the public key next to it
+/// if there is one, and the passphrase read from `passphrase_file` if it is set
+fn ssh_key(
+    username: &str,
+    path: &Path,
+    passphrase_file: Option<&Path>,
+) -> Result<git2::Cred, git2::Error> {
+    let passphrase = passphrase_file
+        .map(std::fs::read_to_string)
+        .transpose()
+        .map_err(|e| {
+            git2::Error::from_str(&format!("Couldn't read the ssh key passphrase: {}", e))
+        })?;
+    let mut public_key = path.as_os_str().to_owned();
+    public_key.push(".pub");
+    let public_key = PathBuf::from(public_key);
+    git2::Cred::ssh_key(
+        username,
+        Some(public_key.as_path()).filter(|public_key| public_key.exists()),
+        path,
+        passphrase.as_deref().map(str::trim_end),
+    )
+}
+
 /// RemoteCallbacks is non-cloneable but we have to use it twice, hence this
-/// function
-fn callbacks(state: &UpdateState) -> git2::RemoteCallbacks {
+/// function. Authenticates with `credentials` over HTTPS, and over ssh with `ssh_key_path`
+/// if it is set, or the ssh agent otherwise.
+fn callbacks<'a>(
+    state: &'a UpdateState,
+    settings: &'a UpdateSettings,
+    credentials: Option<&'a GitCredentials>,
+) -> git2::RemoteCallbacks<'a> {
     let mut callbacks = RemoteCallbacks::new();
     callbacks
-        .credentials(|_url, username, _| git2::Cred::ssh_key_from_agent(username.unwrap_or("git")))
+        .credentials(move |_url, username, allowed| {
+            let username = username.unwrap_or("git");
+            match (credentials, &settings.ssh_key_path) {
+                (Some(credentials), _)
+                    if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) =>
+                {
+                    git2::Cred::userpass_plaintext(&credentials.username, &credentials.password)
+                }
+                (_, Some(path)) => {
+                    ssh_key(username, path, settings.ssh_key_passphrase_file.as_deref())
+                }
+                _ => git2::Cred::ssh_key_from_agent(username),
+            }
+        })
         .certificate_check(move |cert, host| {
             // libgit2 only considers "~/.ssh/known_hosts" when checking the git host certificate,
             // see https://github.com/libgit2/libgit2/blob/115db540cfb633c2a618aa60757454839047eadf/src/libgit2/transports/ssh_libssh2.c#L435
@@ -156,42 +385,222 @@ fn callbacks(state: &UpdateState) -> git2::RemoteCallbacks {
     callbacks
 }
 
+#[derive(Debug, Error)]
+pub enum DetectDefaultBranchError {
+    #[error("Refusing to connect: {0}")]
+    HostNotAllowed(#[from] HostNotAllowed),
+    #[error("Error creating the remote: {0}")]
+    CreateRemote(git2::Error),
+    #[error("Error connecting to the remote: {0}")]
+    ConnectRemote(git2::Error),
+    #[error("Error getting the HEAD of the remote: {0}")]
+    RemoteHead(git2::Error),
+    #[error("The HEAD of the remote is not a branch: {0}")]
+    NotABranch(String),
+}
+
+/// The branch the HEAD of the remote repository points to
+pub fn detect_default_branch(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+    credentials: Option<&GitCredentials>,
+) -> Result<String, DetectDefaultBranchError> {
+    state.check_host(handle.git_host(settings.git_auth))?;
+
+    let url = handle.git_url(settings.git_auth);
+    if state.explain_commands {
+        explain(
+            &url,
+            &format!("$ git ls-remote --symref {} HEAD", shell_quote(&url)),
+            false,
+        );
+    }
+
+    let mut remote = git2::Remote::create_detached(url.as_str())
+        .map_err(DetectDefaultBranchError::CreateRemote)?;
+    remote
+        .connect_auth(
+            git2::Direction::Fetch,
+            Some(callbacks(state, settings, credentials)),
+            None,
+        )
+        .map_err(DetectDefaultBranchError::ConnectRemote)?;
+    let head = remote
+        .default_branch()
+        .map_err(DetectDefaultBranchError::RemoteHead)?;
+    let head = head.as_str().unwrap_or_default();
+    head.strip_prefix("refs/heads/")
+        .map(str::to_string)
+        .ok_or_else(|| DetectDefaultBranchError::NotABranch(head.to_string()))
+}
+
+/// Where `default_ref` is fetched to, since it needn't be a branch
+const DEFAULT_REF: &str = "refs/update-daemon/default-ref";
+
+/// The remote the update branch is pushed to when `fork` is set
+const FORK_REMOTE: &str = "update-daemon-fork";
+
+/// The reference the update is based on: the fetched `default_ref` if it is set,
+/// otherwise the remote default branch
+fn default_base<'r>(
+    settings: &UpdateSettings,
+    repo: &'r Repository,
+) -> Result<git2::Reference<'r>, git2::Error> {
+    match settings.default_ref {
+        Some(_) => repo.find_reference(DEFAULT_REF),
+        None => repo
+            .find_branch(
+                &remote_branch(settings, &settings.default_branch),
+                BranchType::Remote,
+            )
+            .map(git2::Branch::into_reference),
+    }
+}
+
+/// Name of `default_base` as given to git commands
+fn default_base_name(settings: &UpdateSettings) -> String {
+    match settings.default_ref {
+        Some(_) => DEFAULT_REF.to_string(),
+        None => remote_branch(settings, &settings.default_branch),
+    }
+}
+
+/// Where the human commits kept in the update branch with `rebase_on_human_commits` end
+const UPDATE_BASE_REF: &str = "refs/update-daemon/update-base";
+
+/// The reference the update commit goes on top of: the end of the human commits kept
+/// in the update branch if there are any, otherwise `default_base`
+fn update_base<'r>(
+    settings: &UpdateSettings,
+    repo: &'r Repository,
+) -> Result<git2::Reference<'r>, git2::Error> {
+    repo.find_reference(UPDATE_BASE_REF)
+        .or_else(|_| default_base(settings, repo))
+}
+
+/// Name of `update_base` as given to git commands
+fn update_base_name(settings: &UpdateSettings, repo: &Repository) -> String {
+    match repo.find_reference(UPDATE_BASE_REF) {
+        Ok(_) => UPDATE_BASE_REF.to_string(),
+        Err(_) => default_base_name(settings),
+    }
+}
+
+/// Name of the remote-tracking branch of `branch`
+fn remote_branch(settings: &UpdateSettings, branch: &str) -> String {
+    format!("{}/{}", settings.remote_name, branch)
+}
+
+/// Name of the remote the update branch is pushed to: the fork, if there is one
+fn update_remote(settings: &UpdateSettings) -> &str {
+    match settings.fork {
+        Some(_) => FORK_REMOTE,
+        None => &settings.remote_name,
+    }
+}
+
+/// Name of the remote-tracking branch of the update branch
+fn remote_update_branch(settings: &UpdateSettings) -> String {
+    format!("{}/{}", update_remote(settings), settings.update_branch)
+}
+
+/// Point the remote `name` at `url`, adding it if it is missing
+fn set_remote<'r>(
+    repo: &'r Repository,
+    name: &str,
+    url: &str,
+    explain_command: &dyn Fn(String),
+) -> Result<git2::Remote<'r>, InitError> {
+    match repo.find_remote(name) {
+        Ok(_) => {
+            explain_command(format!(
+                "git remote set-url {} {}",
+                shell_quote(name),
+                shell_quote(url)
+            ));
+            repo.remote_set_url(name, url)
+                .map_err(InitError::SetRemoteUrl)?;
+            repo.find_remote(name).map_err(InitError::FindRemote)
+        }
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            explain_command(format!(
+                "git remote add {} {}",
+                shell_quote(name),
+                shell_quote(url)
+            ));
+            repo.remote(name, url).map_err(InitError::CreateRemote)
+        }
+        Err(e) => Err(InitError::FindRemote(e)),
+    }
+}
+
 /// Initialize the repository:
 /// If there is a repository cloned from the same URL, open it,
 /// Otherwise clone it.
-/// Reset the local default branch to the upstream one.
+/// Fetch `default_ref`, if it is set.
+/// Fetch the update branch from the fork, if `fork` is set.
+/// Reset the local default branch to the upstream one (or `default_ref`).
 pub fn init_repo(
     state: &UpdateState,
     settings: &UpdateSettings,
     handle: &RepoHandle,
+    credentials: Option<&GitCredentials>,
 ) -> Result<Repository, InitError> {
-    let url = handle.to_string();
-    let urlhash = calculate_hash(&url);
+    state.check_host(handle.git_host(settings.git_auth))?;
+    let fork = match &settings.fork {
+        Some(fork) => {
+            let fork = handle
+                .fork(fork)
+                .ok_or_else(|| InitError::ForkUnsupported(handle.to_string()))?;
+            state.check_host(fork.git_host(settings.git_auth))?;
+            Some(fork)
+        }
+        None => None,
+    };
+
+    let url = handle.git_url(settings.git_auth);
     let mut repo_dir = state.cache_dir.clone();
-    repo_dir.push(urlhash);
+    repo_dir.push(cache_dir_name(settings, handle));
+
+    let explain_command = |command: String| {
+        if state.explain_commands {
+            explain(&url, &format!("$ {}", command), false);
+        }
+    };
 
     let mut fetch_options = FetchOptions::new();
-    fetch_options.remote_callbacks(callbacks(state));
+    fetch_options.remote_callbacks(callbacks(state, settings, credentials));
 
     let repo = if repo_dir.exists() {
         debug!("Repository {} found at {:?}", handle, repo_dir);
 
-        let repo = Repository::open(repo_dir).map_err(InitError::OpenRepository)?;
+        let repo = Repository::open(&repo_dir).map_err(InitError::OpenRepository)?;
 
-        {
-            repo.remote_set_url("origin", &url)
-                .map_err(InitError::SetRemoteUrl)?;
+        explain_command(format!("cd {}", shell_quote(&repo_dir.to_string_lossy())));
 
-            let mut remote = repo.find_remote("origin").map_err(InitError::FindRemote)?;
+        {
+            // The remote is missing if `remote_name` changed since the clone
+            let mut remote = set_remote(&repo, &settings.remote_name, &url, &explain_command)?;
 
             remote
-                .connect_auth(git2::Direction::Fetch, Some(callbacks(state)), None)
+                .connect_auth(
+                    git2::Direction::Fetch,
+                    Some(callbacks(state, settings, credentials)),
+                    None,
+                )
                 .map_err(InitError::ConnectRemote)?;
 
             remote.prune(None).map_err(InitError::Prune)?;
 
             remote.disconnect().map_err(InitError::DisconnectRemote)?;
 
+            explain_command(format!(
+                "git fetch --prune {} {} {}",
+                shell_quote(&settings.remote_name),
+                shell_quote(&settings.default_branch),
+                shell_quote(&settings.update_branch)
+            ));
             remote
                 .fetch(&[&settings.default_branch], Some(&mut fetch_options), None)
                 .map_err(InitError::FetchDefault)?;
@@ -207,25 +616,86 @@ pub fn init_repo(
 
         create_dir(&repo_dir).map_err(InitError::CreateCloneDir)?;
 
+        explain_command(format!(
+            "git clone --origin {} {} {} && cd {2}",
+            shell_quote(&settings.remote_name),
+            shell_quote(&url),
+            shell_quote(&repo_dir.to_string_lossy())
+        ));
+
+        // Abort the clone once it has received more than max_repo_size
+        let too_large = Cell::new(false);
+        let mut clone_callbacks = callbacks(state, settings, credentials);
+        if let Some(max_repo_size) = settings.max_repo_size {
+            let too_large = &too_large;
+            clone_callbacks.transfer_progress(move |progress| {
+                too_large.set(progress.received_bytes() as u64 > max_repo_size);
+                !too_large.get()
+            });
+        }
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(clone_callbacks);
+
         let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fetch_options);
+        builder
+            .fetch_options(fetch_options)
+            .remote_create(|repo, _, url| repo.remote(&settings.remote_name, url));
         match builder.clone(&url, &repo_dir) {
             Ok(repo) => repo,
             Err(e) => {
                 remove_dir_all(repo_dir).map_err(InitError::CleanFailedClone)?;
-                return Err(InitError::Clone(e));
+                return Err(match settings.max_repo_size {
+                    Some(max_repo_size) if too_large.get() => InitError::TooLarge(max_repo_size),
+                    _ => InitError::Clone(e),
+                });
             }
         }
     };
 
+    if let Some(default_ref) = &settings.default_ref {
+        let refspec = format!("+{}:{}", default_ref, DEFAULT_REF);
+        explain_command(format!(
+            "git fetch --no-tags {} {}",
+            shell_quote(&settings.remote_name),
+            shell_quote(&refspec)
+        ));
+
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks(state, settings, credentials));
+        // With automatic tag following, libgit2 doesn't update the destination of tags
+        fetch_options.download_tags(git2::AutotagOption::None);
+        repo.find_remote(&settings.remote_name)
+            .map_err(InitError::FindRemote)?
+            .fetch(&[&refspec], Some(&mut fetch_options), None)
+            .map_err(InitError::FetchDefaultRef)?;
+    }
+
+    if let Some(fork) = fork {
+        let fork_url = fork.git_url(settings.git_auth);
+        let mut remote = set_remote(&repo, FORK_REMOTE, &fork_url, &explain_command)?;
+        explain_command(format!(
+            "git fetch --prune {} {}",
+            FORK_REMOTE,
+            shell_quote(&settings.update_branch)
+        ));
+
+        let mut fetch_options = FetchOptions::new();
+        fetch_options
+            .remote_callbacks(callbacks(state, settings, credentials))
+            .prune(git2::FetchPrune::On);
+        remote
+            .fetch(&[&settings.update_branch], Some(&mut fetch_options), None)
+            .map_err(InitError::FetchFork)?;
+    }
+
     {
-        let default_branch = repo
-            .find_branch(
-                &format!("origin/{}", settings.default_branch),
-                BranchType::Remote,
-            )
-            .map_err(InitError::FindDefaultBranch)?;
+        let default_branch = default_base(settings, &repo).map_err(InitError::FindDefaultBranch)?;
 
+        explain_command(format!(
+            "git checkout -B {} {}",
+            shell_quote(&settings.default_branch),
+            shell_quote(&default_base_name(settings))
+        ));
         force_checkout_branch(&repo, &settings.default_branch, &default_branch)?;
     }
 
@@ -240,68 +710,181 @@ pub enum SetupUpdateBranchError {
     PeelUpdateBranchCommit(git2::Error),
     #[error("Error peeling to default branch commit: {0}")]
     PeelDefaultBranchCommit(git2::Error),
-    #[error("There are human commits in the update branch")]
-    HumanCommitsInUpdateBranch,
+    #[error("There are human commits in the update branch, authored by {0:?} (add it to own_commit_emails if it is update-daemon's)")]
+    HumanCommitsInUpdateBranch(String),
+    #[error("The human commit {0} of the update branch conflicts with the default branch")]
+    HumanCommitConflict(String),
+    #[error("Error keeping the human commits of the update branch: {0}")]
+    KeepHumanCommits(git2::Error),
     #[error("Failed to force-checkout update branch: {0}")]
     ForceCheckoutUpdateBranch(#[from] ForceCheckoutBranchError),
     #[error("Failed to count ahead/behind for the update branch: {0}")]
     GraphAheadBehind(git2::Error),
 }
 
+/// With `rebase_on_human_commits`, the human commits of the update branch on top of
+/// the default branch, if there are any: the last of them if they already are right on
+/// top of it, otherwise its replay, cherry-picking them (without the update commits) on
+/// top of it. The replayed commits keep their committer, so that replaying them again
+/// gives the same commits.
+fn keep_human_commits(
+    settings: &UpdateSettings,
+    repo: &Repository,
+    update_commit: &git2::Commit,
+    default_commit: &git2::Commit,
+) -> Result<Option<git2::Oid>, SetupUpdateBranchError> {
+    let commits = (|| {
+        let mut walk = repo.revwalk()?;
+        walk.push(update_commit.id())?;
+        walk.hide(default_commit.id())?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        walk.map(|id| repo.find_commit(id?))
+            .collect::<Result<Vec<_>, _>>()
+    })()
+    .map_err(SetupUpdateBranchError::KeepHumanCommits)?;
+    let is_human = |commit: &git2::Commit| {
+        !settings.is_own_commit_email(commit.author().email().unwrap_or_default())
+    };
+    let humans: Vec<_> = commits.iter().filter(|commit| is_human(commit)).collect();
+    let Some(last) = humans.last() else {
+        return Ok(None);
+    };
+
+    let mut parent = default_commit.id();
+    let on_top = commits[..humans.len()].iter().all(|commit| {
+        let linear = commit.parent_ids().eq(std::iter::once(parent));
+        parent = commit.id();
+        is_human(commit) && linear
+    });
+    if on_top {
+        return Ok(Some(last.id()));
+    }
+
+    let mut base = default_commit.clone();
+    for commit in humans {
+        // Merges are replayed against their first parent
+        let mainline = if commit.parent_count() > 1 { 1 } else { 0 };
+        let mut index = repo
+            .cherrypick_commit(commit, &base, mainline, None)
+            .map_err(SetupUpdateBranchError::KeepHumanCommits)?;
+        if index.has_conflicts() {
+            return Err(SetupUpdateBranchError::HumanCommitConflict(
+                commit.id().to_string(),
+            ));
+        }
+        let tree = index
+            .write_tree_to(repo)
+            .and_then(|id| repo.find_tree(id))
+            .map_err(SetupUpdateBranchError::KeepHumanCommits)?;
+        // Already in the default branch
+        if tree.id() == base.tree_id() {
+            continue;
+        }
+        base = repo
+            .commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                commit.message_raw().unwrap_or_default(),
+                &tree,
+                &[&base],
+            )
+            .and_then(|id| repo.find_commit(id))
+            .map_err(SetupUpdateBranchError::KeepHumanCommits)?;
+    }
+    Ok(Some(base.id()))
+}
+
+/// Check out the update branch, based either on its remote counterpart or,
+/// if that doesn't exist or is outdated, on the default branch (or `default_ref`).
+/// With `rebase_on_human_commits`, it is based on the human commits of the remote
+/// update branch instead, if it has any. Returns the name of the reference it is based on.
 pub fn setup_update_branch(
     settings: &UpdateSettings,
     repo: &Repository,
-) -> Result<(), SetupUpdateBranchError> {
-    let update_branch = repo.find_branch(
-        &format!("origin/{}", settings.update_branch),
-        BranchType::Remote,
-    );
-
-    let default_branch = repo
-        .find_branch(
-            &format!("origin/{}", &settings.default_branch),
-            BranchType::Remote,
-        )
-        .map_err(SetupUpdateBranchError::FindDefaultBranch)?;
+) -> Result<String, SetupUpdateBranchError> {
+    // Left from a previous run
+    if let Ok(mut update_base) = repo.find_reference(UPDATE_BASE_REF) {
+        update_base
+            .delete()
+            .map_err(SetupUpdateBranchError::KeepHumanCommits)?;
+    }
+
+    let update_branch = repo.find_branch(&remote_update_branch(settings), BranchType::Remote);
+
+    let default_branch =
+        default_base(settings, repo).map_err(SetupUpdateBranchError::FindDefaultBranch)?;
 
-    let branch = if let Ok(b) = update_branch {
+    let default_branch_name = default_base_name(settings);
+    let update_branch_name = remote_update_branch(settings);
+
+    let (branch, base) = if let Ok(b) = update_branch {
         let update_branch_commit = b
             .get()
             .peel_to_commit()
             .map_err(SetupUpdateBranchError::PeelUpdateBranchCommit)?;
         let default_branch_commit = default_branch
-            .get()
             .peel_to_commit()
             .map_err(SetupUpdateBranchError::PeelDefaultBranchCommit)?;
+        if settings.rebase_on_human_commits {
+            let kept = keep_human_commits(
+                settings,
+                repo,
+                &update_branch_commit,
+                &default_branch_commit,
+            )?;
+            if let Some(id) = kept {
+                let update_base = repo
+                    .reference(
+                        UPDATE_BASE_REF,
+                        id,
+                        true,
+                        "update-daemon: keep human commits",
+                    )
+                    .map_err(SetupUpdateBranchError::KeepHumanCommits)?;
+                force_checkout_branch(repo, &settings.update_branch, &update_base)?;
+                return Ok(UPDATE_BASE_REF.to_string());
+            }
+        }
         // NB: we need to handle the case of update branch even with default
         // branch specially, otherwise we can get spurious "human commits"
         // errors where the update branch doesn't even have commits.
+        let author = update_branch_commit.author();
+        let author_email = author.email().unwrap_or_default();
         if update_branch_commit.id() != default_branch_commit.id()
-            && update_branch_commit.author().email() != Some(&settings.author.email)
+            && !settings.is_own_commit_email(author_email)
         {
-            return Err(SetupUpdateBranchError::HumanCommitsInUpdateBranch);
+            return Err(SetupUpdateBranchError::HumanCommitsInUpdateBranch(
+                author_email.to_string(),
+            ));
         }
         let (_ahead, behind) = repo
             .graph_ahead_behind(update_branch_commit.id(), default_branch_commit.id())
             .map_err(SetupUpdateBranchError::GraphAheadBehind)?;
         if behind > 0 {
             // update branch is outdated, reset to default, as we'll have to force-push anyway
-            default_branch
+            (default_branch, default_branch_name)
         } else {
             // update branch isn't outdated, so use it
-            b
+            (b.into_reference(), update_branch_name)
         }
     } else {
-        default_branch
+        (default_branch, default_branch_name)
     };
 
     force_checkout_branch(repo, &settings.update_branch, &branch)?;
 
-    Ok(())
+    Ok(base)
 }
 
 #[derive(Debug, Error)]
 pub enum CommitError {
+    #[error("{0} is not in the commit, it appears to be gitignored")]
+    LockNotTracked(String),
+    #[error(
+        "Nothing to commit, the changes to {0} were not staged; is it gitignored or untracked?"
+    )]
+    NothingStaged(String),
     #[error("Error getting index file: {0}")]
     Index(git2::Error),
     #[error("Error adding files to index: {0}")]
@@ -330,10 +913,106 @@ pub enum CommitError {
     KeyGet(gpgme::Error),
     #[error("Error adding signer key: {0}")]
     SignerAdd(gpgme::Error),
+    #[error("Couldn't read the signing passphrase file {0:?}: {1}")]
+    PassphraseFile(PathBuf, std::io::Error),
+    #[error("Signing with ssh needs signing_key or ssh_key_path to be set")]
+    NoSshSigningKey,
+    #[error("Error running ssh-keygen: {0}")]
+    SshKeygen(std::io::Error),
+    #[error("Error signing commit with ssh-keygen: {0}")]
+    SshSign(String),
     #[error("Error updating reference: {0}")]
     ReferenceUpdate(git2::Error),
 }
 
+/// Armored GPG signature of `commit_buf`
+fn sign_gpg(settings: &UpdateSettings, commit_buf: &[u8]) -> Result<Vec<u8>, CommitError> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp).map_err(CommitError::Sign)?;
+    let mut outbuf = Vec::new();
+
+    // If the configuration specifies a signing key ID or fingerprint,
+    // obtain the secret key from the gpg-agent and add it to the list of signers
+    if let Some(signing_key) = &settings.signing_key {
+        let key = ctx
+            .get_secret_key(signing_key)
+            .map_err(CommitError::KeyGet)?;
+        ctx.add_signer(&key).map_err(CommitError::SignerAdd)?;
+    };
+
+    ctx.set_armor(true);
+    let Some(passphrase_file) = &settings.signing_passphrase_file else {
+        ctx.sign_detached(commit_buf, &mut outbuf)
+            .map_err(CommitError::Sign)?;
+        return Ok(outbuf);
+    };
+
+    // Loopback pinentry has gpg-agent ask for the passphrase here, instead of prompting
+    let passphrase = std::fs::read_to_string(passphrase_file)
+        .map_err(|e| CommitError::PassphraseFile(passphrase_file.clone(), e))?;
+    let passphrase = passphrase.trim_end();
+    ctx.set_pinentry_mode(PinentryMode::Loopback)
+        .map_err(CommitError::Sign)?;
+    ctx.with_passphrase_provider(
+        |request: PassphraseRequest, out: &mut dyn std::io::Write| {
+            // Another attempt would only get the same wrong passphrase
+            if request.prev_attempt_failed {
+                return Err(gpgme::Error::BAD_PASSPHRASE);
+            }
+            out.write_all(passphrase.as_bytes())?;
+            out.write_all(b"\n")?;
+            Ok(())
+        },
+        |ctx| ctx.sign_detached(commit_buf, &mut outbuf),
+    )
+    .map_err(CommitError::Sign)?;
+    Ok(outbuf)
+}
+
+/// SSH signature of `commit_buf`, made by `ssh-keygen` like git does with the key at
+/// `signing_key`, or `ssh_key_path` if it isn't set
+fn sign_ssh(settings: &UpdateSettings, commit_buf: &[u8]) -> Result<Vec<u8>, CommitError> {
+    let key = settings
+        .signing_key
+        .as_deref()
+        .map(Path::new)
+        .or(settings.ssh_key_path.as_deref())
+        .ok_or(CommitError::NoSshSigningKey)?;
+
+    let mut ssh_keygen = Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-n", "git", "-f"])
+        .arg(key)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(CommitError::SshKeygen)?;
+    // The signature is only written once all of the input is read
+    ssh_keygen
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(commit_buf)
+        .map_err(CommitError::SshKeygen)?;
+    let output = ssh_keygen
+        .wait_with_output()
+        .map_err(CommitError::SshKeygen)?;
+    if !output.status.success() {
+        return Err(CommitError::SshSign(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Message of the update commit: `commit_message_template` if it is set,
+/// otherwise `title` followed by the diff
+fn commit_message(settings: &UpdateSettings, diff: &str) -> String {
+    match &settings.commit_message_template {
+        Some(template) => render(template, &[("diff", diff)]),
+        None => format!("{}\n\n{}", settings.title, diff),
+    }
+}
+
 /// Stage all changed files and add them to index.
 /// `diff` is going to be the commit message.
 pub fn commit(
@@ -355,13 +1034,22 @@ pub fn commit(
         .find_tree(index.write_tree().map_err(CommitError::WriteTree)?)
         .map_err(CommitError::FindTree)?;
 
+    let lock_path = settings.lock_repo_path();
+    if tree.get_path(&lock_path).is_err() {
+        return Err(CommitError::LockNotTracked(lock_path.display().to_string()));
+    }
+
     let parent = &repo
         .head()
         .map_err(CommitError::Head)?
         .peel_to_commit()
         .map_err(CommitError::PeelHead)?;
 
-    let message = format!("{}\n\n{}", settings.title, diff);
+    if tree.id() == parent.tree_id() {
+        return Err(CommitError::NothingStaged(lock_path.display().to_string()));
+    }
+
+    let message = commit_message(settings, &diff);
 
     if settings.sign_commits {
         // Create commit object
@@ -369,24 +1057,14 @@ pub fn commit(
             .commit_create_buffer(&author, &author, &message, &tree, &[parent])
             .map_err(CommitError::Buffer)?;
 
-        let mut ctx = Context::from_protocol(Protocol::OpenPgp).map_err(CommitError::Sign)?;
-        let mut outbuf = Vec::new();
-
-        // If the configuration specifies a signing key ID or fingerprint,
-        // obtain the secret key from the gpg-agent and add it to the list of signers
-        if let Some(signing_key) = &settings.signing_key {
-            let key = ctx
-                .get_secret_key(signing_key)
-                .map_err(CommitError::KeyGet)?;
-            ctx.add_signer(&key).map_err(CommitError::SignerAdd)?;
-        };
-
         // Sign commit
-        ctx.set_armor(true);
-        ctx.sign_detached(&*commit_buf, &mut outbuf)
-            .map_err(CommitError::Sign)?;
+        let outbuf = match settings.signing_method {
+            SigningMethod::Gpg => sign_gpg(settings, &commit_buf)?,
+            SigningMethod::Ssh => sign_ssh(settings, &commit_buf)?,
+        };
         let out = str::from_utf8(&outbuf).map_err(CommitError::Utf8)?;
 
+        // Both kinds of signatures go in the gpgsig header
         let commit_content = str::from_utf8(&commit_buf)
             .map_err(CommitError::Utf8)?
             .to_string();
@@ -418,18 +1096,41 @@ pub enum PushError {
     FindRemote(git2::Error),
     #[error("Error pushing to remote: {0}")]
     Push(git2::Error),
+    #[error("Error finding the update branch commit: {0}")]
+    FindUpdateBranch(git2::Error),
+    #[error("Error reading the signature of commit {0}: {1}")]
+    ExtractSignature(git2::Oid, git2::Error),
+    #[error("Commit {0} is not signed, although sign_commits is set")]
+    Unsigned(git2::Oid),
 }
 
-/// Push the changes to the `origin` remote.
+/// Push the changes to the `remote_name` remote, or the fork if there is one.
+/// With `sign_commits`, refuse to push an unsigned commit.
 pub fn push(
     state: &UpdateState,
     settings: &UpdateSettings,
     repo: &Repository,
+    credentials: Option<&GitCredentials>,
 ) -> Result<(), PushError> {
-    let mut remote = repo.find_remote("origin").map_err(PushError::FindRemote)?;
+    if settings.sign_commits {
+        let commit = repo
+            .refname_to_id(&format!("refs/heads/{}", settings.update_branch))
+            .map_err(PushError::FindUpdateBranch)?;
+        match repo.extract_signature(&commit, None) {
+            Ok(_) => {}
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                return Err(PushError::Unsigned(commit))
+            }
+            Err(e) => return Err(PushError::ExtractSignature(commit, e)),
+        }
+    }
+
+    let mut remote = repo
+        .find_remote(update_remote(settings))
+        .map_err(PushError::FindRemote)?;
 
     let mut push_options = PushOptions::new();
-    push_options.remote_callbacks(callbacks(state));
+    push_options.remote_callbacks(callbacks(state, settings, credentials));
     remote
         .push(
             //         ↓ force-push
@@ -444,6 +1145,35 @@ pub fn push(
     Ok(())
 }
 
+/// Whether the update branch was fetched from the remote it is pushed to
+fn has_remote_update_branch(settings: &UpdateSettings, repo: &Repository) -> bool {
+    repo.find_branch(&remote_update_branch(settings), BranchType::Remote)
+        .is_ok()
+}
+
+/// Delete the update branch from the remote it is pushed to
+pub fn delete_update_branch(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    repo: &Repository,
+    credentials: Option<&GitCredentials>,
+) -> Result<(), PushError> {
+    let mut remote = repo
+        .find_remote(update_remote(settings))
+        .map_err(PushError::FindRemote)?;
+
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(callbacks(state, settings, credentials));
+    remote
+        .push(
+            &[&format!(":refs/heads/{}", settings.update_branch)],
+            Some(&mut push_options),
+        )
+        .map_err(PushError::Push)?;
+
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum ResetError {
     #[error("Error soft-resetting update branch to default: {0}")]
@@ -454,17 +1184,13 @@ pub enum ResetError {
     PeelDefaultBranchCommit(git2::Error),
 }
 
+/// Reset the update branch to `update_base`, keeping the changes staged
 pub fn soft_reset_to_default(
     settings: &UpdateSettings,
     repo: &Repository,
 ) -> Result<(), ResetError> {
-    let commit = repo
-        .find_branch(
-            &format!("origin/{}", &settings.default_branch),
-            BranchType::Remote,
-        )
+    let commit = update_base(settings, repo)
         .map_err(ResetError::FindDefaultBranch)?
-        .into_reference()
         .peel_to_commit()
         .map_err(ResetError::PeelDefaultBranchCommit)?;
     repo.reset(commit.as_object(), ResetType::Soft, None)
@@ -472,6 +1198,118 @@ pub fn soft_reset_to_default(
     Ok(())
 }
 
+#[derive(Debug, Error)]
+pub enum CheckoutRemoteBranchError {
+    #[error("Error finding remote for existing repository: {0}")]
+    FindRemote(git2::Error),
+    #[error("Error fetching the branch: {0}")]
+    Fetch(git2::Error),
+    #[error("Error finding the fetched branch: {0}")]
+    FindBranch(git2::Error),
+    #[error("Error peeling to branch commit: {0}")]
+    PeelBranchCommit(git2::Error),
+    #[error("Failed to force-checkout the branch: {0}")]
+    ForceCheckoutBranch(#[from] ForceCheckoutBranchError),
+}
+
+pub fn checkout_remote_branch(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    repo: &Repository,
+    branch: &str,
+    credentials: Option<&GitCredentials>,
+) -> Result<git2::Oid, CheckoutRemoteBranchError> {
+    let mut remote = repo
+        .find_remote(&settings.remote_name)
+        .map_err(CheckoutRemoteBranchError::FindRemote)?;
+
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks(state, settings, credentials));
+    remote
+        .fetch(&[branch], Some(&mut fetch_options), None)
+        .map_err(CheckoutRemoteBranchError::Fetch)?;
+
+    let remote_branch = repo
+        .find_branch(&remote_branch(settings, branch), BranchType::Remote)
+        .map_err(CheckoutRemoteBranchError::FindBranch)?;
+    let commit = remote_branch
+        .get()
+        .peel_to_commit()
+        .map_err(CheckoutRemoteBranchError::PeelBranchCommit)?;
+
+    force_checkout_branch(repo, branch, remote_branch.get())?;
+
+    Ok(commit.id())
+}
+
+#[derive(Debug, Error)]
+pub enum CompareError {
+    #[error("Error retrieving head: {0}")]
+    Head(git2::Error),
+    #[error("Error peeling head to tree: {0}")]
+    PeelHead(git2::Error),
+    #[error("Error peeling to update branch tree: {0}")]
+    PeelUpdateBranchTree(git2::Error),
+}
+
+/// Check whether the remote update branch already has the same tree as HEAD,
+/// i.e. whether pushing HEAD would change nothing but commit metadata.
+pub fn update_branch_up_to_date(
+    settings: &UpdateSettings,
+    repo: &Repository,
+) -> Result<bool, CompareError> {
+    let update_branch = match repo.find_branch(&remote_update_branch(settings), BranchType::Remote)
+    {
+        Ok(b) => b,
+        Err(_) => return Ok(false),
+    };
+    let update_branch_tree = update_branch
+        .get()
+        .peel_to_tree()
+        .map_err(CompareError::PeelUpdateBranchTree)?;
+    let head_tree = repo
+        .head()
+        .map_err(CompareError::Head)?
+        .peel_to_tree()
+        .map_err(CompareError::PeelHead)?;
+    Ok(head_tree.id() == update_branch_tree.id())
+}
+
+#[derive(Debug, Error)]
+pub enum CountAheadError {
+    #[error("Error retrieving head: {0}")]
+    Head(git2::Error),
+    #[error("Error peeling head to commit: {0}")]
+    PeelHead(git2::Error),
+    #[error("Error finding default branch on repository: {0}")]
+    FindDefaultBranch(git2::Error),
+    #[error("Error peeling to default branch commit: {0}")]
+    PeelDefaultBranchCommit(git2::Error),
+    #[error("Failed to count ahead/behind for the update branch: {0}")]
+    GraphAheadBehind(git2::Error),
+}
+
+/// Count the commits of HEAD that aren't on the remote default branch (or `default_ref`),
+/// nor among the human commits kept in the update branch
+pub fn commits_ahead_of_default(
+    settings: &UpdateSettings,
+    repo: &Repository,
+) -> Result<usize, CountAheadError> {
+    let head = repo
+        .head()
+        .map_err(CountAheadError::Head)?
+        .peel_to_commit()
+        .map_err(CountAheadError::PeelHead)?;
+    let default_branch = update_base(settings, repo)
+        .map_err(CountAheadError::FindDefaultBranch)?
+        .peel_to_commit()
+        .map_err(CountAheadError::PeelDefaultBranchCommit)?;
+    let (ahead, _behind) = repo
+        .graph_ahead_behind(head.id(), default_branch.id())
+        .map_err(CountAheadError::GraphAheadBehind)?;
+    Ok(ahead)
+}
+
 #[derive(Debug, Error)]
 pub enum ForceCheckoutBranchError {
     #[error("Error resetting update branch: {0}")]
@@ -487,10 +1325,9 @@ pub enum ForceCheckoutBranchError {
 pub fn force_checkout_branch(
     repo: &git2::Repository,
     new_branch_name: &str,
-    branch: &git2::Branch<'_>,
+    branch: &git2::Reference<'_>,
 ) -> Result<(), ForceCheckoutBranchError> {
     let commit = branch
-        .get()
         .peel_to_commit()
         .map_err(ForceCheckoutBranchError::PeelBranchCommit)?;
 
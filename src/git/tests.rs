@@ -0,0 +1,914 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+
+fn test_state(cache_dir: PathBuf, allowed_hosts: Option<Vec<String>>) -> UpdateState {
+    UpdateState {
+        cache_dir,
+        global_ssh_config: None,
+        local_ssh_config: None,
+        git_semaphore: None,
+        dry_run: false,
+        explain_commands: false,
+        allowed_hosts,
+        shutdown: Default::default(),
+    }
+}
+
+fn test_settings() -> UpdateSettings {
+    UpdateSettingsOptional {
+        author: Some(Author {
+            name: "update-daemon".to_string(),
+            email: "update-daemon@example.com".to_string(),
+        }),
+        cooldown: Some(1000),
+        ..Default::default()
+    }
+    .try_into()
+    .unwrap()
+}
+
+/// Create a bare "remote" repository with a single commit on `master`
+/// containing `files`, given as (name, contents)
+fn init_origin_with(path: &Path, files: &[(&str, &str)]) -> Repository {
+    let repo = Repository::init_bare(path).unwrap();
+    {
+        let mut tree = repo.treebuilder(None).unwrap();
+        for (name, contents) in files {
+            let blob = repo.blob(contents.as_bytes()).unwrap();
+            tree.insert(name, blob, 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let author = Signature::now("Human", "human@example.com").unwrap();
+        repo.commit(
+            Some("refs/heads/master"),
+            &author,
+            &author,
+            "Initial commit",
+            &tree,
+            &[],
+        )
+        .unwrap();
+        repo.set_head("refs/heads/master").unwrap();
+    }
+    repo
+}
+
+/// Create a bare "remote" repository with `lock` committed on `master`
+fn init_origin(path: &Path, lock: &str) -> Repository {
+    init_origin_with(path, &[("flake.lock", lock)])
+}
+
+/// The git part of a single pass of `update_repo`, with `lock` being the result
+/// of the flake update. Returns whether the update branch was pushed.
+fn update_pass(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+    lock: &str,
+) -> bool {
+    let credentials = crate::request::git_credentials(settings, handle).unwrap();
+    let repo = UDRepo::init(state, settings, handle, credentials).unwrap();
+    repo.setup_update_branch(settings).unwrap();
+    std::fs::write(repo.path().unwrap().join("flake.lock"), lock).unwrap();
+    repo.soft_reset_to_default(settings).unwrap();
+    repo.commit(settings, lock.to_string()).unwrap();
+    if repo.update_branch_up_to_date(settings).unwrap() {
+        return false;
+    }
+    repo.push(state, settings).unwrap();
+    true
+}
+
+#[test]
+fn unchanged_update_branch_is_not_pushed_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+    let update_branch_commit = || {
+        origin
+            .find_branch(&settings.update_branch, BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap()
+            .id()
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let pushed = update_branch_commit();
+
+    // The next pass sees its own commit and has nothing to do
+    assert!(!update_pass(&state, &settings, &handle, "new"));
+    assert_eq!(update_branch_commit(), pushed);
+
+    // ...until there is an actual update
+    assert!(update_pass(&state, &settings, &handle, "newer"));
+    assert_ne!(update_branch_commit(), pushed);
+}
+
+#[test]
+fn hosts_outside_allowlist_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(
+        dir.path().to_path_buf(),
+        Some(vec!["github.com".to_string()]),
+    );
+    let settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: "ssh://git@example.com/repo".to_string(),
+    };
+
+    assert!(matches!(
+        UDRepo::init(&state, &settings, &handle, None),
+        Err(InitError::HostNotAllowed(_))
+    ));
+    // Nothing was cloned
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn ignored_lock_is_not_committed_silently() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin_with(
+        &dir.path().join("origin.git"),
+        &[(".gitignore", "flake.lock\n")],
+    );
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    repo.setup_update_branch(&settings).unwrap();
+    std::fs::write(repo.path().unwrap().join("flake.lock"), "new").unwrap();
+    assert!(matches!(
+        repo.commit(&settings, "new".to_string()),
+        Err(CommitError::LockNotTracked(_))
+    ));
+}
+
+#[test]
+fn own_commit_emails_are_not_human() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+    {
+        let master = origin.head().unwrap().peel_to_commit().unwrap();
+        let blob = origin.blob(b"new").unwrap();
+        let mut tree = origin.treebuilder(Some(&master.tree().unwrap())).unwrap();
+        tree.insert("flake.lock", blob, 0o100644).unwrap();
+        let tree = origin.find_tree(tree.write().unwrap()).unwrap();
+        let author = Signature::now("Old bot", "old-bot@example.com").unwrap();
+        origin
+            .commit(
+                Some("refs/heads/automatic-update"),
+                &author,
+                &author,
+                "Update",
+                &tree,
+                &[&master],
+            )
+            .unwrap();
+    }
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    assert!(matches!(
+        repo.setup_update_branch(&settings),
+        Err(SetupUpdateBranchError::HumanCommitsInUpdateBranch(email)) if email == "old-bot@example.com"
+    ));
+
+    settings.own_commit_emails = vec!["old-bot@example.com".to_string()];
+    repo.setup_update_branch(&settings).unwrap();
+}
+
+#[test]
+fn update_is_based_on_default_ref() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+    let tagged = {
+        let master = origin.head().unwrap().peel_to_commit().unwrap();
+        let author = Signature::now("Human", "human@example.com").unwrap();
+        let tagged = origin
+            .commit(
+                None,
+                &author,
+                &author,
+                "Release",
+                &master.tree().unwrap(),
+                &[&master],
+            )
+            .unwrap();
+        origin
+            .tag(
+                "v1",
+                &origin.find_object(tagged, None).unwrap(),
+                &author,
+                "v1",
+                false,
+            )
+            .unwrap();
+        tagged
+    };
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.default_ref = Some("v1".to_string());
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let pushed = origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .unwrap()
+        .get()
+        .peel_to_commit()
+        .unwrap();
+    assert_eq!(pushed.parent_id(0).unwrap(), tagged);
+}
+
+#[test]
+fn lock_in_flake_subdir_is_committed() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin_with(&dir.path().join("origin.git"), &[]);
+    {
+        let master = origin.head().unwrap().peel_to_commit().unwrap();
+        let mut subdir = origin.treebuilder(None).unwrap();
+        subdir
+            .insert("flake.lock", origin.blob(b"old").unwrap(), 0o100644)
+            .unwrap();
+        let mut tree = origin.treebuilder(Some(&master.tree().unwrap())).unwrap();
+        tree.insert("nix", subdir.write().unwrap(), 0o040000)
+            .unwrap();
+        let tree = origin.find_tree(tree.write().unwrap()).unwrap();
+        let author = Signature::now("Human", "human@example.com").unwrap();
+        origin
+            .commit(
+                Some("refs/heads/master"),
+                &author,
+                &author,
+                "Move the flake",
+                &tree,
+                &[&master],
+            )
+            .unwrap();
+    }
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.flake_subdir = Some("nix".to_string());
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    repo.setup_update_branch(&settings).unwrap();
+    std::fs::write(settings.lock_path(repo.path().unwrap()), "new").unwrap();
+    repo.commit(&settings, "new".to_string()).unwrap();
+
+    let head = repo.repo.head().unwrap().peel_to_tree().unwrap();
+    let lock = head.get_path(Path::new("nix/flake.lock")).unwrap();
+    assert_eq!(repo.repo.find_blob(lock.id()).unwrap().content(), b"new");
+}
+
+#[test]
+fn default_branch_is_detected_from_remote_head() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+    {
+        let master = origin.head().unwrap().peel_to_commit().unwrap();
+        origin.branch("main", &master, false).unwrap();
+        origin.set_head("refs/heads/main").unwrap();
+    }
+
+    let state = test_state(dir.path().to_path_buf(), None);
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert_eq!(
+        detect_default_branch(&state, &test_settings(), &handle, None).unwrap(),
+        "main"
+    );
+}
+
+#[test]
+fn clone_larger_than_max_repo_size_is_aborted() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), &"lock".repeat(1000));
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir.clone(), None);
+    let mut settings = test_settings();
+    settings.max_repo_size = Some(100);
+    let handle = RepoHandle::GitNone {
+        url: format!("file://{}", origin.path().display()),
+    };
+
+    assert!(matches!(
+        UDRepo::init(&state, &settings, &handle, None),
+        Err(InitError::TooLarge(100))
+    ));
+    // The partial clone was cleaned up
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 0);
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Serve the repositories in `root` over HTTP on localhost with `git http-backend`,
+/// to clients authenticating with `username` and `password`. Returns the base URL.
+fn serve_git_http(root: PathBuf, username: &str, password: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let authorization = format!(
+        "Basic {}",
+        base64(format!("{}:{}", username, password).as_bytes())
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            serve_git_http_request(&root, &authorization, stream.unwrap());
+        }
+    });
+    url
+}
+
+/// Answer a single request, asking for credentials if it doesn't have the right ones
+fn serve_git_http_request(root: &Path, authorization: &str, mut stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut request_line = request_line.split_whitespace();
+    let method = request_line.next().unwrap().to_string();
+    let target = request_line.next().unwrap().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => headers.insert(name.to_lowercase(), value.trim().to_string()),
+            None => break,
+        };
+    }
+
+    let mut body = Vec::new();
+    if let Some(length) = headers.get("content-length") {
+        body.resize(length.parse().unwrap(), 0);
+        reader.read_exact(&mut body).unwrap();
+    } else if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    if headers.get("authorization").map(String::as_str) != Some(authorization) {
+        stream
+            .write_all(
+                b"HTTP/1.1 401 Unauthorized\r\n\
+                  WWW-Authenticate: Basic realm=\"git\"\r\n\
+                  Content-Length: 0\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+        return;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let mut backend = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("REMOTE_USER", "update-daemon")
+        .env("REQUEST_METHOD", &method)
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env(
+            "CONTENT_TYPE",
+            headers.get("content-type").cloned().unwrap_or_default(),
+        )
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    backend.stdin.take().unwrap().write_all(&body).unwrap();
+    let output = backend.wait_with_output().unwrap().stdout;
+
+    // The CGI response: headers, with the status in `Status`, then the body
+    let end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let mut status = "200 OK".to_string();
+    let mut response_headers = String::new();
+    for line in String::from_utf8_lossy(&output[..end]).lines() {
+        match line.strip_prefix("Status: ") {
+            Some(line) => status = line.to_string(),
+            None => response_headers.push_str(&format!("{}\r\n", line)),
+        }
+    }
+    let response_body = &output[end + 4..];
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        response_headers,
+        response_body.len()
+    )
+    .unwrap();
+    stream.write_all(response_body).unwrap();
+}
+
+#[test]
+fn https_remote_is_fetched_and_pushed_with_api_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("forge");
+    let origin = init_origin(&root.join("owner/repo.git"), "old");
+    let base_url = serve_git_http(root, "oauth2", "secret");
+    let token_file = dir.path().join("token");
+    std::fs::write(&token_file, "secret").unwrap();
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.git_auth = Some(GitAuth::Https);
+    let handle = RepoHandle::Gitea {
+        base_url,
+        ssh_url: None,
+        token_env_var: None,
+        token_file: Some(token_file),
+        owner: "owner".to_string(),
+        repo: "repo".to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let pushed = origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .unwrap()
+        .get()
+        .peel_to_commit()
+        .unwrap();
+    assert_eq!(pushed.author().email(), Some("update-daemon@example.com"));
+
+    // The next pass fetches the pushed branch again
+    assert!(!update_pass(&state, &settings, &handle, "new"));
+}
+
+#[test]
+fn custom_remote_name_is_fetched_and_pushed() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.remote_name = "upstream".to_string();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    assert!(origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .is_ok());
+    assert!(!update_pass(&state, &settings, &handle, "new"));
+
+    // An existing clone gets the remote when the name changes
+    settings.remote_name = "mirror".to_string();
+    assert!(!update_pass(&state, &settings, &handle, "new"));
+}
+
+#[test]
+fn unsigned_commit_is_not_pushed_when_signing() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    repo.setup_update_branch(&settings).unwrap();
+    std::fs::write(repo.path().unwrap().join("flake.lock"), "new").unwrap();
+    repo.commit(&settings, "new".to_string()).unwrap();
+
+    settings.sign_commits = true;
+    assert!(matches!(
+        repo.push(&state, &settings),
+        Err(PushError::Unsigned(_))
+    ));
+    assert!(origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .is_err());
+}
+
+#[test]
+fn fork_is_refused_outside_github() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state(dir.path().to_path_buf(), None);
+    let mut settings = test_settings();
+    settings.fork = Some(Fork {
+        owner: "bot".to_string(),
+        repo: None,
+        ssh_url: None,
+    });
+    let handle = RepoHandle::GitNone {
+        url: dir.path().join("origin.git").to_string_lossy().to_string(),
+    };
+
+    assert!(matches!(
+        UDRepo::init(&state, &settings, &handle, None),
+        Err(InitError::ForkUnsupported(_))
+    ));
+    // Nothing was cloned
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn update_branch_is_deleted_only_when_pushed() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    assert!(repo.delete_update_branch(&state, &settings).unwrap());
+    assert!(origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .is_err());
+
+    // Once deleted, there is nothing to delete
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    assert!(!repo.delete_update_branch(&state, &settings).unwrap());
+}
+
+/// Commit `file` with `contents` on top of `branch` of `origin`, by `email`
+fn commit_file(origin: &Repository, branch: &str, email: &str, file: &str, contents: &str) {
+    let reference = format!("refs/heads/{}", branch);
+    let parent = origin
+        .find_reference(&reference)
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let blob = origin.blob(contents.as_bytes()).unwrap();
+    let mut tree = origin.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+    tree.insert(file, blob, 0o100644).unwrap();
+    let tree = origin.find_tree(tree.write().unwrap()).unwrap();
+    let author = Signature::now("Someone", email).unwrap();
+    origin
+        .commit(Some(&reference), &author, &author, file, &tree, &[&parent])
+        .unwrap();
+}
+
+#[test]
+fn human_commits_are_kept_on_top_of_the_default_branch() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.rebase_on_human_commits = true;
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    commit_file(
+        &origin,
+        "automatic-update",
+        "human@example.com",
+        "fix.nix",
+        "fixed",
+    );
+    commit_file(&origin, "master", "human@example.com", "README", "moved on");
+
+    // The human commit is replayed on top of the default branch, under the update
+    let history = |origin: &Repository| {
+        let mut walk = origin.revwalk().unwrap();
+        walk.push_ref("refs/heads/automatic-update").unwrap();
+        walk.map(|id| {
+            let commit = origin.find_commit(id.unwrap()).unwrap();
+            (commit.id(), commit.summary().unwrap().to_string())
+        })
+        .collect::<Vec<_>>()
+    };
+    assert!(update_pass(&state, &settings, &handle, "newer"));
+    let pushed = history(&origin);
+    let summaries: Vec<_> = pushed.iter().map(|(_, summary)| summary.as_str()).collect();
+    assert_eq!(summaries[1..], ["fix.nix", "README", "Initial commit"]);
+
+    // ...and kept as is by the next pass, which only updates
+    assert!(update_pass(&state, &settings, &handle, "newest"));
+    let updated = history(&origin);
+    assert_ne!(updated[0], pushed[0]);
+    assert_eq!(updated[1..], pushed[1..]);
+
+    // A conflicting human commit leaves the update branch alone
+    commit_file(
+        &origin,
+        "automatic-update",
+        "human@example.com",
+        "README",
+        "mine",
+    );
+    commit_file(&origin, "master", "human@example.com", "README", "theirs");
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    assert!(matches!(
+        repo.setup_update_branch(&settings),
+        Err(SetupUpdateBranchError::HumanCommitConflict(_))
+    ));
+}
+
+#[test]
+fn commit_message_template_replaces_title() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.commit_message_template =
+        Some("chore(deps): update flake.lock\n\n{{diff}}".to_string());
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let commit = origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .unwrap()
+        .get()
+        .peel_to_commit()
+        .unwrap();
+    assert_eq!(
+        commit.message(),
+        Some("chore(deps): update flake.lock\n\nnew")
+    );
+}
+
+#[test]
+fn dated_update_branch_is_reused_within_the_day() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.update_branch = "flake-update/{{date}}-{{ short_date }}".to_string();
+    settings.resolve_update_branch(chrono::NaiveDate::from_ymd_opt(2023, 10, 5).unwrap());
+    assert_eq!(settings.update_branch, "flake-update/2023-10-05-20231005");
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    assert!(origin
+        .find_branch(&settings.update_branch, BranchType::Local)
+        .is_ok());
+    assert!(!update_pass(&state, &settings, &handle, "new"));
+}
+
+/// Run `git` in `dir` with `env`, returning whether it succeeded
+fn git_succeeds(dir: &Path, args: &[&str], env: &[(&str, &Path)]) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .envs(env.iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
+
+#[test]
+fn gpg_signed_commit_is_pushed_and_verifies() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    // Throwaway keys in their own home, which gpgme finds through GNUPGHOME
+    let gnupg_home = dir.path().join("gnupg");
+    std::fs::create_dir(&gnupg_home).unwrap();
+    std::fs::set_permissions(
+        &gnupg_home,
+        std::os::unix::fs::PermissionsExt::from_mode(0o700),
+    )
+    .unwrap();
+    let generate_key = |uid: &str, passphrase: &str| {
+        let generated = Command::new("gpg")
+            .env("GNUPGHOME", &gnupg_home)
+            .args(["--batch", "--pinentry-mode", "loopback"])
+            .args(["--passphrase", passphrase, "--quick-generate-key"])
+            .args([uid, "ed25519", "sign", "never"])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(generated.success());
+    };
+    generate_key("update-daemon <update-daemon@example.com>", "");
+    generate_key("protected <protected@example.com>", "secret");
+    std::env::set_var("GNUPGHOME", &gnupg_home);
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.sign_commits = true;
+    settings.signing_key = Some("update-daemon@example.com".to_string());
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+    let verify = || {
+        git_succeeds(
+            origin.path(),
+            &["verify-commit", &settings.update_branch],
+            &[("GNUPGHOME", &gnupg_home)],
+        )
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let verified = verify();
+
+    // A wrong passphrase fails the signing instead of waiting for another one
+    let passphrase_file = dir.path().join("passphrase");
+    std::fs::write(&passphrase_file, "wrong\n").unwrap();
+    let mut settings = settings.clone();
+    settings.signing_key = Some("protected@example.com".to_string());
+    settings.signing_passphrase_file = Some(passphrase_file.clone());
+    let repo = UDRepo::init(&state, &settings, &handle, None).unwrap();
+    repo.setup_update_branch(&settings).unwrap();
+    std::fs::write(repo.path().unwrap().join("flake.lock"), "newer").unwrap();
+    repo.soft_reset_to_default(&settings).unwrap();
+    let wrong = repo.commit(&settings, "newer".to_string());
+
+    std::fs::write(&passphrase_file, "secret\n").unwrap();
+    let pushed_protected = update_pass(&state, &settings, &handle, "newer");
+    let verified_protected = verify();
+
+    Command::new("gpgconf")
+        .env("GNUPGHOME", &gnupg_home)
+        .args(["--kill", "gpg-agent"])
+        .status()
+        .ok();
+    assert!(verified);
+    assert!(matches!(wrong, Err(CommitError::Sign(_))));
+    assert!(pushed_protected && verified_protected);
+}
+
+#[test]
+fn ssh_signed_commit_is_pushed_and_verifies() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let key = dir.path().join("id_ed25519");
+    let generated = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key)
+        .status()
+        .unwrap();
+    assert!(generated.success());
+    let public_key = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+    let allowed_signers = dir.path().join("allowed_signers");
+    std::fs::write(
+        &allowed_signers,
+        format!("update-daemon@example.com {}", public_key),
+    )
+    .unwrap();
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir, None);
+    let mut settings = test_settings();
+    settings.sign_commits = true;
+    settings.signing_method = SigningMethod::Ssh;
+    settings.ssh_key_path = Some(key);
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    assert!(git_succeeds(
+        origin.path(),
+        &[
+            "-c",
+            &format!("gpg.ssh.allowedSignersFile={}", allowed_signers.display()),
+            "verify-commit",
+            &settings.update_branch,
+        ],
+        &[],
+    ));
+}
+
+#[test]
+fn only_stale_clones_are_collected() {
+    let dir = tempfile::tempdir().unwrap();
+    let origin = init_origin(&dir.path().join("origin.git"), "old");
+
+    let cache_dir = dir.path().join("cache");
+    create_dir(&cache_dir).unwrap();
+    let state = test_state(cache_dir.clone(), None);
+    let settings = test_settings();
+    let handle = RepoHandle::GitNone {
+        url: origin.path().to_string_lossy().to_string(),
+    };
+    assert!(update_pass(&state, &settings, &handle, "new"));
+    let clone = cache_dir.join(cache_dir_name(&settings, &handle));
+    assert!(clone.is_dir());
+    create_dir(cache_dir.join("12345")).unwrap();
+    create_dir(cache_dir.join("other")).unwrap();
+
+    let keep = HashSet::from([cache_dir_name(&settings, &handle)]);
+    let stale = stale_clones(&cache_dir, &keep, Some(Duration::from_secs(3600))).unwrap();
+    assert_eq!(stale, vec![cache_dir.join("12345")]);
+
+    // A clone that wasn't fetched for too long is stale even if it is configured
+    let long_ago = SystemTime::now() - Duration::from_secs(2 * 3600);
+    File::options()
+        .write(true)
+        .open(clone.join(".git").join("FETCH_HEAD"))
+        .unwrap()
+        .set_modified(long_ago)
+        .unwrap();
+    let stale = stale_clones(&cache_dir, &keep, Some(Duration::from_secs(3600))).unwrap();
+    assert_eq!(
+        stale.into_iter().collect::<HashSet<_>>(),
+        HashSet::from([cache_dir.join("12345"), clone])
+    );
+    assert_eq!(
+        stale_clones(&cache_dir, &keep, None).unwrap(),
+        vec![cache_dir.join("12345")]
+    );
+}
@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Context of the log messages logged while updating a repository,
+//! for `--log-format json`.
+
+use log::Record;
+use serde::Serialize;
+use serde_json::json;
+use std::cell::RefCell;
+use std::future::Future;
+
+#[cfg(test)]
+mod tests;
+
+/// What is being done to the repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Cloning or fetching, and setting up the update branch
+    Init,
+    /// Updating the flake
+    Update,
+    /// Comparing the lock files and preparing the commit
+    Diff,
+    /// Committing and pushing the update branch
+    Push,
+    /// Submitting the "pull request" and the notifications
+    Request,
+}
+
+#[derive(Debug)]
+struct LogContext {
+    repo: String,
+    phase: Phase,
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<LogContext>;
+}
+
+/// Run `future` with the log messages it logs attributed to `repo`
+pub async fn with_repo<F: Future>(repo: String, future: F) -> F::Output {
+    let context = LogContext {
+        repo,
+        phase: Phase::Init,
+    };
+    CONTEXT.scope(RefCell::new(context), future).await
+}
+
+/// Attribute the following log messages of the current repository to `phase`
+pub fn set_phase(phase: Phase) {
+    // Outside of `with_repo`, there's no repository to attribute them to
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().phase = phase);
+}
+
+/// Render `record` as a line of JSON, with the repository and the phase it was logged in,
+/// if any. The repository is then taken out of the message.
+pub fn json_line(record: &Record) -> String {
+    let message = record.args().to_string();
+    let line = CONTEXT.try_with(|context| {
+        let context = context.borrow();
+        let prefix = format!("{}: ", context.repo);
+        json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "repo": context.repo,
+            "phase": context.phase,
+            "message": message.strip_prefix(&prefix).unwrap_or(&message),
+        })
+    });
+    line.unwrap_or_else(|_| {
+        json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": message,
+        })
+    })
+    .to_string()
+}
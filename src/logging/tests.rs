@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+use serde_json::Value;
+
+fn logged(message: std::fmt::Arguments) -> Value {
+    let record = Record::builder()
+        .args(message)
+        .level(log::Level::Info)
+        .target("update_daemon")
+        .build();
+    serde_json::from_str(&json_line(&record)).unwrap()
+}
+
+#[tokio::test]
+async fn repository_messages_carry_their_context() {
+    let line = with_repo("serokell/update-daemon".to_string(), async {
+        set_phase(Phase::Push);
+        logged(format_args!(
+            "{}: Pushed {}",
+            "serokell/update-daemon", "automatic-update"
+        ))
+    })
+    .await;
+    assert_eq!(line["repo"], "serokell/update-daemon");
+    assert_eq!(line["phase"], "push");
+    assert_eq!(line["message"], "Pushed automatic-update");
+    assert_eq!(line["level"], "INFO");
+}
+
+#[test]
+fn other_messages_have_no_context() {
+    set_phase(Phase::Push);
+    let line = logged(format_args!("Processed {} repositories", 3));
+    assert_eq!(line["message"], "Processed 3 repositories");
+    assert!(line.get("repo").is_none());
+    assert!(line.get("phase").is_none());
+}
@@ -3,11 +3,10 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 
 use ssh2_config::SshConfig;
-use std::process::Command;
 use std::sync::Arc;
 use xdg::BaseDirectories;
 
@@ -16,22 +15,33 @@ use thiserror::Error;
 
 use clap::Parser;
 
-use serde::Deserialize;
+use serde::Serialize;
 use serde_json::from_str;
 
+mod config;
+use config::Config;
+mod explain;
+use explain::explain;
 mod git;
 use git::UDRepo;
+mod logging;
+use logging::Phase;
 mod flake_lock;
-use flake_lock::Lock;
+use flake_lock::{DiffRendering, Lock};
+mod nix;
 mod types;
 use types::*;
 mod request;
+mod schedule;
+mod summary;
+use summary::{RepoStatus, RepoSummary};
+mod template;
 
-use merge::Merge;
-
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex as TMutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex as TMutex, Semaphore};
 
 #[derive(Debug, Error)]
 enum FlakeUpdateError {
@@ -40,43 +50,81 @@ enum FlakeUpdateError {
     #[error("Command output was not valid UTF-8: {0}")]
     Utf8(#[from] std::str::Utf8Error),
     #[error("Command was terminated or exited with a non-zero status {0:?} and the following output: \n {1}")]
-    ExitStatus(Option<i32>, String),
+    ExitStatus(Option<i32>, nix::Stderr),
+    #[error("Timed out after {0:?}")]
+    Timeout(Duration),
     #[error("Input {0} is missing from the flake.lock root nodes. Check spelling or consider using the allow_missing_inputs configuration option.")]
     MissingInput(String),
+    #[error("{0}")]
+    Env(#[from] nix::EnvError),
 }
 
 fn flake_update(
+    state: &UpdateState,
+    handle: &RepoHandle,
     workdir: &Path,
     settings: &UpdateSettings,
     lock: &Lock,
 ) -> Result<(), FlakeUpdateError> {
-    let mut nix_flake_update = Command::new("nix");
-    nix_flake_update.arg("flake").arg("update");
+    let mut nix_flake_update = nix::nix_command(settings, workdir)?;
+
+    for input in settings.inputs.iter() {
+        // Abort flake update if input is missing from the flake.lock root nodes
+        // and allow_missing_inputs is not set
+        if !settings.allow_missing_inputs && lock.get_root_dep(input.clone()).is_none() {
+            return Err(FlakeUpdateError::MissingInput(input.clone()));
+        };
+    }
 
     // If a list of inputs to update is provided, update only the specified inputs
-    if !settings.inputs.is_empty() {
-        for input in settings.inputs.iter() {
-            // Abort flake update if input is missing from the flake.lock root nodes
-            // and allow_missing_inputs is not set
-            if !settings.allow_missing_inputs && lock.get_root_dep(input.clone()).is_none() {
-                return Err(FlakeUpdateError::MissingInput(input.clone()));
-            };
-            nix_flake_update.arg(input);
+    match settings.nix_update_syntax {
+        NixUpdateSyntax::Old if !settings.inputs.is_empty() => {
+            nix_flake_update.arg("flake").arg("lock");
+            for input in settings.inputs.iter() {
+                nix_flake_update.arg("--update-input").arg(input);
+            }
         }
-    };
+        _ => {
+            nix_flake_update
+                .arg("flake")
+                .arg("update")
+                .args(&settings.inputs);
+        }
+    }
+
+    if settings.lock_filename != "flake.lock" {
+        nix_flake_update
+            .arg("--reference-lock-file")
+            .arg(&settings.lock_filename)
+            .arg("--output-lock-file")
+            .arg(&settings.lock_filename);
+    }
 
     nix_flake_update.arg("--no-warn-dirty");
-    nix_flake_update.current_dir(workdir.to_str().unwrap());
-    let output = nix_flake_update.output()?;
+    nix::explain_command(state, handle, settings, &nix_flake_update);
+    let output = match nix::output_with_timeout(nix_flake_update, settings.flake_update_timeout)? {
+        nix::TimedOutput::Finished(output) => output,
+        nix::TimedOutput::TimedOut(stderr) => {
+            warn!(
+                "{}: nix flake update timed out, its output so far:\n{}",
+                handle,
+                String::from_utf8_lossy(&stderr)
+            );
+            return Err(FlakeUpdateError::Timeout(settings.flake_update_timeout));
+        }
+    };
 
     info!("{}", std::str::from_utf8(&output.stdout)?);
 
+    let stderr = nix::split_warnings(std::str::from_utf8(&output.stderr)?);
     if !output.status.success() {
-        return Err(FlakeUpdateError::ExitStatus(
-            output.status.code(),
-            std::str::from_utf8(&output.stderr)?.to_string(),
-        ));
+        return Err(FlakeUpdateError::ExitStatus(output.status.code(), stderr));
+    }
+    // Such as the retries of fetches, which come before failures
+    for warning in &stderr.warnings {
+        warn!("{}: nix flake update: {}", handle, warning);
     }
+    debug!("{}: {}", handle, stderr.rest.trim_end());
 
     Ok(())
 }
@@ -85,6 +133,12 @@ fn flake_update(
 enum UpdateError {
     #[error("Error during repository initialisation: {0}")]
     InitError(#[from] git::InitError),
+    #[error("Refusing to use the API: {0}")]
+    HostNotAllowed(#[from] HostNotAllowed),
+    #[error("Couldn't read extra_body_file: {0}")]
+    ExtraBodyFile(std::io::Error),
+    #[error("Couldn't write the lock file with inputs held back: {0}")]
+    RestoreLock(std::io::Error),
     #[error("Failed to get flake lock information: {0}")]
     GetLockError(#[from] flake_lock::GetLockError),
     #[error("Error during update branch setup: {0}")]
@@ -101,6 +155,92 @@ enum UpdateError {
     RequestError(#[from] request::RequestError),
     #[error("Error during soft-reset: {0}")]
     ResetError(#[from] git::ResetError),
+    #[error("Error comparing with the remote update branch: {0}")]
+    CompareError(#[from] git::CompareError),
+    #[error("Error checking out the pull request branch: {0}")]
+    CheckoutError(#[from] git::CheckoutRemoteBranchError),
+    #[error("Error counting the update branch commits: {0}")]
+    CountAheadError(#[from] git::CountAheadError),
+    #[error("Shutting down")]
+    ShuttingDown,
+}
+
+/// Marks the snapshot of the reported lock, hidden in the "pull request" body
+const LOCK_SNAPSHOT_MARKER: &str = "<!-- update-daemon lock snapshot: ";
+
+/// Marks the input updated by the "pull request" when updating `sequential_inputs`
+const SEQUENTIAL_INPUT_MARKER: &str = "<!-- update-daemon input: ";
+
+/// Find the value following `marker` in a "pull request" body
+fn find_marker<'a>(body: &'a str, marker: &str) -> Option<&'a str> {
+    let start = body.find(marker)? + marker.len();
+    let len = body[start..].find(" -->")?;
+    Some(&body[start..start + len])
+}
+
+/// Find the snapshot of the lock reported in a "pull request" body
+fn find_lock_snapshot(body: &str) -> Option<Lock> {
+    from_str(find_marker(body, LOCK_SNAPSHOT_MARKER)?).ok()
+}
+
+/// Remembers the input of the last "pull request" submitted with `sequential_inputs`
+const SEQUENTIAL_INPUT_FILE: &str = "update-daemon-sequential-input";
+
+/// Update only one of `sequential_inputs`, so that their updates are reviewed one at a time:
+/// the one of the open "pull request" if there is one. Otherwise, the previous one was merged
+/// or closed, and the inputs take turns: the first one with an update after `last`, the input
+/// of the previous one, in the order of `sequential_inputs`.
+/// Sets `settings.inputs` (and the title and the label of `input_label_template`) to the
+/// chosen input.
+async fn flake_update_sequential(
+    state: &UpdateState,
+    handle: &RepoHandle,
+    workdir: &Path,
+    settings: &mut UpdateSettings,
+    before: &Lock,
+    default_branch_lock: &Lock,
+    last: Option<String>,
+) -> Result<(), UpdateError> {
+    let current = request::find_request(settings, handle)
+        .await?
+        .and_then(|request| request.body)
+        .and_then(|body| find_marker(&body, SEQUENTIAL_INPUT_MARKER).map(str::to_string))
+        .filter(|input| settings.sequential_inputs.contains(input));
+    let candidates = match current {
+        Some(input) => vec![input],
+        None => {
+            let start = last
+                .and_then(|last| settings.sequential_inputs.iter().position(|i| *i == last))
+                .map_or(0, |last| last + 1);
+            let mut inputs = settings.sequential_inputs.clone();
+            let len = inputs.len();
+            inputs.rotate_left(start % len);
+            inputs
+        }
+    };
+
+    for input in candidates {
+        settings.inputs = vec![input.clone()];
+        flake_update(state, handle, workdir, settings, before)?;
+        // Inputs without updates leave the lock as it was, so the next one can be tried
+        if settings
+            .diff_locks(
+                default_branch_lock,
+                &flake_lock::read_lock(&settings.lock_path(workdir))?,
+            )?
+            .len()
+            > 0
+        {
+            info!("{}: Updating {}", handle, input);
+            settings.title = format!("{} ({})", settings.title, input);
+            if let Some(template) = &settings.input_label_template {
+                let label = template::render(template, &[("input", &input)]);
+                settings.labels.push(label);
+            }
+            break;
+        }
+    }
+    Ok(())
 }
 
 async fn wait_for_delay(last_ts: Instant, delay: Duration) {
@@ -110,75 +250,690 @@ async fn wait_for_delay(last_ts: Instant, delay: Duration) {
     }
 }
 
+/// Describes updates that only change the inputs of the inputs
+const TRANSITIVE_CHANGES_NOTE: &str = "Only transitive inputs were updated";
+
+/// With `report_transitive_changes`, the lock file's contents, to notice the changes
+/// that don't show up in the diff of the root inputs
+fn transitive_lock_bytes(
+    settings: &UpdateSettings,
+    workdir: &Path,
+) -> Result<Option<Vec<u8>>, flake_lock::GetLockError> {
+    if settings.report_transitive_changes {
+        Ok(Some(std::fs::read(settings.lock_path(workdir))?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// With `release_inputs`, undo the updates of those inputs that don't include a new release,
+/// by locking them as in `before_contents` again. Returns the resulting lock.
+async fn hold_back_unreleased(
+    state: &UpdateState,
+    handle: &RepoHandle,
+    workdir: &Path,
+    settings: &UpdateSettings,
+    before: &Lock,
+    before_contents: &[u8],
+    after: Lock,
+) -> Result<Lock, UpdateError> {
+    let diff = before.diff(&after)?;
+    let mut held_back = Vec::new();
+    for input in &settings.release_inputs {
+        if let Some(flake_lock::InputChange::Update { old, new }) = diff.get(input) {
+            match (old.github_rev(), new.github_rev()) {
+                (Some((owner, repo, old_rev)), Some((_, _, new_rev))) => {
+                    match request::includes_new_release(
+                        state, settings, handle, owner, repo, old_rev, new_rev,
+                    )
+                    .await?
+                    {
+                        Some(true) => {}
+                        Some(false) => held_back.push(input.clone()),
+                        None => {
+                            warn!(
+                                "{}: {}/{} has no releases, holding {} back",
+                                handle, owner, repo, input
+                            );
+                            held_back.push(input.clone());
+                        }
+                    }
+                }
+                _ => warn!(
+                    "{}: {} isn't a GitHub input, its releases can't be checked",
+                    handle, input
+                ),
+            }
+        }
+    }
+    if held_back.is_empty() {
+        return Ok(after);
+    }
+
+    info!(
+        "{}: Holding back {} until a new release",
+        handle,
+        held_back.join(", ")
+    );
+    revert_inputs(workdir, settings, before_contents, &held_back)
+}
+
+/// With `report_only_inputs`, log the updates of those inputs and undo them, the same way
+/// as `hold_back_unreleased`. Returns the resulting lock.
+fn revert_report_only(
+    handle: &RepoHandle,
+    workdir: &Path,
+    settings: &UpdateSettings,
+    before: &Lock,
+    before_contents: &[u8],
+    after: Lock,
+) -> Result<Lock, UpdateError> {
+    let diff = before.diff(&after)?;
+    let reported = diff.only(&settings.report_only_inputs);
+    if reported.len() == 0 {
+        return Ok(after);
+    }
+
+    info!(
+        "{}: Not proposing the updates of report-only inputs:\n{}",
+        handle,
+        reported.spaced(&settings.display_options())
+    );
+    let excluded: Vec<String> = reported.inputs().cloned().collect();
+    revert_inputs(workdir, settings, before_contents, &excluded)
+}
+
+/// With `min_input_age`, undo the updates of the inputs to revisions modified within
+/// that many days, the same way as `hold_back_unreleased`. Returns the resulting lock.
+fn hold_back_recent(
+    handle: &RepoHandle,
+    workdir: &Path,
+    settings: &UpdateSettings,
+    before: &Lock,
+    before_contents: &[u8],
+    after: Lock,
+) -> Result<Lock, UpdateError> {
+    let Some(days) = settings.min_input_age else {
+        return Ok(after);
+    };
+    let diff = before.diff(&after)?;
+    let threshold = chrono::Utc::now().timestamp() - days as i64 * 24 * 60 * 60;
+    let recent = diff.updated_after(threshold);
+    if recent.len() == 0 {
+        return Ok(after);
+    }
+
+    info!(
+        "{}: Holding back inputs updated within {} days:\n{}",
+        handle,
+        days,
+        recent.spaced(&settings.display_options())
+    );
+    let excluded: Vec<String> = recent.inputs().cloned().collect();
+    revert_inputs(workdir, settings, before_contents, &excluded)
+}
+
+/// Undo the updates of `excluded`, by locking them as in `before_contents` again in the
+/// lockfile. The other inputs keep the revisions they were updated to. Returns the
+/// resulting lock.
+fn revert_inputs(
+    workdir: &Path,
+    settings: &UpdateSettings,
+    before_contents: &[u8],
+    excluded: &[String],
+) -> Result<Lock, UpdateError> {
+    let lock_path = settings.lock_path(workdir);
+    let after_contents =
+        std::fs::read_to_string(&lock_path).map_err(flake_lock::GetLockError::from)?;
+    let reverted = flake_lock::revert_inputs(
+        &String::from_utf8_lossy(before_contents),
+        &after_contents,
+        excluded,
+    )?;
+    std::fs::write(&lock_path, reverted).map_err(UpdateError::RestoreLock)?;
+    Ok(flake_lock::read_lock(&lock_path)?)
+}
+
+/// What `update_repo` did, for the summary of the run
+#[derive(Debug, Default)]
+struct UpdateOutcome {
+    /// Whether an update was submitted (or, in a dry run, would have been)
+    updated: bool,
+    /// Number of inputs changed compared to the default branch
+    inputs_changed: usize,
+    /// Whether it stopped before committing anything, due to a shutdown
+    skipped: bool,
+    /// The "pull request" submitted or updated, if any
+    url: Option<String>,
+    /// The changes, as the markdown table of the "pull request"
+    diff: String,
+}
+
 async fn update_repo(
     handle: RepoHandle,
     state: &UpdateState,
-    settings: UpdateSettings,
+    mut settings: UpdateSettings,
     previous_update: Arc<TMutex<Instant>>,
-) -> Result<(), UpdateError> {
+) -> Result<UpdateOutcome, UpdateError> {
+    if state.shutdown.is_requested() {
+        return Err(UpdateError::ShuttingDown);
+    }
     info!("Updating {}", handle);
 
-    let repo = UDRepo::init(state, &settings, &handle)?;
+    state.check_host(handle.api_host())?;
+
+    let credentials =
+        request::git_credentials(&settings, &handle).map_err(request::RequestError::from)?;
+
+    if settings.detect_default_branch {
+        let detected = {
+            let _permit = state.git_permit().await;
+            git::detect_default_branch(state, &settings, &handle, credentials.as_ref())
+        };
+        match detected {
+            Ok(branch) => settings.default_branch = branch,
+            Err(e) => warn!(
+                "{}: Couldn't detect the default branch, using {}: {}",
+                handle, settings.default_branch, e
+            ),
+        }
+    }
+
+    // The managed "pull request" is updated from whatever branch it is submitted from
+    if settings.managed_pr_number.is_some() && !settings.comment_only {
+        if let Some(head) = request::find_request_head(&settings, &handle).await? {
+            settings.update_branch = head.branch;
+        }
+    }
+
+    let repo = {
+        let _permit = state.git_permit().await;
+        UDRepo::init(state, &settings, &handle, credentials)?
+    };
     let workdir = repo.path().unwrap();
 
-    let default_branch_lock = flake_lock::get_lock(workdir)?;
+    let default_branch_lock = match flake_lock::read_lock(&settings.lock_path(workdir)) {
+        Err(flake_lock::GetLockError::IOError(e))
+            if settings.skip_if_no_lock && e.kind() == std::io::ErrorKind::NotFound =>
+        {
+            info!(
+                "{}: Skipping, there is no {}",
+                handle,
+                settings.lock_repo_path().display()
+            );
+            return Ok(UpdateOutcome::default());
+        }
+        lock => lock?,
+    };
+    let default_branch_bytes = transitive_lock_bytes(&settings, workdir)?;
 
-    repo.setup_update_branch(&settings)?;
+    if let Some(age) = settings.recent_lock_age(&default_branch_lock) {
+        info!(
+            "{}: Skipping, the newest input in the lock was modified {} days ago",
+            handle, age
+        );
+        return Ok(UpdateOutcome::default());
+    }
 
-    let before = flake_lock::get_lock(workdir)?;
+    if settings.comment_only {
+        return comment_diff(
+            state,
+            repo,
+            settings,
+            handle,
+            &default_branch_lock,
+            previous_update,
+        )
+        .await;
+    }
 
-    flake_update(workdir, &settings, &before)?;
+    repo.setup_update_branch(&settings)?;
 
-    let after = flake_lock::get_lock(workdir)?;
+    logging::set_phase(Phase::Update);
+    let before = flake_lock::read_lock(&settings.lock_path(workdir))?;
+    let before_bytes = transitive_lock_bytes(&settings, workdir)?;
+    let before_contents = if settings.release_inputs.is_empty()
+        && settings.report_only_inputs.is_empty()
+        && settings.min_input_age.is_none()
+    {
+        None
+    } else {
+        Some(std::fs::read(settings.lock_path(workdir)).map_err(flake_lock::GetLockError::from)?)
+    };
 
-    let diff = before.diff(&after)?;
-    let diff_default = default_branch_lock.diff(&after)?;
+    if settings.sequential_inputs.is_empty() {
+        flake_update(state, &handle, workdir, &settings, &before)?;
+    } else {
+        let last = std::fs::read_to_string(repo.git_dir().join(SEQUENTIAL_INPUT_FILE)).ok();
+        flake_update_sequential(
+            state,
+            &handle,
+            workdir,
+            &mut settings,
+            &before,
+            &default_branch_lock,
+            last,
+        )
+        .await?;
+    }
 
-    let mut body = diff_default.markdown();
-    body.push_str(&format!(
-        "\nLast updated: {}\n\n{}",
-        chrono::Utc::now(),
-        settings.extra_body
-    ));
+    logging::set_phase(Phase::Diff);
+    let mut after = flake_lock::read_lock(&settings.lock_path(workdir))?;
+    if let Some(before_contents) = before_contents {
+        after = hold_back_unreleased(
+            state,
+            &handle,
+            workdir,
+            &settings,
+            &before,
+            &before_contents,
+            after,
+        )
+        .await?;
+        after = revert_report_only(
+            &handle,
+            workdir,
+            &settings,
+            &before,
+            &before_contents,
+            after,
+        )?;
+        after = hold_back_recent(
+            &handle,
+            workdir,
+            &settings,
+            &before,
+            &before_contents,
+            after,
+        )?;
+    }
+    let after_bytes = transitive_lock_bytes(&settings, workdir)?;
 
-    let delay = settings.cooldown;
+    let diff = settings.diff_locks(&before, &after)?;
+    let diff_default = settings.diff_locks(&default_branch_lock, &after)?;
+    let display = settings.display_options();
+    // Without report_transitive_changes, the bytes are never compared
+    let changed = diff.len() > 0 || before_bytes != after_bytes;
+    let summary = if diff_default.len() > 0 {
+        diff_default.spaced(&display)
+    } else {
+        TRANSITIVE_CHANGES_NOTE.to_string()
+    };
+
+    if changed {
+        info!("{}:\n{}", handle, summary);
+    } else {
+        info!("{}: Nothing to update", handle);
+        // The human commits kept in the update branch still need their "pull request"
+        if diff_default.len() == 0
+            && default_branch_bytes == after_bytes
+            && !repo.keeps_human_commits()
+        {
+            // The managed "pull request" and its branch belong to somebody else
+            if settings.close_when_empty && settings.managed_pr_number.is_none() {
+                close_empty_requests(state, &settings, &handle).await?;
+            }
+            if settings.delete_branch_when_empty && settings.managed_pr_number.is_none() {
+                let _permit = state.git_permit().await;
+                if repo.delete_update_branch(state, &settings)? {
+                    info!(
+                        "{}: Deleted {}, it has nothing left to update",
+                        handle, settings.update_branch
+                    );
+                }
+            }
+            return Ok(UpdateOutcome::default());
+        }
+    }
 
-    if diff.len() > 0 {
-        info!("{}:\n{}", handle, diff_default.spaced());
+    // With squash_history, the "pull request" always consists of a single update commit
+    // on top of the default branch, even if the reused update branch has accumulated more
+    let squash =
+        settings.squash_history && !changed && repo.commits_ahead_of_default(&settings)? > 1;
+    // From here on, the branch is committed, pushed and the "pull request" updated,
+    // which is better done completely or not at all
+    if state.shutdown.is_requested() {
+        return Err(UpdateError::ShuttingDown);
+    }
+    logging::set_phase(Phase::Push);
+    if changed || squash {
+        // Trailers go in a paragraph of their own, after the summary
+        let message = match &settings.commit_trailer {
+            Some(key) if diff_default.len() > 0 => {
+                format!("{}\n{}", summary, diff_default.trailers(key, &display))
+            }
+            _ => summary.clone(),
+        };
         repo.soft_reset_to_default(&settings)?;
-        repo.commit(&settings, diff_default.spaced())?;
-        repo.push(state, &settings)?;
+        repo.commit(&settings, message)?;
+    }
 
-        let mut locked_ts = previous_update.lock().await;
-        wait_for_delay(*locked_ts, delay).await;
-        let res = request::submit_or_update_request(settings, handle, body, true).await;
-        *locked_ts = Instant::now();
-        res?;
+    // The update branch may already have been pushed with the same contents,
+    // e.g. by the previous pass in loop mode; re-pushing would only churn the
+    // "pull request", so skip unless the request itself is missing.
+    let up_to_date = !squash && repo.update_branch_up_to_date(&settings)?;
+    let existing_request = if settings.incremental_diff || up_to_date {
+        request::find_request(&settings, &handle).await?
     } else {
-        info!("{}: Nothing to update", handle);
-        if diff_default.len() > 0 {
-            repo.push(state, &settings)?;
+        None
+    };
+
+    if up_to_date {
+        if let Some(request) = &existing_request {
+            info!("{}: Already up to date: {}", handle, request.url);
+            return Ok(UpdateOutcome::default());
+        }
+        if let RepoHandle::GitNone { .. } = handle {
+            info!("{}: The update branch is already up to date", handle);
+            return Ok(UpdateOutcome::default());
+        }
+    }
 
-            let mut locked_ts = previous_update.lock().await;
-            wait_for_delay(*locked_ts, delay).await;
-            let res = request::submit_or_update_request(settings, handle, body, true).await;
-            *locked_ts = Instant::now();
-            res?;
+    let mut diff_table = String::new();
+
+    if settings.incremental_diff {
+        let previous = existing_request
+            .and_then(|request| request.body)
+            .as_deref()
+            .and_then(find_lock_snapshot);
+        if let Some(previous) = previous {
+            let diff_previous = previous.diff(&after)?;
+            if diff_previous.len() > 0 {
+                diff_table.push_str("### Changes since the last update\n\n");
+                diff_table.push_str(&diff_previous.markdown(&display));
+                diff_table.push_str("\n### All changes\n\n");
+            }
         }
     }
+
+    if diff_default.len() > 0 {
+        diff_table.push_str(&diff_default.markdown(&display));
+    } else {
+        diff_table.push_str(&format!("{}\n", TRANSITIVE_CHANGES_NOTE));
+    }
+    let mut checks = String::new();
+    if let Some(attr) = &settings.eval_attr {
+        checks.push_str(&format!(
+            "\n{}\n",
+            nix::eval_status(state, &handle, &settings, workdir, attr)
+        ));
+    }
+    let mut draft = false;
+    if settings.run_flake_check {
+        let check = nix::flake_check(state, &handle, &settings, workdir).await;
+        draft = !check.passed && settings.draft_on_failed_check;
+        checks.push_str(&format!("\n{}\n", check.report));
+    }
+    let timestamp = chrono::Utc::now().to_string();
+    let mut extra_body = settings.extra_body.clone();
+    if let Some(path) = &settings.extra_body_file {
+        extra_body.push_str(&std::fs::read_to_string(path).map_err(UpdateError::ExtraBodyFile)?);
+    }
+    let mut body = match &settings.body_template {
+        Some(body_template) => template::render(
+            body_template,
+            &[
+                ("diff_table", &diff_table),
+                ("checks", &checks),
+                ("timestamp", &timestamp),
+                ("extra_body", &extra_body),
+                ("repo", &handle.to_string()),
+            ],
+        ),
+        None => format!(
+            "{}{}\nLast updated: {}\n\n{}",
+            diff_table, checks, timestamp, extra_body
+        ),
+    };
+
+    if !settings.sequential_inputs.is_empty() {
+        if let [input] = settings.inputs.as_slice() {
+            body.push_str(&format!("\n{}{} -->\n", SEQUENTIAL_INPUT_MARKER, input));
+        }
+    }
+
+    if settings.incremental_diff {
+        if let Some(snapshot) = after.root_snapshot() {
+            body.push_str(&format!(
+                "\n{}{} -->\n",
+                LOCK_SNAPSHOT_MARKER,
+                serde_json::to_string(&snapshot).unwrap()
+            ));
+        }
+    }
+
+    {
+        let _permit = state.git_permit().await;
+        repo.push(state, &settings)?;
+    }
+
+    logging::set_phase(Phase::Request);
+    if !state.dry_run {
+        // Give the CI time to register its checks on the pushed branch
+        tokio::time::sleep(settings.pre_pr_delay).await;
+    }
+
+    let url = submit_request(
+        state,
+        settings.clone(),
+        handle.clone(),
+        body,
+        draft,
+        previous_update,
+    )
+    .await?;
+    if !settings.sequential_inputs.is_empty() && !state.dry_run {
+        if let [input] = settings.inputs.as_slice() {
+            let path = repo.git_dir().join(SEQUENTIAL_INPUT_FILE);
+            if let Err(e) = std::fs::write(&path, input) {
+                warn!("{}: Couldn't write {}: {}", handle, path.display(), e);
+            }
+        }
+    }
+    if !state.dry_run {
+        request::notify_update(
+            state,
+            &settings,
+            &handle,
+            &summary,
+            &diff_default,
+            url.as_deref(),
+        )
+        .await;
+    }
+    Ok(UpdateOutcome {
+        updated: true,
+        inputs_changed: diff_default.len(),
+        skipped: false,
+        url,
+        diff: diff_default.markdown(&settings.display_options()),
+    })
+}
+
+/// With nothing left to update, close the update "pull requests"
+async fn close_empty_requests(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+) -> Result<(), UpdateError> {
+    let description = request::describe_closing(settings, handle);
+    if state.dry_run {
+        explain(&handle.to_string(), &description, true);
+        return Ok(());
+    }
+    if state.explain_commands {
+        explain(&handle.to_string(), &description, false);
+    }
+    for url in request::close_requests(settings, handle).await? {
+        info!("{}: Closed {}, it has nothing left to update", handle, url);
+    }
     Ok(())
 }
 
+/// Directory of the cache counting the consecutive failed updates of each repository,
+/// for `report_errors_after`
+const FAILURES_DIR: &str = "failures";
+
+/// The file counting the consecutive failed updates of the repository
+fn failures_file(state: &UpdateState, settings: &UpdateSettings, handle: &RepoHandle) -> PathBuf {
+    state
+        .cache_dir
+        .join(FAILURES_DIR)
+        .join(git::cache_dir_name(settings, handle))
+}
+
+/// Count one more failed update in `path`, returning how many there are in a row
+fn count_failure(path: &Path) -> u32 {
+    let failures = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|count| count.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+        + 1;
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(path, failures.to_string()));
+    if let Err(e) = written {
+        warn!("Couldn't count the failure in {}: {}", path.display(), e);
+    }
+    failures
+}
+
+/// Remembers the head of the "pull request" last commented on in `comment_only` mode
+const COMMENTED_FILE: &str = "update-daemon-commented";
+
+/// Comment the flake.lock diff on an existing "pull request", without managing any branches
+async fn comment_diff(
+    state: &UpdateState,
+    repo: UDRepo,
+    settings: UpdateSettings,
+    handle: RepoHandle,
+    default_branch_lock: &Lock,
+    previous_update: Arc<TMutex<Instant>>,
+) -> Result<UpdateOutcome, UpdateError> {
+    let target = match request::find_request_head(&settings, &handle).await? {
+        Some(target) => target,
+        None => {
+            info!("{}: No pull request to comment on", handle);
+            return Ok(UpdateOutcome::default());
+        }
+    };
+
+    let head = {
+        let _permit = state.git_permit().await;
+        repo.checkout_remote_branch(state, &settings, &target.branch)?
+    };
+
+    // Comment only once per pull request head
+    let commented = format!("{} {}", target.url, head);
+    let commented_file = repo.git_dir().join(COMMENTED_FILE);
+    if std::fs::read_to_string(&commented_file).ok().as_deref() == Some(commented.as_str()) {
+        info!("{}: Already commented on {}", handle, target.url);
+        return Ok(UpdateOutcome::default());
+    }
+
+    let lock = flake_lock::read_lock(&settings.lock_path(repo.path().unwrap()))?;
+    let diff = settings.diff_locks(default_branch_lock, &lock)?;
+    if diff.len() == 0 {
+        info!("{}: {} doesn't change flake.lock", handle, target.url);
+        return Ok(UpdateOutcome::default());
+    }
+    let body = diff.markdown(&settings.display_options());
+    let outcome = UpdateOutcome {
+        updated: true,
+        inputs_changed: diff.len(),
+        skipped: false,
+        url: Some(target.url.clone()),
+        diff: body.clone(),
+    };
+
+    let description = request::describe_comment(&target);
+    if state.dry_run {
+        info!("{}: Dry run, the comment would be:\n{}", handle, body);
+        explain(&handle.to_string(), &description, true);
+        return Ok(outcome);
+    }
+    if state.explain_commands {
+        explain(&handle.to_string(), &description, false);
+    }
+
+    let mut locked_ts = previous_update.lock().await;
+    wait_for_delay(*locked_ts, settings.cooldown).await;
+    let res = request::comment_on_request(handle, &target, body).await;
+    *locked_ts = Instant::now();
+    res?;
+
+    if let Err(e) = std::fs::write(&commented_file, commented) {
+        warn!("Couldn't remember commenting on {}: {}", target.url, e);
+    }
+    Ok(outcome)
+}
+
+/// Submit or update the "pull request" (as a draft if `draft`), keeping the cooldown
+/// between submissions. Returns the URL of the "pull request", if there is one.
+async fn submit_request(
+    state: &UpdateState,
+    settings: UpdateSettings,
+    handle: RepoHandle,
+    body: String,
+    draft: bool,
+    previous_update: Arc<TMutex<Instant>>,
+) -> Result<Option<String>, UpdateError> {
+    let description = request::describe_submission(&settings, &handle);
+    if state.dry_run {
+        info!(
+            "{}: Dry run, the pull request body would be:\n{}",
+            handle, body
+        );
+        explain(&handle.to_string(), &description, true);
+        return Ok(None);
+    }
+    if state.explain_commands {
+        explain(&handle.to_string(), &description, false);
+    }
+
+    let mut locked_ts = previous_update.lock().await;
+    wait_for_delay(*locked_ts, settings.cooldown).await;
+    let res = request::submit_or_update_request(settings, handle, body, true, draft).await;
+    *locked_ts = Instant::now();
+    Ok(res?)
+}
+
 /// Submit "pull requests" (currently only Github supported) with nix flake updates
 #[derive(Debug, Parser)]
 #[clap(version = "0.1.0", author = "Serokell <https://serokell.io/>")]
 struct Options {
-    /// The configuration file
+    /// The configuration file, or an http(s) URL to fetch it from
     #[clap()]
     config: Option<String>,
+    /// Environment variable holding the Authorization header to send when fetching the configuration from a URL
+    #[clap(long)]
+    config_auth_env_var: Option<String>,
+    /// Only fetch the configuration from this host (may be repeated); any host if not given
+    #[clap(long)]
+    config_allowed_host: Vec<String>,
     /// Verbosity level
     #[clap(default_value = "info", long, short)]
     verbosity: log::LevelFilter,
+    /// Log human-readable lines, or JSON lines with the repository and phase as fields
+    #[clap(long, value_enum, default_value = "pretty")]
+    log_format: LogFormat,
+    /// Update the flakes, but don't push anything or submit any requests
+    #[clap(long)]
+    dry_run: bool,
+    /// Log the shell commands and API calls equivalent to what is being done
+    #[clap(long)]
+    explain_commands: bool,
+    /// Directory to keep the clones in, instead of the cache_dir of the configuration
+    /// or the XDG cache directory
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// Update the repositories once and exit, even if the configuration has a schedule
+    #[clap(long)]
+    once: bool,
+    /// Write a report of each run to this file: JSON if it ends with .json, markdown otherwise
+    #[clap(long)]
+    summary_file: Option<PathBuf>,
     #[clap(subcommand)]
     subcmd: Option<SubCommand>,
 }
@@ -188,18 +943,69 @@ struct Options {
 enum SubCommand {
     #[clap()]
     CheckConfig,
+    /// Print the effective settings of every repository as JSON
+    #[clap()]
+    PrintConfig,
+    /// Update only the repository with this name: owner/repo on GitHub and Gitea,
+    /// the project on GitLab, or the URL of a git+none repository
+    #[clap()]
+    Update { repo: String },
+    /// Remove the clones of repositories that aren't configured any more from the cache,
+    /// and those that weren't fetched for cache_max_age_days
+    #[clap()]
+    Gc,
+    /// Check the API token of every repository by asking the forge who it authenticates as
     #[clap()]
+    TestAuth {
+        /// Only check the repository with this name, as for update
+        repo: Option<String>,
+    },
+    #[clap()]
+    /// Print the changes between two lock files
     DiffLocks {
-        old: flake_lock::Lock,
-        new: flake_lock::Lock,
+        /// The old lock file, or - to read it from stdin
+        old: PathBuf,
+        /// The new lock file, or - to read it from stdin
+        new: PathBuf,
+        /// Number of characters of revs and hashes to show, 0 to show them in full
+        #[clap(long, default_value = "10")]
+        rev_display_length: usize,
+        /// Print the diff as text, as an ASCII table, or as JSON (see the README for its structure)
+        #[clap(long, value_enum, default_value = "text")]
+        format: DiffFormat,
     },
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
+#[derive(Debug, Clone, clap::ValueEnum)]
+enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+enum DiffFormat {
+    Text,
+    Table,
+    Json,
+}
+
+/// A repository with its fully merged settings, as printed by `print-config`
+#[derive(Serialize)]
+struct EffectiveRepo<'a> {
     #[serde(flatten)]
-    settings: UpdateSettingsOptional,
-    repos: Vec<Repo>,
+    handle: &'a RepoHandle,
+    settings: UpdateSettings,
+}
+
+/// Read the lock file given to `diff-locks`: from `path`, or from stdin if it is `-`
+fn read_lock_arg(path: &Path) -> Result<Lock, flake_lock::GetLockError> {
+    if path == Path::new("-") {
+        let mut contents = String::new();
+        std::io::stdin().read_to_string(&mut contents)?;
+        flake_lock::parse_lock(&contents)
+    } else {
+        flake_lock::read_lock(path)
+    }
 }
 
 fn good_panic<E, O>(description: &'static str, code: i32) -> Box<dyn Fn(E) -> O>
@@ -212,7 +1018,77 @@ where
     })
 }
 
-fn init_update_state() -> UpdateState {
+/// Log every repository whose API token (or the token for its `release_inputs`) can't be
+/// read, returning whether all of them can
+fn check_tokens(config: &Config) -> bool {
+    let mut ok = true;
+    for repo in config.repos.iter().filter(|repo| repo.is_enabled()) {
+        if let Err(e) = request::check_token(&repo.handle) {
+            error!("{}: {}", repo.handle, e);
+            ok = false;
+        }
+        // Invalid settings are reported on their own
+        let settings: Result<UpdateSettings, _> = config.repo_settings(repo).try_into();
+        if settings.is_ok_and(|settings| !settings.release_inputs.is_empty()) {
+            if let Err(e) = request::check_release_token(&repo.handle) {
+                error!(
+                    "{}: Can't check the releases of release_inputs: {}",
+                    repo.handle, e
+                );
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Remove the stale clones from the cache, returning whether it succeeded
+fn gc_cache(config: &Config, state: &UpdateState) -> bool {
+    let mut keep = std::collections::HashSet::new();
+    for repo in &config.repos {
+        let settings: Result<UpdateSettings, _> = config.repo_settings(repo).try_into();
+        match settings {
+            Ok(settings) => keep.insert(git::cache_dir_name(&settings, &repo.handle)),
+            // Without its settings, the clone of the repository can't be told apart
+            Err(e) => {
+                error!("{}: Not removing stale clones: {}", repo.handle, e);
+                return false;
+            }
+        };
+    }
+    let max_age = config
+        .cache_max_age_days
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let stale = match git::stale_clones(&state.cache_dir, &keep, max_age) {
+        Ok(stale) => stale,
+        Err(e) => {
+            error!("Couldn't list the clones in {:?}: {}", state.cache_dir, e);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+    for dir in stale {
+        let command = format!("$ rm -rf {}", explain::shell_quote(&dir.to_string_lossy()));
+        if state.dry_run {
+            explain("gc", &command, true);
+            continue;
+        }
+        if state.explain_commands {
+            explain("gc", &command, false);
+        }
+        match std::fs::remove_dir_all(&dir) {
+            Ok(()) => info!("Removed the stale clone {:?}", dir),
+            Err(e) => {
+                error!("Couldn't remove the stale clone {:?}: {}", dir, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+fn init_update_state(config: &Config, dry_run: bool, explain_commands: bool) -> UpdateState {
     let global_ssh_config =
         File::open("/etc/ssh/ssh_config")
             .ok()
@@ -226,14 +1102,24 @@ fn init_update_state() -> UpdateState {
             });
     let local_ssh_config =
         SshConfig::parse_default_file(ssh2_config::ParseRule::ALLOW_UNKNOWN_FIELDS).ok();
-    let cache_dir = BaseDirectories::new()
-        .unwrap()
-        .create_cache_directory("update-daemon")
-        .unwrap_or_else(good_panic("Failed to create a cache directory", 77));
+    let cache_dir = match &config.cache_dir {
+        Some(cache_dir) => std::fs::create_dir_all(cache_dir).map(|()| cache_dir.clone()),
+        None => BaseDirectories::new()
+            .unwrap()
+            .create_cache_directory("update-daemon"),
+    }
+    .unwrap_or_else(good_panic("Failed to create a cache directory", 77));
     UpdateState {
         cache_dir,
         global_ssh_config,
         local_ssh_config,
+        git_semaphore: config
+            .git_concurrency
+            .map(|permits| Arc::new(Semaphore::new(permits))),
+        dry_run,
+        explain_commands,
+        allowed_hosts: config.allowed_hosts.clone(),
+        shutdown: Default::default(),
     }
 }
 
@@ -242,71 +1128,350 @@ async fn main() {
     let options: Options = Options::parse();
 
     let mut builder = pretty_env_logger::formatted_builder();
+    if let LogFormat::Json = options.log_format {
+        builder.format(|buf, record| writeln!(buf, "{}", logging::json_line(record)));
+    }
 
     builder.filter_level(options.verbosity).init();
 
-    if let Some(SubCommand::DiffLocks { old, new }) = options.subcmd {
+    if let Some(SubCommand::DiffLocks {
+        old,
+        new,
+        rev_display_length,
+        format,
+    }) = options.subcmd
+    {
+        if old == Path::new("-") && new == Path::new("-") {
+            error!("Only one of the lock files can be read from stdin");
+            std::process::exit(64);
+        }
+        let [old, new] = [old, new].map(|path| {
+            read_lock_arg(&path).unwrap_or_else(|e| {
+                error!("Unable to read the lock file {}: {}", path.display(), e);
+                std::process::exit(66);
+            })
+        });
         debug!("old:\n{:#?}", old);
         debug!("new:\n{:#?}", new);
         let diff = old
             .diff(&new)
             .unwrap_or_else(good_panic("Unable to generate a diff", 65));
         debug!("diff:\n{:#?}", diff);
-        println!("{}", diff.spaced());
+        let display = flake_lock::DisplayOptions {
+            rev_length: rev_display_length,
+            ..Default::default()
+        };
+        match format {
+            DiffFormat::Text => println!("{}", diff.render(DiffRendering::Spaced, &display)),
+            DiffFormat::Table => print!("{}", diff.render(DiffRendering::Table, &display)),
+            DiffFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&diff)
+                    .unwrap_or_else(good_panic("Unable to serialize the diff", 70))
+            ),
+        }
         std::process::exit(0);
     }
 
     let xdg = BaseDirectories::new().unwrap();
-    let config_file = xdg.find_config_file("update-daemon/config.json");
-
-    let config: Config = from_str(
-        std::fs::read_to_string(options.config.unwrap_or_else(|| {
-            config_file
-                .expect("Unable to find a configuration file")
-                .to_string_lossy()
-                .to_string()
-        }))
-        .unwrap_or_else(good_panic("Unable to read the configuration file", 66))
-        .as_str(),
+    let config_file = config::CONFIG_FILE_NAMES
+        .iter()
+        .find_map(|name| xdg.find_config_file(name));
+
+    let config_source = options.config.unwrap_or_else(|| {
+        config_file
+            .expect("Unable to find a configuration file")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let mut config: Config = config::parse_config(
+        &config_source,
+        &config::read_config(
+            &config_source,
+            options.config_auth_env_var,
+            (!options.config_allowed_host.is_empty())
+                .then_some(options.config_allowed_host.as_slice()),
+        )
+        .await
+        .unwrap_or_else(good_panic("Unable to read the configuration file", 66)),
     )
     .unwrap_or_else(good_panic("Unable to parse the configuration file", 78));
+    if options.cache_dir.is_some() {
+        config.cache_dir = options.cache_dir;
+    }
+    config.expand_github_orgs().await.unwrap_or_else(good_panic(
+        "Unable to list the repositories of a GitHub organization",
+        69,
+    ));
 
     match options.subcmd {
         Some(SubCommand::CheckConfig) => {
             info!("Config parsed successfully: \n{:#?}", config);
-            let settings: Result<UpdateSettings, _> = config.settings.try_into();
+            let settings: Result<UpdateSettings, _> = config.settings.clone().try_into();
             match settings {
-                Err(e) => warn!("The default settings are incomplete, you must complete them for each separate repo: {}", e),
+                Err(e @ UpdateSettingsError::MissingField(_)) => warn!("The default settings are incomplete, you must complete them for each separate repo: {}", e),
+                Err(e) => error!("The default settings are invalid: {}", e),
                 Ok(s) => info!("Default settings are complete:\n{:#?}", s)
             }
 
+            let mut valid = true;
+            for repo in &config.repos {
+                let settings: Result<UpdateSettings, _> = config.repo_settings(repo).try_into();
+                if let Err(e) = settings {
+                    error!("{}: {}", repo.handle, e);
+                    valid = false;
+                }
+            }
+            valid &= check_tokens(&config);
+            let enabled = config.repos.iter().filter(|repo| repo.is_enabled()).count();
+            info!(
+                "{} repositories enabled, {} disabled",
+                enabled,
+                config.repos.len() - enabled
+            );
+
+            std::process::exit(if valid { 0 } else { 78 });
+        }
+        Some(SubCommand::PrintConfig) => {
+            let repos = config
+                .repos
+                .iter()
+                .map(|repo| {
+                    let settings: UpdateSettings =
+                        config.repo_settings(repo).try_into().unwrap_or_else(|e| {
+                            error!("{}: {}", repo.handle, e);
+                            std::process::exit(78);
+                        });
+                    EffectiveRepo {
+                        handle: &repo.handle,
+                        settings,
+                    }
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&repos)
+                    .unwrap_or_else(good_panic("Unable to serialize the configuration", 70))
+            );
             std::process::exit(0);
         }
+        // The repository is then updated like all of them would be
+        Some(SubCommand::Update { repo: name }) => {
+            config.repos.retain(|repo| repo.handle.name() == name);
+            // Naming the repository is enough to update it
+            for repo in &mut config.repos {
+                repo.enabled = Some(true);
+            }
+            if config.repos.is_empty() {
+                error!("There is no repository named {} in the configuration", name);
+                std::process::exit(64);
+            }
+            // The clones of the other repositories aren't stale
+            config.gc_cache = Some(false);
+            // Updating one repository is a one-off
+            config.schedule = None;
+        }
+        Some(SubCommand::Gc) => {
+            let state = init_update_state(&config, options.dry_run, options.explain_commands);
+            std::process::exit(if gc_cache(&config, &state) { 0 } else { 1 });
+        }
+        Some(SubCommand::TestAuth { repo: selector }) => {
+            if let Some(selector) = &selector {
+                config.repos.retain(|repo| repo.handle.name() == *selector);
+                if config.repos.is_empty() {
+                    error!(
+                        "There is no repository named {} in the configuration",
+                        selector
+                    );
+                    std::process::exit(64);
+                }
+            }
+            let state = init_update_state(&config, false, false);
+            let mut ok = true;
+            for repo in &config.repos {
+                let name = repo.handle.to_string();
+                let result = match state.check_host(repo.handle.api_host()) {
+                    Err(e) => Err(e.to_string()),
+                    Ok(()) => request::authenticated_user(&repo.handle)
+                        .await
+                        .map_err(|e| e.to_string()),
+                };
+                match result {
+                    Ok(Some(user)) => info!("{}: Authenticated as {}", name, user),
+                    Ok(None) => info!("{}: No API to authenticate with", name),
+                    Err(e) => {
+                        error!("{}: {}", name, e);
+                        ok = false;
+                    }
+                }
+            }
+            std::process::exit(if ok { 0 } else { 77 });
+        }
         _ => {
             debug!("{:?}", config);
         }
     }
 
+    if !check_tokens(&config) {
+        error!("API tokens are missing, not updating anything");
+        std::process::exit(78);
+    }
+
+    // For the sake of efficient memory usage 'UpdateState' is created only once
+    let state = Arc::new(init_update_state(
+        &config,
+        options.dry_run,
+        options.explain_commands,
+    ));
+
+    let mut terminate =
+        signal(SignalKind::terminate()).unwrap_or_else(good_panic("Unable to handle SIGTERM", 71));
+    let shutdown = Arc::clone(&state.shutdown);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        }
+        shutdown.request();
+    });
+
+    let schedule = match &config.schedule {
+        Some(schedule) if !options.once => schedule.clone(),
+        _ => {
+            std::process::exit(run_updates(&config, &state, options.summary_file.as_deref()).await)
+        }
+    };
+    loop {
+        let code = run_updates(&config, &state, options.summary_file.as_deref()).await;
+        if state.shutdown.is_requested() {
+            std::process::exit(code);
+        }
+        let wait = schedule.until_next(chrono::Utc::now());
+        info!(
+            "Run finished with exit code {}, starting the next one in {:.0?}",
+            code, wait
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = state.shutdown.requested() => std::process::exit(0),
+        }
+    }
+}
+
+/// The cooldowns needed by the repositories on GitHub, by name, so that each API token
+/// doesn't run out of its rate limit budget before updating all of its repositories
+async fn rate_limit_cooldowns(config: &Config, state: &UpdateState) -> HashMap<String, Duration> {
+    let mut by_token: HashMap<_, Vec<&Repo>> = HashMap::new();
+    for repo in config.repos.iter().filter(|repo| repo.is_enabled()) {
+        if let RepoHandle::GitHub {
+            base_url,
+            token_env_var,
+            token_file,
+            ..
+        } = &repo.handle
+        {
+            if state.check_host(repo.handle.api_host()).is_ok() {
+                by_token
+                    .entry((base_url, token_env_var, token_file))
+                    .or_default()
+                    .push(repo);
+            }
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut cooldowns = HashMap::new();
+    for repos in by_token.values() {
+        let handle = &repos[0].handle;
+        let budget = match request::github_rate_budget(handle).await {
+            Ok(Some(budget)) => budget,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("{}: Couldn't get the rate limit budget: {}", handle, e);
+                continue;
+            }
+        };
+        debug!(
+            "{}: {} requests left of the rate limit of its token, reset at {}",
+            handle,
+            budget.remaining,
+            chrono::DateTime::from_timestamp(budget.reset as i64, 0)
+                .map_or_else(|| budget.reset.to_string(), |reset| reset.to_rfc3339())
+        );
+        if let Some(cooldown) = budget.cooldown(repos.len(), now) {
+            warn!(
+                "{}: Only {} requests left of the rate limit of its token for {} repositories, submitting at most every {:.0?}",
+                handle,
+                budget.remaining,
+                repos.len(),
+                cooldown
+            );
+            for repo in repos {
+                cooldowns.insert(repo.handle.to_string(), cooldown);
+            }
+        }
+    }
+    cooldowns
+}
+
+/// Update every enabled repository once, writing the report of the run to `summary_file`
+/// if it is set, and returning the exit code of the run
+async fn run_updates(
+    config: &Config,
+    state: &Arc<UpdateState>,
+    summary_file: Option<&Path>,
+) -> i32 {
+    let started = Instant::now();
+    // Dates in update_branch are the same for every repository, even past midnight
+    let run_date = chrono::Utc::now().date_naive();
+    let deadline = config
+        .overall_timeout
+        .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
+    let grace_period = Duration::from_secs(config.shutdown_grace_period.unwrap_or(60));
     let ts = Arc::new(TMutex::new(Instant::now()));
     let mut handles = Vec::new();
-    // For the sake of efficient memory usage 'UpdateState' is created only once
-    let state = Arc::new(init_update_state());
+    let mut skipped = Vec::new();
+    // Before any repository is being updated, so that none of them is removed meanwhile
+    if config.gc_cache.unwrap_or(false) {
+        gc_cache(config, state);
+    }
+    let rate_limit_cooldowns = rate_limit_cooldowns(config, state).await;
 
     for repo in config.clone().repos {
-        let mut settings = repo.clone().settings.unwrap_or_default();
-
-        settings.merge(config.clone().settings);
+        if !repo.is_enabled() {
+            info!("{}: Skipping, it is disabled", repo.handle);
+            continue;
+        }
+        if state.shutdown.is_requested() {
+            skipped.push(repo.handle.to_string());
+            continue;
+        }
+        let settings = config.repo_settings(&repo);
+        let repo_name = repo.handle.to_string();
+        let rate_limit_cooldown = rate_limit_cooldowns.get(&repo_name).copied();
 
         let repo_longlived = repo.clone();
 
         let ts_copy1 = Arc::clone(&ts);
         let ts_copy2 = Arc::clone(&ts);
-        let state = Arc::clone(&state);
-        let handle = tokio::spawn(async move {
-            match settings.try_into() {
+        let state = Arc::clone(state);
+        let update = async move {
+            let settings: Result<UpdateSettings, _> = settings.try_into();
+            let settings = settings.map(|mut settings| {
+                settings.resolve_update_branch(run_date);
+                if let Some(cooldown) = rate_limit_cooldown {
+                    settings.cooldown = settings.cooldown.max(cooldown);
+                }
+                settings
+            });
+            match settings {
                 Err(e) => {
                     error!("{}: {}", repo_longlived.handle, e);
-                    Err(())
+                    Err(e.to_string())
                 }
                 Ok(settings) => match update_repo(
                     repo.handle.clone(),
@@ -316,8 +1481,67 @@ async fn main() {
                 )
                 .await
                 {
+                    // A human took over the update branch, which is no reason for an error report
+                    Err(UpdateError::SetupUpdateBranchError(
+                        git::SetupUpdateBranchError::HumanCommitsInUpdateBranch(author),
+                    )) => {
+                        warn!(
+                            "{}: Leaving the update branch alone, it has commits by {}",
+                            repo_longlived.handle, author
+                        );
+                        Ok(UpdateOutcome::default())
+                    }
+                    Err(UpdateError::SetupUpdateBranchError(
+                        e @ git::SetupUpdateBranchError::HumanCommitConflict(_),
+                    )) => {
+                        warn!(
+                            "{}: Leaving the update branch alone: {}",
+                            repo_longlived.handle, e
+                        );
+                        Ok(UpdateOutcome::default())
+                    }
+                    Err(UpdateError::ShuttingDown) => Ok(UpdateOutcome {
+                        skipped: true,
+                        ..Default::default()
+                    }),
                     Err(e) => {
                         error!("{}: {}", repo_longlived.handle, e);
+                        let message = e.to_string();
+
+                        if state.dry_run {
+                            info!(
+                                "{}: Dry run, not submitting the error report",
+                                repo_longlived.handle
+                            );
+                            return Err(message);
+                        }
+
+                        request::notify_error(&state, &settings, &repo_longlived.handle, &message)
+                            .await;
+
+                        if !settings.report_errors {
+                            return Err(message);
+                        }
+                        let failures = count_failure(&failures_file(
+                            &state,
+                            &settings,
+                            &repo_longlived.handle,
+                        ));
+                        if failures < settings.report_errors_after {
+                            info!(
+                                "{}: Not reporting the error yet, it failed {} of the {} times in a row needed",
+                                repo_longlived.handle, failures, settings.report_errors_after
+                            );
+                            return Err(message);
+                        }
+
+                        if let Err(e) = state.check_host(repo_longlived.handle.api_host()) {
+                            error!(
+                                "{}: Not submitting the error report: {}",
+                                repo_longlived.handle, e
+                            );
+                            return Err(message);
+                        }
 
                         let delay = (&settings as &UpdateSettings).cooldown;
                         let mut locked_ts = ts_copy2.lock().await;
@@ -337,22 +1561,128 @@ async fn main() {
                         if let Err(e) = result {
                             error!("An error occurred while submitting the error report: {}", e);
                         }
-                        Err(())
+                        Err(message)
+                    }
+                    Ok(outcome) => {
+                        // The failures only count when they are in a row
+                        let _ = std::fs::remove_file(failures_file(
+                            &state,
+                            &settings,
+                            &repo_longlived.handle,
+                        ));
+                        Ok(outcome)
                     }
-                    Ok(()) => Ok(()),
                 },
             }
-        });
-        handles.push(handle);
+        };
+        let handle = tokio::spawn(logging::with_repo(repo_name.clone(), update));
+        handles.push((repo_name, handle));
     }
-    if futures::future::join_all(handles)
-        .await
-        .iter()
-        .all(|res| matches!(res, Ok(r) if r.is_ok()))
-    {
-        std::process::exit(0);
+    // Past the deadline or the shutdown grace period, the unfinished updates
+    // are cancelled and come out as `None`
+    let outcomes =
+        futures::future::join_all(handles.into_iter().map(|(name, mut handle)| async move {
+            let deadline = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let grace_over = async {
+                state.shutdown.requested().await;
+                tokio::time::sleep(grace_period).await;
+            };
+            let limit = tokio::select! {
+                outcome = &mut handle => return (name, Some(outcome)),
+                _ = deadline => "overall timeout",
+                _ = grace_over => "shutdown grace period",
+            };
+            handle.abort();
+            error!("{}: Didn't finish within the {}", name, limit);
+            (name, None)
+        }))
+        .await;
+
+    let mut updated = 0;
+    let mut inputs_changed = 0;
+    let mut errors = 0;
+    let mut unfinished = 0;
+    let mut processed = 0;
+    let mut summaries = Vec::new();
+    for (name, outcome) in outcomes {
+        let status = match outcome {
+            Some(Ok(Ok(outcome))) if outcome.skipped => {
+                skipped.push(name.clone());
+                continue;
+            }
+            Some(Ok(Ok(outcome))) => {
+                processed += 1;
+                updated += outcome.updated as usize;
+                inputs_changed += outcome.inputs_changed;
+                if outcome.updated {
+                    RepoStatus::Updated {
+                        url: outcome.url,
+                        inputs_changed: outcome.inputs_changed,
+                        diff: outcome.diff,
+                    }
+                } else {
+                    RepoStatus::UpToDate
+                }
+            }
+            Some(Ok(Err(error))) => {
+                processed += 1;
+                errors += 1;
+                RepoStatus::Failed { error }
+            }
+            Some(Err(e)) => {
+                processed += 1;
+                errors += 1;
+                RepoStatus::Failed {
+                    error: e.to_string(),
+                }
+            }
+            None => {
+                unfinished += 1;
+                RepoStatus::Cancelled
+            }
+        };
+        summaries.push(RepoSummary { repo: name, status });
+    }
+    if !skipped.is_empty() {
+        warn!(
+            "Skipped {} repositories due to the shutdown: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+    }
+    summaries.extend(skipped.into_iter().map(|repo| RepoSummary {
+        repo,
+        status: RepoStatus::Skipped,
+    }));
+    if let Some(path) = summary_file {
+        if let Err(e) = summary::write(path, &summaries) {
+            error!("Couldn't write the summary to {}: {}", path.display(), e);
+        }
+    }
+    info!(
+        "Processed {} repositories in {:.1?}: {} updated, {} inputs changed, {} errors",
+        processed,
+        started.elapsed(),
+        updated,
+        inputs_changed,
+        errors
+    );
+
+    if unfinished > 0 {
+        error!(
+            "{} repositories didn't finish in time and were cancelled",
+            unfinished
+        );
+        75
+    } else if errors == 0 {
+        0
     } else {
         error!("Errors occured, please see above logs");
-        std::process::exit(1);
-    };
+        1
+    }
 }
@@ -0,0 +1,312 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::*;
+use thiserror::Error;
+
+use super::explain::{explain, render_command, render_env};
+use super::types::*;
+
+#[cfg(test)]
+mod tests;
+
+/// How many of the last lines of a failed command's output go into the "pull request" body
+const ERROR_LINES: usize = 20;
+
+#[derive(Debug, Error)]
+#[error("Couldn't get the env var {0} for the command: {1}")]
+pub struct EnvError(String, std::env::VarError);
+
+/// What nix printed to stderr, with its warnings apart from the rest
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stderr {
+    /// The warnings, each with the indented lines following it
+    pub warnings: Vec<String>,
+    /// Everything else, including the errors
+    pub rest: String,
+}
+
+impl std::fmt::Display for Stderr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rest.trim_end())?;
+        if !self.warnings.is_empty() {
+            write!(f, "\n\nWarnings:\n{}", self.warnings.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the ANSI escape sequences nix colors its messages with
+fn strip_colors(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Up to the final byte of the sequence
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Split what nix printed to stderr into its warnings and the rest
+pub fn split_warnings(stderr: &str) -> Stderr {
+    let mut split = Stderr::default();
+    let mut in_warning = false;
+    for line in stderr.lines().map(strip_colors) {
+        if line.starts_with("warning:") {
+            split.warnings.push(line);
+            in_warning = true;
+        } else if in_warning && line.starts_with(char::is_whitespace) {
+            let warning = split.warnings.last_mut().unwrap();
+            warning.push('\n');
+            warning.push_str(&line);
+        } else {
+            split.rest.push_str(&line);
+            split.rest.push('\n');
+            in_warning = false;
+        }
+    }
+    split
+}
+
+/// A `nix` command running in the flake directory of `workdir` (wrapped in the sandbox command, if any)
+/// with `nix_options` and `extra_nix_args`, and the repository's environment variables set over the
+/// environment inherited from update-daemon
+pub fn nix_command(settings: &UpdateSettings, workdir: &Path) -> Result<Command, EnvError> {
+    let mut command = match settings.sandbox_command.split_first() {
+        Some((program, args)) => {
+            let mut command = Command::new(program);
+            command.args(args).arg("nix");
+            command
+        }
+        None => Command::new("nix"),
+    };
+    // Before the subcommand and its arguments, which override them
+    let mut options = settings.nix_options.iter().collect::<Vec<_>>();
+    options.sort();
+    for (name, value) in options {
+        command.arg("--option").arg(name).arg(value);
+    }
+    command.args(&settings.extra_nix_args);
+    command.current_dir(settings.flake_dir(workdir));
+    for (name, value) in settings.env.iter() {
+        let value = match value {
+            EnvValue::Value(value) => value.clone(),
+            EnvValue::FromEnv { from_env } => {
+                std::env::var(from_env).map_err(|e| EnvError(from_env.clone(), e))?
+            }
+        };
+        command.env(name, value);
+    }
+    Ok(command)
+}
+
+/// How often `output_with_timeout` checks whether the command has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The result of `output_with_timeout`
+pub enum TimedOutput {
+    Finished(Output),
+    /// The command was killed, with the stderr it had written until then
+    TimedOut(Vec<u8>),
+}
+
+/// Output read so far, and the thread reading it
+type Reader = (Arc<Mutex<Vec<u8>>>, JoinHandle<()>);
+
+/// Read `source` to the end in a background thread
+fn read_in_background(mut source: impl Read + Send + 'static) -> Reader {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let thread_buffer = Arc::clone(&buffer);
+    let thread = std::thread::spawn(move || {
+        let mut chunk = [0; 4096];
+        while let Ok(n @ 1..) = source.read(&mut chunk) {
+            thread_buffer.lock().unwrap().extend_from_slice(&chunk[..n]);
+        }
+    });
+    (buffer, thread)
+}
+
+/// Wait for the rest of the output, until the pipe is closed
+fn finish_reading((buffer, thread): Reader) -> Vec<u8> {
+    // The thread doesn't panic, and the buffer is there regardless
+    let _ = thread.join();
+    std::mem::take(&mut *buffer.lock().unwrap())
+}
+
+/// Run `command` in a process group of its own, like `Command::output`, but kill
+/// the whole group (e.g. nix and the processes it spawned) after `timeout`
+pub fn output_with_timeout(
+    mut command: Command,
+    timeout: Duration,
+) -> std::io::Result<TimedOutput> {
+    let mut child = command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(TimedOutput::Finished(Output {
+                status,
+                stdout: finish_reading(stdout),
+                stderr: finish_reading(stderr),
+            }));
+        }
+        if Instant::now() >= deadline {
+            // A negative pid signals the whole process group, which has the child's pid
+            // SAFETY: kill has no memory safety requirements
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            child.wait()?;
+            let stderr = stderr.0.lock().unwrap().clone();
+            return Ok(TimedOutput::TimedOut(stderr));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Log the command, with the repository's environment variables, if requested
+pub fn explain_command(
+    state: &UpdateState,
+    handle: &RepoHandle,
+    settings: &UpdateSettings,
+    command: &Command,
+) {
+    if state.explain_commands {
+        explain(
+            &handle.to_string(),
+            &format!("$ {}{}", render_env(&settings.env), render_command(command)),
+            false,
+        );
+    }
+}
+
+/// Evaluate `attr` of the flake in `workdir`, summarizing the result in a line for the
+/// "pull request" body. A failure is only reported, as the evaluation is informational.
+pub fn eval_status(
+    state: &UpdateState,
+    handle: &RepoHandle,
+    settings: &UpdateSettings,
+    workdir: &Path,
+    attr: &str,
+) -> String {
+    let installable = format!(".#{}", attr);
+    let output = nix_command(settings, workdir)
+        .map_err(|e| e.to_string())
+        .and_then(|mut command| {
+            command
+                .arg("eval")
+                .arg(&installable)
+                .arg("--json")
+                .arg("--no-warn-dirty");
+            explain_command(state, handle, settings, &command);
+            command.output().map_err(|e| e.to_string())
+        });
+
+    match output {
+        Ok(output) if output.status.success() => {
+            format!("✅ `nix eval {}` succeeds", installable)
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let lines = stderr.lines().collect::<Vec<_>>();
+            warn!(
+                "{}: Failed to evaluate {}:\n{}",
+                handle, installable, stderr
+            );
+            format!(
+                "❌ `nix eval {}` fails:\n\n```\n{}\n```",
+                installable,
+                lines[lines.len().saturating_sub(ERROR_LINES)..].join("\n")
+            )
+        }
+        Err(e) => {
+            warn!("{}: Failed to run nix eval: {}", handle, e);
+            format!("❌ Couldn't run `nix eval {}`: {}", installable, e)
+        }
+    }
+}
+
+/// The result of `nix flake check`
+pub struct FlakeCheck {
+    pub passed: bool,
+    /// Section for the "pull request" body
+    pub report: String,
+}
+
+/// Run `nix flake check` (with `flake_check_args`) on the flake in `workdir`,
+/// giving up after `flake_check_timeout`
+pub async fn flake_check(
+    state: &UpdateState,
+    handle: &RepoHandle,
+    settings: &UpdateSettings,
+    workdir: &Path,
+) -> FlakeCheck {
+    let failed = |report: String| FlakeCheck {
+        passed: false,
+        report: format!("### ❌ `nix flake check` fails\n\n{}", report),
+    };
+
+    let mut command = match nix_command(settings, workdir) {
+        Ok(command) => command,
+        Err(e) => return failed(format!("Couldn't run it: {}", e)),
+    };
+    command
+        .arg("flake")
+        .arg("check")
+        .args(&settings.flake_check_args)
+        .arg("--no-warn-dirty");
+    explain_command(state, handle, settings, &command);
+
+    let mut command = tokio::process::Command::from(command);
+    // Don't leave the check running after the timeout
+    command.kill_on_drop(true);
+    match tokio::time::timeout(settings.flake_check_timeout, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => FlakeCheck {
+            passed: true,
+            report: "✅ `nix flake check` succeeds".to_string(),
+        },
+        Ok(Ok(output)) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let lines = stderr.lines().collect::<Vec<_>>();
+            warn!("{}: nix flake check failed:\n{}", handle, stderr);
+            failed(format!(
+                "```\n{}\n```",
+                lines[lines.len().saturating_sub(ERROR_LINES)..].join("\n")
+            ))
+        }
+        Ok(Err(e)) => {
+            warn!("{}: Failed to run nix flake check: {}", handle, e);
+            failed(format!("Couldn't run it: {}", e))
+        }
+        Err(_) => {
+            warn!(
+                "{}: nix flake check timed out after {:?}",
+                handle, settings.flake_check_timeout
+            );
+            failed(format!(
+                "It didn't finish in {} seconds",
+                settings.flake_check_timeout.as_secs()
+            ))
+        }
+    }
+}
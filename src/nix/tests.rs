@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+#[test]
+fn finished_command_output_is_complete() {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("echo out; echo err >&2");
+
+    match output_with_timeout(command, Duration::from_secs(60)).unwrap() {
+        TimedOutput::Finished(output) => {
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"out\n");
+            assert_eq!(output.stderr, b"err\n");
+        }
+        TimedOutput::TimedOut(_) => panic!("the command timed out"),
+    }
+}
+
+/// Whether the process `pid` has exited (possibly not reaped yet)
+fn exited(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .trim_start()
+            .starts_with('Z'),
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn timed_out_command_group_is_killed() {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("sleep 60 & echo $! >&2; wait");
+
+    let started = Instant::now();
+    let stderr = match output_with_timeout(command, Duration::from_millis(500)).unwrap() {
+        TimedOutput::TimedOut(stderr) => String::from_utf8(stderr).unwrap(),
+        TimedOutput::Finished(_) => panic!("the command wasn't killed"),
+    };
+    assert!(started.elapsed() < Duration::from_secs(30));
+
+    // The sleep started by the command is killed with it
+    let sleep = stderr.trim();
+    while !exited(sleep) {
+        assert!(started.elapsed() < Duration::from_secs(30));
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn nix_arguments_precede_the_subcommand() {
+    use std::convert::TryInto;
+
+    let settings: UpdateSettings = UpdateSettingsOptional {
+        author: Some(Author {
+            name: "update-daemon".to_string(),
+            email: "update-daemon@example.com".to_string(),
+        }),
+        cooldown: Some(1000),
+        sandbox_command: Some(vec!["sandbox".to_string()]),
+        nix_options: Some(
+            [
+                ("substituters", "https://a"),
+                ("extra-substituters", "https://b"),
+            ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        ),
+        extra_nix_args: Some(vec![
+            "--extra-experimental-features".to_string(),
+            "nix-command flakes".to_string(),
+        ]),
+        ..Default::default()
+    }
+    .try_into()
+    .unwrap();
+
+    let mut command = nix_command(&settings, Path::new("/")).unwrap();
+    command.arg("flake").arg("update").arg("--no-warn-dirty");
+    assert_eq!(command.get_program(), "sandbox");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        [
+            "nix",
+            "--option",
+            "extra-substituters",
+            "https://b",
+            "--option",
+            "substituters",
+            "https://a",
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "flake",
+            "update",
+            "--no-warn-dirty"
+        ]
+    );
+}
+
+#[test]
+fn warnings_are_split_from_errors() {
+    let stderr = "\u{1b}[35;1mwarning:\u{1b}[0m error: unable to download 'https://github.com/x': HTTP error 429; retrying in 281 ms\n\
+                  • Updated input 'nixpkgs':\n    'github:NixOS/nixpkgs/1'\n\
+                  warning: Git tree is dirty\n\
+                  error:\n       … while updating the lock file\n\n       error: cannot find flake\n";
+    let split = split_warnings(stderr);
+    assert_eq!(
+        split.warnings,
+        [
+            "warning: error: unable to download 'https://github.com/x': HTTP error 429; retrying in 281 ms",
+            "warning: Git tree is dirty"
+        ]
+    );
+    assert_eq!(
+        split.rest,
+        "• Updated input 'nixpkgs':\n    'github:NixOS/nixpkgs/1'\n\
+         error:\n       … while updating the lock file\n\n       error: cannot find flake\n"
+    );
+    assert!(split.to_string().ends_with(
+        "error: cannot find flake\n\nWarnings:\nwarning: error: unable to download \
+         'https://github.com/x': HTTP error 429; retrying in 281 ms\nwarning: Git tree is dirty"
+    ));
+}
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::super::types::{url_host, HostNotAllowed, RepoHandle, UpdateState};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+const COLOR_SUCCESS: u32 = 0x2ecc71;
+const COLOR_ERROR: u32 = 0xe74c3c;
+
+/// Discord limits embed descriptions to 4096 characters
+const MAX_DESCRIPTION: usize = 4000;
+
+#[derive(Debug, Error)]
+pub enum DiscordError {
+    #[error("Couldn't get the webhook URL from env var: {0}")]
+    WebhookUrl(#[from] std::env::VarError),
+    #[error("Error posting to the webhook: {0}")]
+    Post(#[from] reqwest::Error),
+    #[error("{0}")]
+    HostNotAllowed(#[from] HostNotAllowed),
+}
+
+fn code_block(text: &str) -> String {
+    let text = match text.char_indices().nth(MAX_DESCRIPTION) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    };
+    format!("```\n{}\n```", text.trim_end())
+}
+
+async fn post_embed(
+    state: &UpdateState,
+    webhook_env_var: &str,
+    embed: Value,
+) -> Result<(), DiscordError> {
+    let url = std::env::var(webhook_env_var)?;
+    state.check_host(url_host(&url))?;
+    reqwest::Client::new()
+        .post(url)
+        .json(&json!({ "embeds": [embed] }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+pub async fn notify_update(
+    state: &UpdateState,
+    webhook_env_var: &str,
+    handle: &RepoHandle,
+    summary: &str,
+    url: Option<&str>,
+) -> Result<(), DiscordError> {
+    let mut embed = json!({
+        "title": format!("Updated flake.lock in {}", handle),
+        "description": code_block(summary),
+        "color": COLOR_SUCCESS,
+    });
+    if let Some(url) = url {
+        embed["url"] = json!(url);
+    }
+    post_embed(state, webhook_env_var, embed).await
+}
+
+pub async fn notify_error(
+    state: &UpdateState,
+    webhook_env_var: &str,
+    handle: &RepoHandle,
+    error: &str,
+) -> Result<(), DiscordError> {
+    let embed = json!({
+        "title": format!("Failed to update flake.lock in {}", handle),
+        "description": code_block(error),
+        "color": COLOR_ERROR,
+    });
+    post_embed(state, webhook_env_var, embed).await
+}
@@ -0,0 +1,421 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Pull requests on Gitea and Forgejo, which share the REST API
+
+use super::super::types::UpdateSettings;
+use super::{
+    draft_title, is_error_report, requests_to_update, select_request, updated_title,
+    AmbiguousRequests, ExistingRequest, RequestHead, ERROR_REPORT_SEARCH,
+};
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use log::*;
+
+/// Number of pull requests or issues requested per page
+const PAGE_LIMIT: usize = 50;
+
+#[derive(Debug, Error)]
+pub enum PullRequestError {
+    #[error("Error during a gitea API call: {0}")]
+    GiteaError(#[from] reqwest::Error),
+    #[error("{0}")]
+    Ambiguous(#[from] AmbiguousRequests),
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequest {
+    number: u64,
+    title: String,
+    html_url: String,
+    body: Option<String>,
+    head: Branch,
+    base: Branch,
+    #[serde(default)]
+    labels: Vec<Label>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Branch {
+    #[serde(rename = "ref")]
+    ref_field: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Label {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Issue {
+    number: u64,
+    html_url: String,
+    title: String,
+    body: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    login: String,
+}
+
+/// Client for the API of a single repository
+struct Client {
+    http: reqwest::Client,
+    repo_url: String,
+    api_url: String,
+    token: String,
+}
+
+impl Client {
+    fn new(base_url: &str, owner: &str, repo: &str, token: String) -> Self {
+        let api_url = format!("{}/api/v1", base_url.trim_end_matches('/'));
+        Client {
+            http: reqwest::Client::new(),
+            repo_url: format!("{}/repos/{}/{}", api_url, owner, repo),
+            api_url,
+            token,
+        }
+    }
+
+    /// Request to `path` of the repository
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.request_url(method, format!("{}{}", self.repo_url, path))
+    }
+
+    fn request_url(&self, method: Method, url: String) -> RequestBuilder {
+        self.http
+            .request(method, url)
+            .header(AUTHORIZATION, format!("token {}", self.token))
+    }
+
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, PullRequestError> {
+        Ok(request.send().await?.error_for_status()?.json().await?)
+    }
+
+    /// All items listed at `path`, with `query`, page by page
+    async fn list<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<T>, PullRequestError> {
+        let mut items = Vec::new();
+        for page in 1.. {
+            let page: Vec<T> = Client::send(
+                self.request(Method::GET, path)
+                    .query(query)
+                    .query(&[("limit", PAGE_LIMIT), ("page", page)]),
+            )
+            .await?;
+            let last = page.len() < PAGE_LIMIT;
+            items.extend(page);
+            if last {
+                break;
+            }
+        }
+        Ok(items)
+    }
+}
+
+fn is_update_pull_request(settings: &UpdateSettings, pr: &PullRequest) -> bool {
+    let head_matches = if settings.search_head_prefix {
+        pr.head.ref_field.starts_with(&settings.update_branch)
+    } else {
+        pr.head.ref_field == settings.update_branch
+    };
+    head_matches && (settings.search_any_base || pr.base.ref_field == settings.default_branch)
+}
+
+/// Find the update pull requests: the managed one if `managed_pr_number` is set,
+/// otherwise the open ones from `update_branch`
+async fn find_update_pull_requests(
+    client: &Client,
+    settings: &UpdateSettings,
+) -> Result<Vec<PullRequest>, PullRequestError> {
+    if let Some(number) = settings.managed_pr_number {
+        let path = format!("/pulls/{}", number);
+        return Ok(vec![
+            Client::send(client.request(Method::GET, &path)).await?,
+        ]);
+    }
+    Ok(client
+        .list::<PullRequest>("/pulls", &[("state", "open")])
+        .await?
+        .into_iter()
+        // `select_request` takes the last one, which keeps it the first listed
+        .rev()
+        .filter(|pr| is_update_pull_request(settings, pr))
+        .collect())
+}
+
+/// Find the update pull request, if there is one
+async fn find_update_pull_request(
+    client: &Client,
+    settings: &UpdateSettings,
+) -> Result<Option<PullRequest>, PullRequestError> {
+    let prs = find_update_pull_requests(client, settings).await?;
+    Ok(select_request(settings, prs)?)
+}
+
+/// Find the update pull request, if there is one
+pub async fn find_pull_request(
+    settings: &UpdateSettings,
+    base_url: String,
+    owner: String,
+    repo: String,
+    token: String,
+) -> Result<Option<ExistingRequest>, PullRequestError> {
+    let client = Client::new(&base_url, &owner, &repo, token);
+
+    Ok(find_update_pull_request(&client, settings)
+        .await?
+        .map(|pr| ExistingRequest {
+            url: pr.html_url,
+            body: pr.body,
+        }))
+}
+
+/// Find the update pull request and its head branch. In `comment_only` mode, this is
+/// the open one with `comment_label` if it is set.
+pub async fn find_request_head(
+    settings: &UpdateSettings,
+    base_url: String,
+    owner: String,
+    repo: String,
+    token: String,
+) -> Result<Option<RequestHead>, PullRequestError> {
+    let client = Client::new(&base_url, &owner, &repo, token);
+    let found = match (&settings.comment_label, settings.comment_only) {
+        (Some(label), true) => client
+            .list::<PullRequest>("/pulls", &[("state", "open")])
+            .await?
+            .into_iter()
+            .find(|pr| {
+                pr.base.ref_field == settings.default_branch
+                    && pr.labels.iter().any(|l| &l.name == label)
+            }),
+        _ => find_update_pull_request(&client, settings).await?,
+    };
+
+    Ok(found.map(|pr| RequestHead {
+        number: pr.number,
+        branch: pr.head.ref_field,
+        url: pr.html_url,
+    }))
+}
+
+/// Login of the user the token belongs to
+pub async fn current_user(base_url: String, token: String) -> Result<String, PullRequestError> {
+    let client = Client::new(&base_url, "", "", token);
+    let me: User =
+        Client::send(client.request_url(Method::GET, format!("{}/user", client.api_url))).await?;
+    Ok(me.login)
+}
+
+async fn comment(client: &Client, number: u64, body: String) -> Result<(), PullRequestError> {
+    let path = format!("/issues/{}/comments", number);
+    let _: serde_json::Value = Client::send(
+        client
+            .request(Method::POST, &path)
+            .json(&json!({ "body": body })),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn comment_on_pull_request(
+    base_url: String,
+    owner: String,
+    repo: String,
+    token: String,
+    number: u64,
+    body: String,
+) -> Result<(), PullRequestError> {
+    let client = Client::new(&base_url, &owner, &repo, token);
+    comment(&client, number, body).await
+}
+
+/// Add `labels` to the pull request, which leaves the ones it already has alone.
+/// Failing to add them (e.g. because they don't exist in the repository) is only worth a warning.
+async fn add_labels(client: &Client, settings: &UpdateSettings, number: u64) {
+    if settings.labels.is_empty() {
+        return;
+    }
+    let path = format!("/issues/{}/labels", number);
+    let added: Result<serde_json::Value, _> = Client::send(
+        client
+            .request(Method::POST, &path)
+            .json(&json!({ "labels": settings.labels })),
+    )
+    .await;
+    if let Err(e) = added {
+        warn!("Couldn't add the labels to #{}: {}", number, e);
+    }
+}
+
+/// Request reviews from `reviewers` and assign `assignees` to a new pull request.
+/// Failing to (e.g. for users who don't exist) is only worth a warning.
+async fn request_people(client: &Client, settings: &UpdateSettings, number: u64) {
+    if !settings.reviewers.is_empty() {
+        let path = format!("/pulls/{}/requested_reviewers", number);
+        let requested: Result<serde_json::Value, _> = Client::send(
+            client
+                .request(Method::POST, &path)
+                .json(&json!({ "reviewers": settings.reviewers })),
+        )
+        .await;
+        if let Err(e) = requested {
+            warn!("Couldn't request reviews on #{}: {}", number, e);
+        }
+    }
+    if !settings.assignees.is_empty() {
+        let path = format!("/issues/{}", number);
+        let assigned: Result<serde_json::Value, _> = Client::send(
+            client
+                .request(Method::PATCH, &path)
+                .json(&json!({ "assignees": settings.assignees })),
+        )
+        .await;
+        if let Err(e) = assigned {
+            warn!("Couldn't assign #{}: {}", number, e);
+        }
+    }
+}
+
+pub async fn submit_or_update_pull_request(
+    settings: UpdateSettings,
+    base_url: String,
+    owner: String,
+    repo: String,
+    token: String,
+    body: String,
+    submit: bool,
+) -> Result<Option<String>, PullRequestError> {
+    let client = Client::new(&base_url, &owner, &repo, token);
+
+    // If there are PRs already, update them and be done
+    let prs = requests_to_update(
+        &settings,
+        find_update_pull_requests(&client, &settings).await?,
+    )?;
+    if !prs.is_empty() {
+        for pr in &prs {
+            let path = format!("/pulls/{}", pr.number);
+            let _: PullRequest = Client::send(client.request(Method::PATCH, &path).json(&json!({
+                "title": updated_title(&settings, &pr.title, "WIP:"),
+                "body": body,
+            })))
+            .await?;
+            add_labels(&client, &settings, pr.number).await;
+            info!("Updated PR {}", pr.html_url);
+        }
+        Ok(prs.last().map(|pr| pr.html_url.clone()))
+    }
+    // If there isn't, submit only when `submit` is passed
+    else if submit {
+        let title = if settings.draft {
+            draft_title(&settings.title, "WIP:")
+        } else {
+            settings.title.clone()
+        };
+        let pr: PullRequest = Client::send(client.request(Method::POST, "/pulls").json(&json!({
+            "title": title,
+            "head": settings.update_branch,
+            "base": settings.default_branch,
+            "body": body,
+        })))
+        .await?;
+        add_labels(&client, &settings, pr.number).await;
+        request_people(&client, &settings, pr.number).await;
+        info!("Submitted PR {}", pr.html_url);
+        Ok(Some(pr.html_url))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Close the update pull requests, returning their URLs
+pub async fn close_pull_requests(
+    settings: &UpdateSettings,
+    base_url: String,
+    owner: String,
+    repo: String,
+    token: String,
+) -> Result<Vec<String>, PullRequestError> {
+    let client = Client::new(&base_url, &owner, &repo, token);
+
+    let prs = requests_to_update(
+        settings,
+        find_update_pull_requests(&client, settings).await?,
+    )?;
+    let mut closed = Vec::new();
+    for pr in prs {
+        let path = format!("/pulls/{}", pr.number);
+        let _: PullRequest = Client::send(
+            client
+                .request(Method::PATCH, &path)
+                .json(&json!({ "state": "closed" })),
+        )
+        .await?;
+        closed.push(pr.html_url);
+    }
+    Ok(closed)
+}
+
+pub async fn submit_issue_or_pull_request_comment(
+    settings: UpdateSettings,
+    base_url: String,
+    owner: String,
+    repo: String,
+    token: String,
+    title: String,
+    body: String,
+) -> Result<(), PullRequestError> {
+    let client = Client::new(&base_url, &owner, &repo, token);
+
+    // If there is a PR already, comment on it
+    if let Some(pr) = find_update_pull_request(&client, &settings).await? {
+        return comment(&client, pr.number, body).await;
+    }
+
+    let me: User =
+        Client::send(client.request_url(Method::GET, format!("{}/user", client.api_url))).await?;
+
+    let issues: Vec<Issue> = client
+        .list(
+            "/issues",
+            &[
+                ("state", "open"),
+                ("type", "issues"),
+                ("created_by", &me.login),
+                ("q", ERROR_REPORT_SEARCH),
+            ],
+        )
+        .await?;
+
+    let mut reports = issues
+        .into_iter()
+        .filter(|issue| is_error_report(&issue.title, issue.body.as_deref()));
+    let report = reports.next();
+    if reports.next().is_some() {
+        warn!("More than one error report issue; picking the first one");
+    }
+    if let Some(issue) = report {
+        info!("Commenting on issue {}", issue.html_url);
+        comment(&client, issue.number, body).await
+    } else {
+        let _: Issue = Client::send(
+            client
+                .request(Method::POST, "/issues")
+                .json(&json!({ "title": title, "body": body })),
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -2,21 +2,39 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use super::super::types::UpdateSettings;
+use super::super::types::{MergeMethod, UpdateSettings};
+use super::retry::{retry_rate_limited, Backoff};
+use super::{
+    is_error_report, requests_to_update, select_request, AmbiguousRequests, ExistingRequest,
+    OrgRepository, RateBudget, RequestHead, ERROR_REPORT_SEARCH,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
 use thiserror::Error;
 
 use log::*;
 
-const GITHUB_BASE_URL: &str = "https://api.github.com";
+pub const GITHUB_BASE_URL: &str = "https://api.github.com";
 
 #[derive(Debug, Error)]
 pub enum PullRequestError {
     #[error("Repository was archived so is read-only.")]
     ReadOnlyRepo,
+    #[error("Hit a GitHub rate limit: {0}")]
+    RateLimit(String),
     #[error("Other error during a github operation: {0}")]
     GithubError(octocrab::Error),
-    #[error("Couldn't get a GITHUB_TOKEN env var: {0}")]
-    TokenError(#[from] std::env::VarError),
+    #[error("GitHub GraphQL API errors: {0}")]
+    GraphqlError(String),
+    #[error("Unexpected GitHub GraphQL API response: {0}")]
+    GraphqlResponseError(#[from] serde_json::Error),
+    #[error("There is no discussion category {0}")]
+    NoDiscussionCategory(String),
+    #[error("{0}")]
+    Ambiguous(#[from] AmbiguousRequests),
 }
 
 impl From<octocrab::Error> for PullRequestError {
@@ -27,63 +45,469 @@ impl From<octocrab::Error> for PullRequestError {
             {
                 PullRequestError::ReadOnlyRepo
             }
+            // Rate limits are reported with a 403 or 429 and only distinguishable by the message
+            octocrab::Error::GitHub { ref source, .. }
+                if source.message.contains("rate limit")
+                    || source.message.contains("abuse detection") =>
+            {
+                PullRequestError::RateLimit(source.message.clone())
+            }
             e => PullRequestError::GithubError(e),
         }
     }
 }
 
-pub async fn submit_or_update_pull_request(
-    settings: UpdateSettings,
-    base_url: Option<String>,
-    owner: String,
-    repo: String,
-    token_env_var: Option<String>,
-    body: String,
-    submit: bool,
-) -> Result<(), PullRequestError> {
+/// Run `operation`, retrying it with a backoff while it hits GitHub rate limits
+pub async fn retry_rate_limit<T, F, Fut>(
+    settings: &UpdateSettings,
+    operation: F,
+) -> Result<T, PullRequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, PullRequestError>>,
+{
+    retry_rate_limited(
+        &Backoff::from_settings(settings),
+        |e| matches!(e, PullRequestError::RateLimit(_)),
+        operation,
+    )
+    .await
+}
+
+/// Clients by base URL and token, so that the repositories on the same GitHub instance
+/// with the same token share the connection pool
+static CLIENTS: OnceLock<Mutex<HashMap<(String, String), octocrab::Octocrab>>> = OnceLock::new();
+
+fn client(base_url: Option<String>, token: String) -> Result<octocrab::Octocrab, PullRequestError> {
+    let key = (
+        base_url.unwrap_or_else(|| GITHUB_BASE_URL.to_string()),
+        token,
+    );
+    let mut clients = CLIENTS.get_or_init(Default::default).lock().unwrap();
+    if let Some(crab) = clients.get(&key) {
+        return Ok(crab.clone());
+    }
     let crab = octocrab::OctocrabBuilder::new()
-        .base_url(base_url.unwrap_or_else(|| GITHUB_BASE_URL.to_string()))?
-        .personal_token(std::env::var(
-            token_env_var.unwrap_or_else(|| "GITHUB_TOKEN".to_string()),
-        )?)
+        .base_url(key.0.as_str())?
+        .personal_token(key.1.clone())
         .build()?;
-    let query = format!(
-        "head:{} base:{} is:pr state:open repo:{}/{}",
-        settings.update_branch, settings.default_branch, owner, repo
+    clients.insert(key, crab.clone());
+    Ok(crab)
+}
+
+/// Login of the user the token belongs to
+pub async fn current_user(
+    base_url: Option<String>,
+    token: String,
+) -> Result<String, PullRequestError> {
+    Ok(client(base_url, token)?.current().user().await?.login)
+}
+
+/// What is left of the core rate limit of `token`
+pub async fn rate_budget(
+    base_url: Option<String>,
+    token: String,
+) -> Result<RateBudget, PullRequestError> {
+    let core = client(base_url, token)?
+        .ratelimit()
+        .get()
+        .await?
+        .resources
+        .core;
+    Ok(RateBudget {
+        remaining: core.remaining,
+        reset: core.reset as u64,
+    })
+}
+
+/// The repositories of the organization `owner`
+pub async fn org_repositories(
+    base_url: Option<String>,
+    owner: String,
+    token: String,
+) -> Result<Vec<OrgRepository>, PullRequestError> {
+    let crab = client(base_url, token)?;
+    let page = crab.orgs(owner).list_repos().per_page(100).send().await?;
+    Ok(crab
+        .all_pages(page)
+        .await?
+        .into_iter()
+        .map(|repo| OrgRepository {
+            name: repo.name,
+            archived: repo.archived.unwrap_or(false),
+            fork: repo.fork.unwrap_or(false),
+        })
+        .collect())
+}
+
+/// Search query matching the open update pull request.
+/// NB: GitHub always matches the head branch by prefix.
+fn search_query(settings: &UpdateSettings, owner: &str, repo: &str) -> String {
+    let mut query = format!(
+        "head:{} is:pr state:open repo:{}/{}",
+        settings.update_branch, owner, repo
     );
-    let mut page = crab
+    if !settings.search_any_base {
+        query.push_str(&format!(" base:{}", settings.default_branch));
+    }
+    query
+}
+
+/// Find the update pull requests: the managed one if `managed_pr_number` is set,
+/// otherwise the open ones from `update_branch`
+async fn find_update_issues(
+    crab: &octocrab::Octocrab,
+    settings: &UpdateSettings,
+    owner: &str,
+    repo: &str,
+) -> Result<Vec<octocrab::models::issues::Issue>, PullRequestError> {
+    if let Some(number) = settings.managed_pr_number {
+        return Ok(vec![crab.issues(owner, repo).get(number).await?]);
+    }
+    let query = search_query(settings, owner, repo);
+    let page = crab
         .search()
         .issues_and_pull_requests(query.as_str())
         .send()
         .await?;
+    if settings.fork.is_none() && settings.search_head_prefix {
+        return Ok(page.items);
+    }
+    // Search results don't tell which repository the head branch is in,
+    // and the head qualifier always matches by prefix
+    let head_prefix = settings
+        .fork
+        .as_ref()
+        .map(|fork| format!("{}:", fork.owner));
+    let mut issues = Vec::new();
+    for issue in page.items {
+        let pull = crab.pulls(owner, repo).get(issue.number).await?;
+        let in_fork = match &head_prefix {
+            Some(head_prefix) => pull
+                .head
+                .label
+                .as_ref()
+                .is_some_and(|label| label.starts_with(head_prefix)),
+            None => true,
+        };
+        if in_fork && (settings.search_head_prefix || pull.head.ref_field == settings.update_branch)
+        {
+            issues.push(issue);
+        }
+    }
+    Ok(issues)
+}
 
-    // If there is a PR already, update it and be done
-    if let Some(pr) = page.items.pop() {
-        crab.issues(owner, repo)
-            .update(pr.number)
-            .title(settings.title.as_str())
-            .body(&body)
-            .send()
-            .await?;
-        info!("Updated PR {}", pr.html_url);
+/// Find the update pull request, if there is one
+async fn find_update_issue(
+    crab: &octocrab::Octocrab,
+    settings: &UpdateSettings,
+    owner: &str,
+    repo: &str,
+) -> Result<Option<octocrab::models::issues::Issue>, PullRequestError> {
+    let issues = find_update_issues(crab, settings, owner, repo).await?;
+    Ok(select_request(settings, issues)?)
+}
+
+/// Head of the update pull request: `update_branch`, in the fork if there is one
+fn pull_request_head(settings: &UpdateSettings) -> String {
+    match &settings.fork {
+        Some(fork) => format!("{}:{}", fork.owner, settings.update_branch),
+        None => settings.update_branch.clone(),
+    }
+}
+
+/// Find the update pull request, if there is one
+pub async fn find_pull_request(
+    settings: &UpdateSettings,
+    base_url: Option<String>,
+    owner: String,
+    repo: String,
+    token: String,
+) -> Result<Option<ExistingRequest>, PullRequestError> {
+    let crab = client(base_url, token)?;
+
+    if let Some(category) = &settings.discussion_category {
+        return Ok(find_discussion(&crab, settings, &owner, &repo, category)
+            .await?
+            .discussion
+            .map(|discussion| ExistingRequest {
+                url: discussion.url,
+                body: Some(discussion.body),
+            }));
+    }
+
+    Ok(find_update_issue(&crab, settings, &owner, &repo)
+        .await?
+        .map(|pr| ExistingRequest {
+            url: pr.html_url.to_string(),
+            body: pr.body,
+        }))
+}
+
+/// Find the update pull request and its head branch. In `comment_only` mode, this is
+/// the open one with `comment_label` if it is set.
+pub async fn find_request_head(
+    settings: &UpdateSettings,
+    base_url: Option<String>,
+    owner: String,
+    repo: String,
+    token: String,
+) -> Result<Option<RequestHead>, PullRequestError> {
+    let crab = client(base_url, token)?;
+    let found = match (&settings.comment_label, settings.comment_only) {
+        (Some(label), true) => {
+            let query = format!(
+                "label:\"{}\" base:{} is:pr state:open repo:{}/{}",
+                label, settings.default_branch, owner, repo
+            );
+            let mut page = crab
+                .search()
+                .issues_and_pull_requests(query.as_str())
+                .send()
+                .await?;
+            page.items.pop()
+        }
+        _ => find_update_issue(&crab, settings, &owner, &repo).await?,
+    };
+
+    let pr = match found {
+        Some(pr) => pr,
+        None => return Ok(None),
+    };
+    // Search results don't include the head branch
+    let pull = crab.pulls(owner, repo).get(pr.number).await?;
+    Ok(Some(RequestHead {
+        number: pr.number,
+        branch: pull.head.ref_field,
+        url: pr.html_url.to_string(),
+    }))
+}
+
+pub async fn comment_on_pull_request(
+    base_url: Option<String>,
+    owner: String,
+    repo: String,
+    token: String,
+    number: u64,
+    body: String,
+) -> Result<(), PullRequestError> {
+    let crab = client(base_url, token)?;
+    crab.issues(owner, repo)
+        .create_comment(number, body)
+        .await?;
+    Ok(())
+}
+
+/// Add `labels` to the pull request, which leaves the ones it already has alone.
+/// Failing to add them (e.g. for lack of permissions) is only worth a warning.
+async fn add_labels(
+    crab: &octocrab::Octocrab,
+    settings: &UpdateSettings,
+    owner: &str,
+    repo: &str,
+    number: u64,
+) {
+    if settings.labels.is_empty() {
+        return;
+    }
+    if let Err(e) = crab
+        .issues(owner, repo)
+        .add_labels(number, &settings.labels)
+        .await
+    {
+        warn!(
+            "Couldn't add the labels to {}/{}#{}: {}",
+            owner, repo, number, e
+        );
+    }
+}
+
+/// Request reviews from `reviewers` and assign `assignees` to a new pull request.
+/// Failing to (e.g. for users who can't be requested) is only worth a warning.
+async fn request_people(
+    crab: &octocrab::Octocrab,
+    settings: &UpdateSettings,
+    owner: &str,
+    repo: &str,
+    number: u64,
+) {
+    if !settings.reviewers.is_empty() {
+        let route = format!(
+            "repos/{}/{}/pulls/{}/requested_reviewers",
+            owner, repo, number
+        );
+        let requested: Result<serde_json::Value, _> = crab
+            .post(route, Some(&json!({ "reviewers": settings.reviewers })))
+            .await;
+        if let Err(e) = requested {
+            warn!(
+                "Couldn't request reviews on {}/{}#{}: {}",
+                owner, repo, number, e
+            );
+        }
+    }
+    if !settings.assignees.is_empty() {
+        let route = format!("repos/{}/{}/issues/{}/assignees", owner, repo, number);
+        let assigned: Result<serde_json::Value, _> = crab
+            .post(route, Some(&json!({ "assignees": settings.assignees })))
+            .await;
+        if let Err(e) = assigned {
+            warn!("Couldn't assign {}/{}#{}: {}", owner, repo, number, e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PullRequestId {
+    pull_request: Id,
+}
+
+#[derive(Debug, Deserialize)]
+struct Id {
+    id: String,
+}
+
+/// Enable auto-merge of a new pull request with `method`. Failing to (e.g. because
+/// the repository doesn't allow auto-merge) is only worth a warning.
+async fn enable_auto_merge(
+    crab: &octocrab::Octocrab,
+    method: MergeMethod,
+    owner: &str,
+    repo: &str,
+    number: u64,
+) {
+    let method = match method {
+        MergeMethod::Merge => "MERGE",
+        MergeMethod::Squash => "SQUASH",
+        MergeMethod::Rebase => "REBASE",
+    };
+    let enabled = async {
+        let pull: Repository<PullRequestId> = graphql(
+            crab,
+            "query($owner: String!, $repo: String!, $number: Int!) {
+                repository(owner: $owner, name: $repo) { pullRequest(number: $number) { id } }
+            }",
+            json!({ "owner": owner, "repo": repo, "number": number }),
+        )
+        .await?;
+        graphql::<serde_json::Value>(
+            crab,
+            "mutation($id: ID!, $method: PullRequestMergeMethod!) {
+                enablePullRequestAutoMerge(input: { pullRequestId: $id, mergeMethod: $method }) {
+                    clientMutationId
+                }
+            }",
+            json!({ "id": pull.repository.pull_request.id, "method": method }),
+        )
+        .await
+    };
+    if let Err(e) = enabled.await {
+        warn!(
+            "Couldn't enable auto-merge on {}/{}#{}: {}",
+            owner, repo, number, e
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn submit_or_update_pull_request(
+    settings: UpdateSettings,
+    base_url: Option<String>,
+    owner: String,
+    repo: String,
+    token: String,
+    body: String,
+    submit: bool,
+    draft: bool,
+) -> Result<Option<String>, PullRequestError> {
+    let crab = client(base_url, token)?;
+
+    if let Some(category) = &settings.discussion_category {
+        return submit_or_update_discussion(
+            &crab, &settings, &owner, &repo, category, body, submit,
+        )
+        .await;
+    }
+
+    // If there are PRs already, update them and be done
+    let prs = requests_to_update(
+        &settings,
+        find_update_issues(&crab, &settings, &owner, &repo).await?,
+    )?;
+    if !prs.is_empty() {
+        for pr in &prs {
+            crab.issues(owner.clone(), repo.clone())
+                .update(pr.number)
+                .title(settings.title.as_str())
+                .body(&body)
+                .send()
+                .await?;
+            add_labels(&crab, &settings, &owner, &repo, pr.number).await;
+            info!("Updated PR {}", pr.html_url);
+        }
+        Ok(prs.last().map(|pr| pr.html_url.to_string()))
     }
     // If there isn't, submit only when `submit` is passed
     else if submit {
         let pr = crab
             .pulls(owner.clone(), repo.clone())
             .create(
-                settings.title,
-                settings.update_branch,
-                settings.default_branch,
+                settings.title.as_str(),
+                pull_request_head(&settings),
+                settings.default_branch.as_str(),
             )
             .body(body)
             .maintainer_can_modify(true)
+            .draft(draft || settings.draft)
             .send()
             .await?;
-        crab.issues(owner, repo).update(pr.number).send().await?;
-        info!("Submitted PR {}", pr.html_url.unwrap());
+        add_labels(&crab, &settings, &owner, &repo, pr.number).await;
+        request_people(&crab, &settings, &owner, &repo, pr.number).await;
+        // Drafts can't be merged, automatically or not
+        match settings.auto_merge {
+            Some(method) if !(draft || settings.draft) => {
+                enable_auto_merge(&crab, method, &owner, &repo, pr.number).await
+            }
+            _ => {}
+        }
+        let url = pr.html_url.unwrap().to_string();
+        info!("Submitted PR {}", url);
+        Ok(Some(url))
+    } else {
+        Ok(None)
     }
-    Ok(())
+}
+
+/// Close the update pull requests, returning their URLs.
+/// Discussions are left alone, as they can't be closed.
+pub async fn close_pull_requests(
+    settings: &UpdateSettings,
+    base_url: Option<String>,
+    owner: String,
+    repo: String,
+    token: String,
+) -> Result<Vec<String>, PullRequestError> {
+    if settings.discussion_category.is_some() {
+        return Ok(Vec::new());
+    }
+    let crab = client(base_url, token)?;
+
+    let prs = requests_to_update(
+        settings,
+        find_update_issues(&crab, settings, &owner, &repo).await?,
+    )?;
+    let mut closed = Vec::new();
+    for pr in prs {
+        crab.issues(owner.clone(), repo.clone())
+            .update(pr.number)
+            .state(octocrab::models::IssueState::Closed)
+            .send()
+            .await?;
+        closed.push(pr.html_url.to_string());
+    }
+    Ok(closed)
 }
 
 pub async fn submit_issue_or_pull_request_comment(
@@ -91,46 +515,41 @@ pub async fn submit_issue_or_pull_request_comment(
     base_url: Option<String>,
     owner: String,
     repo: String,
-    token_env_var: Option<String>,
+    token: String,
     title: String,
     body: String,
 ) -> Result<(), PullRequestError> {
-    let crab = octocrab::OctocrabBuilder::new()
-        .base_url(base_url.unwrap_or_else(|| GITHUB_BASE_URL.to_string()))?
-        .personal_token(std::env::var(
-            token_env_var.unwrap_or_else(|| "GITHUB_TOKEN".to_string()),
-        )?)
-        .build()?;
-
-    let query = format!(
-        "head:{} base:{} is:pr state:open repo:{}/{}",
-        settings.update_branch, settings.default_branch, owner, repo
-    );
-
-    let mut page = crab
-        .search()
-        .issues_and_pull_requests(query.as_str())
-        .send()
-        .await?;
+    let crab = client(base_url, token)?;
 
     // If there is a PR already, comment on it
-    if let Some(pr) = page.items.pop() {
+    if let Some(pr) = find_update_issue(&crab, &settings, &owner, &repo).await? {
         crab.issues(owner, repo)
             .create_comment(pr.number, body)
             .await?;
     } else {
         let me = crab.current().user().await?.login;
 
-        // FIXME: technically this might match unrelated issues if the user is not uniquely used by this bot
-        let query = format!("state:open is:issue author:{} repo:{}/{}", me, owner, repo);
+        let query = format!(
+            "state:open is:issue author:{} repo:{}/{} \"{}\" in:body",
+            me, owner, repo, ERROR_REPORT_SEARCH
+        );
 
-        let mut page = crab
+        let page = crab
             .search()
             .issues_and_pull_requests(query.as_str())
+            .per_page(100)
             .send()
             .await?;
+        let issues = crab.all_pages(page).await?;
+        let mut reports = issues
+            .into_iter()
+            .filter(|issue| is_error_report(&issue.title, issue.body.as_deref()));
+        let report = reports.next();
+        if reports.next().is_some() {
+            warn!("More than one error report issue; picking the first one");
+        }
 
-        if let Some(issue) = page.items.pop() {
+        if let Some(issue) = report {
             crab.issues(owner, repo)
                 .create_comment(issue.number, body)
                 .await?;
@@ -145,3 +564,243 @@ pub async fn submit_issue_or_pull_request_comment(
 
     Ok(())
 }
+
+/// Run a GraphQL query, returning its data
+async fn graphql<T: serde::de::DeserializeOwned>(
+    crab: &octocrab::Octocrab,
+    query: &str,
+    variables: serde_json::Value,
+) -> Result<T, PullRequestError> {
+    let mut response: serde_json::Value = crab
+        .post(
+            "graphql",
+            Some(&json!({ "query": query, "variables": variables })),
+        )
+        .await?;
+    if let Some(errors) = response.get("errors") {
+        return Err(PullRequestError::GraphqlError(errors.to_string()));
+    }
+    Ok(serde_json::from_value(response["data"].take())?)
+}
+
+#[derive(Debug, Deserialize)]
+struct Nodes<T> {
+    nodes: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscussionRepository {
+    id: String,
+    discussion_categories: Nodes<DiscussionCategory>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscussionCategory {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CategoryDiscussions {
+    discussions: Nodes<Discussion>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Discussion {
+    id: String,
+    url: String,
+    title: String,
+    body: String,
+    closed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository<T> {
+    repository: T,
+}
+
+/// The open update discussion (the one titled `title` in the category), if there is one,
+/// with what is needed to start it otherwise
+struct DiscussionTarget {
+    repository_id: String,
+    category_id: String,
+    discussion: Option<Discussion>,
+}
+
+async fn find_discussion(
+    crab: &octocrab::Octocrab,
+    settings: &UpdateSettings,
+    owner: &str,
+    repo: &str,
+    category: &str,
+) -> Result<DiscussionTarget, PullRequestError> {
+    let repository: Repository<DiscussionRepository> = graphql(
+        crab,
+        "query($owner: String!, $repo: String!) {
+            repository(owner: $owner, name: $repo) {
+                id
+                discussionCategories(first: 100) { nodes { id name } }
+            }
+        }",
+        json!({ "owner": owner, "repo": repo }),
+    )
+    .await?;
+    let repository = repository.repository;
+    let category_id = repository
+        .discussion_categories
+        .nodes
+        .into_iter()
+        .find(|c| c.name.eq_ignore_ascii_case(category))
+        .ok_or_else(|| PullRequestError::NoDiscussionCategory(category.to_string()))?
+        .id;
+
+    let discussions: Repository<CategoryDiscussions> = graphql(
+        crab,
+        "query($owner: String!, $repo: String!, $category: ID!) {
+            repository(owner: $owner, name: $repo) {
+                discussions(
+                    first: 100,
+                    categoryId: $category,
+                    orderBy: { field: CREATED_AT, direction: DESC }
+                ) {
+                    nodes { id url title body closed }
+                }
+            }
+        }",
+        json!({ "owner": owner, "repo": repo, "category": category_id }),
+    )
+    .await?;
+    let discussion = discussions
+        .repository
+        .discussions
+        .nodes
+        .into_iter()
+        .find(|d| !d.closed && d.title == settings.title);
+
+    Ok(DiscussionTarget {
+        repository_id: repository.id,
+        category_id,
+        discussion,
+    })
+}
+
+/// Update the open update discussion in `category`, or start one if `submit` is passed
+async fn submit_or_update_discussion(
+    crab: &octocrab::Octocrab,
+    settings: &UpdateSettings,
+    owner: &str,
+    repo: &str,
+    category: &str,
+    body: String,
+    submit: bool,
+) -> Result<Option<String>, PullRequestError> {
+    let target = find_discussion(crab, settings, owner, repo, category).await?;
+
+    if let Some(discussion) = target.discussion {
+        let _: serde_json::Value = graphql(
+            crab,
+            "mutation($id: ID!, $body: String!) {
+                updateDiscussion(input: { discussionId: $id, body: $body }) {
+                    discussion { id }
+                }
+            }",
+            json!({ "id": discussion.id, "body": body }),
+        )
+        .await?;
+        info!("Updated discussion {}", discussion.url);
+        Ok(Some(discussion.url))
+    } else if submit {
+        let created: serde_json::Value = graphql(
+            crab,
+            "mutation($repository: ID!, $category: ID!, $title: String!, $body: String!) {
+                createDiscussion(input: {
+                    repositoryId: $repository,
+                    categoryId: $category,
+                    title: $title,
+                    body: $body
+                }) {
+                    discussion { url }
+                }
+            }",
+            json!({
+                "repository": target.repository_id,
+                "category": target.category_id,
+                "title": settings.title,
+                "body": body,
+            }),
+        )
+        .await?;
+        let url = created["createDiscussion"]["discussion"]["url"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        info!("Started discussion {}", url);
+        Ok(Some(url))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Comparison {
+    /// How the head compares to the base: `ahead`, `behind`, `identical` or `diverged`
+    status: String,
+}
+
+async fn compare(
+    crab: &octocrab::Octocrab,
+    owner: &str,
+    repo: &str,
+    base: &str,
+    head: &str,
+) -> Result<String, PullRequestError> {
+    let comparison: Comparison = crab
+        .get(
+            format!("repos/{}/{}/compare/{}...{}", owner, repo, base, head),
+            None::<&()>,
+        )
+        .await?;
+    Ok(comparison.status)
+}
+
+/// Whether the latest release of `owner/repo` was published after `old_rev`
+/// and `new_rev` includes it, or `None` if the repository has no releases
+pub async fn includes_new_release(
+    token: String,
+    owner: String,
+    repo: String,
+    old_rev: String,
+    new_rev: String,
+) -> Result<Option<bool>, PullRequestError> {
+    let crab = client(None, token)?;
+    let release: Release = match crab
+        .get(
+            format!("repos/{}/{}/releases/latest", owner, repo),
+            None::<&()>,
+        )
+        .await
+    {
+        Ok(release) => release,
+        Err(octocrab::Error::GitHub { ref source, .. }) if source.message == "Not Found" => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let tag = release.tag_name;
+
+    Ok(Some(
+        compare(&crab, &owner, &repo, &old_rev, &tag).await? == "ahead"
+            && matches!(
+                compare(&crab, &owner, &repo, &tag, &new_rev)
+                    .await?
+                    .as_str(),
+                "ahead" | "identical"
+            ),
+    ))
+}
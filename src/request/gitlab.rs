@@ -3,6 +3,10 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use super::super::types::UpdateSettings;
+use super::{
+    draft_title, is_error_report, requests_to_update, select_request, updated_title,
+    AmbiguousRequests, ExistingRequest, RequestHead, ERROR_REPORT_SEARCH,
+};
 use thiserror::Error;
 
 use log::*;
@@ -21,100 +25,308 @@ pub enum MergeRequestError {
     ),
     #[error("Couldn't create the endpoint: {0}")]
     GitlabEndpointError(String),
-    #[error("Couldn't get a gitlab token from env var: {0}")]
-    TokenError(#[from] std::env::VarError),
+    #[error("{0}")]
+    Ambiguous(#[from] AmbiguousRequests),
 }
 
-pub async fn submit_or_update_merge_request(
-    settings: UpdateSettings,
+async fn client(
+    base_url: Option<String>,
+    token: String,
+) -> Result<gitlab::AsyncGitlab, MergeRequestError> {
+    Ok(
+        gitlab::Gitlab::builder(base_url.unwrap_or_else(|| "gitlab.com".to_string()), token)
+            .build_async()
+            .await?,
+    )
+}
+
+/// Username of the user the token belongs to
+pub async fn current_user(
+    base_url: Option<String>,
+    token: String,
+) -> Result<String, MergeRequestError> {
+    let gitlab = client(base_url, token).await?;
+    let me_query = users::CurrentUser::builder()
+        .build()
+        .map_err(|_| MergeRequestError::GitlabEndpointError("building current user".to_string()))?;
+    let me: gitlab::types::User = me_query.query_async(&gitlab).await?;
+    Ok(me.username)
+}
+
+/// Endpoint listing the open merge requests that may be the update one
+fn merge_request_search<'a>(
+    settings: &'a UpdateSettings,
+    project: &str,
+) -> Result<MergeRequests<'a>, MergeRequestError> {
+    let mut mr_search = MergeRequests::builder();
+    mr_search
+        .project(project.to_string())
+        .state(MergeRequestState::Opened);
+    if !settings.search_any_base {
+        mr_search.target_branch(&settings.default_branch);
+    }
+    // Source branches can only be matched by prefix after listing
+    if !settings.search_head_prefix {
+        mr_search.source_branch(&settings.update_branch);
+    }
+    mr_search
+        .build()
+        .map_err(|_| MergeRequestError::GitlabEndpointError("building merge request".to_string()))
+}
+
+/// Find the update merge requests: the managed one if `managed_pr_number` is set,
+/// otherwise the open ones from `update_branch`
+async fn find_update_merge_requests(
+    gitlab: &gitlab::AsyncGitlab,
+    settings: &UpdateSettings,
+    project: &str,
+) -> Result<Vec<gitlab::types::MergeRequest>, MergeRequestError> {
+    if let Some(iid) = settings.managed_pr_number {
+        let mr_get = MergeRequest::builder()
+            .project(project.to_string())
+            .merge_request(iid)
+            .build()
+            .map_err(|_| {
+                MergeRequestError::GitlabEndpointError("getting merge request".to_string())
+            })?;
+        return Ok(vec![mr_get.query_async(gitlab).await?]);
+    }
+
+    let mr_search = merge_request_search(settings, project)?;
+
+    let mrs: Vec<gitlab::types::MergeRequest> = paged(mr_search, Pagination::All)
+        .query_async(gitlab)
+        .await?;
+
+    Ok(mrs
+        .into_iter()
+        .filter(|mr| mr.source_branch.starts_with(&settings.update_branch))
+        .collect())
+}
+
+/// Find the update merge request, if there is one
+async fn find_update_merge_request(
+    gitlab: &gitlab::AsyncGitlab,
+    settings: &UpdateSettings,
+    project: &str,
+) -> Result<Option<gitlab::types::MergeRequest>, MergeRequestError> {
+    let mrs = find_update_merge_requests(gitlab, settings, project).await?;
+    Ok(select_request(settings, mrs)?)
+}
+
+/// Find the update merge request, if there is one
+pub async fn find_merge_request(
+    settings: &UpdateSettings,
+    base_url: Option<String>,
+    project: String,
+    token: String,
+) -> Result<Option<ExistingRequest>, MergeRequestError> {
+    let gitlab = client(base_url, token).await?;
+
+    let mr = find_update_merge_request(&gitlab, settings, &project).await?;
+
+    Ok(mr.map(|mr| ExistingRequest {
+        url: mr.web_url,
+        body: mr.description,
+    }))
+}
+
+/// Find the update merge request and its source branch. In `comment_only` mode, this is
+/// the open one with `comment_label` if it is set.
+pub async fn find_request_head(
+    settings: &UpdateSettings,
+    base_url: Option<String>,
+    project: String,
+    token: String,
+) -> Result<Option<RequestHead>, MergeRequestError> {
+    let gitlab = client(base_url, token).await?;
+
+    let mr = match (&settings.comment_label, settings.comment_only) {
+        (Some(label), true) => {
+            let mr_search = MergeRequests::builder()
+                .project(project)
+                .state(MergeRequestState::Opened)
+                .target_branch(&settings.default_branch)
+                .label(label.as_str())
+                .build()
+                .map_err(|_| {
+                    MergeRequestError::GitlabEndpointError("building merge request".to_string())
+                })?;
+
+            let mut mr_page: Vec<gitlab::types::MergeRequest> =
+                mr_search.query_async(&gitlab).await?;
+
+            mr_page.pop()
+        }
+        _ => find_update_merge_request(&gitlab, settings, &project).await?,
+    };
+
+    Ok(mr.map(|mr| RequestHead {
+        number: mr.iid.value(),
+        branch: mr.source_branch,
+        url: mr.web_url,
+    }))
+}
+
+pub async fn comment_on_merge_request(
     base_url: Option<String>,
     project: String,
-    token_env_var: Option<String>,
+    token: String,
+    number: u64,
     body: String,
-    submit: bool,
 ) -> Result<(), MergeRequestError> {
-    let gitlab = gitlab::Gitlab::builder(
-        base_url.unwrap_or_else(|| "gitlab.com".to_string()),
-        std::env::var(token_env_var.unwrap_or_else(|| "GITLAB_TOKEN".to_string()))?,
-    )
-    .build_async()
-    .await?;
-
-    let mr_search = MergeRequests::builder()
-        .project(project.clone())
-        .state(MergeRequestState::Opened)
-        .target_branch(&settings.default_branch)
-        .source_branch(&settings.update_branch)
+    let gitlab = client(base_url, token).await?;
+
+    let mr_note_create = notes::CreateMergeRequestNote::builder()
+        .project(project)
+        .merge_request(number)
+        .body(body)
         .build()
         .map_err(|_| {
-            MergeRequestError::GitlabEndpointError("building merge request".to_string())
+            MergeRequestError::GitlabEndpointError("building merge request note".to_string())
         })?;
 
-    let mut mr_page: Vec<gitlab::types::MergeRequest> = mr_search.query_async(&gitlab).await?;
+    let _: gitlab::types::Note = mr_note_create.query_async(&gitlab).await?;
+    Ok(())
+}
 
-    if let Some(mr) = mr_page.pop() {
-        let mr_edit = EditMergeRequest::builder()
-            .project(mr.project_id.value())
-            .merge_request(mr.iid.value())
-            .title(settings.title)
-            .description(body)
+/// IDs of the users named `usernames`, warning about the ones that don't exist
+async fn user_ids(
+    gitlab: &gitlab::AsyncGitlab,
+    usernames: &[String],
+) -> Result<Vec<u64>, MergeRequestError> {
+    let mut ids = Vec::new();
+    for username in usernames {
+        let users_query = users::Users::builder()
+            .username(username.as_str())
             .build()
-            .map_err(|_| {
+            .map_err(|_| MergeRequestError::GitlabEndpointError("building users".to_string()))?;
+        let users: Vec<gitlab::types::UserBasic> = users_query.query_async(gitlab).await?;
+        match users.first() {
+            Some(user) => ids.push(user.id.value()),
+            None => warn!("There is no GitLab user {}, leaving them out", username),
+        }
+    }
+    Ok(ids)
+}
+
+pub async fn submit_or_update_merge_request(
+    settings: UpdateSettings,
+    base_url: Option<String>,
+    project: String,
+    token: String,
+    body: String,
+    submit: bool,
+) -> Result<Option<String>, MergeRequestError> {
+    let gitlab = client(base_url, token).await?;
+
+    let mrs = requests_to_update(
+        &settings,
+        find_update_merge_requests(&gitlab, &settings, &project).await?,
+    )?;
+    if !mrs.is_empty() {
+        let mut url = None;
+        for mr in mrs {
+            let mut mr_edit = EditMergeRequest::builder();
+            mr_edit
+                .project(mr.project_id.value())
+                .merge_request(mr.iid.value())
+                .title(updated_title(&settings, &mr.title, "Draft:"))
+                .description(body.as_str());
+            // GitLab creates the labels missing in the project
+            for label in &settings.labels {
+                mr_edit.add_label(label.as_str());
+            }
+            let mr_edit = mr_edit.build().map_err(|_| {
                 MergeRequestError::GitlabEndpointError("building merge request".to_string())
             })?;
 
-        let mr: gitlab::types::MergeRequest = mr_edit.query_async(&gitlab).await?;
+            let mr: gitlab::types::MergeRequest = mr_edit.query_async(&gitlab).await?;
 
-        info!("Updated MR {}", mr.web_url);
+            info!("Updated MR {}", mr.web_url);
+            url = Some(mr.web_url);
+        }
+        Ok(url)
     } else if submit {
-        let mr_create = CreateMergeRequest::builder()
+        let assignees = user_ids(&gitlab, &settings.assignees).await?;
+        let reviewers = user_ids(&gitlab, &settings.reviewers).await?;
+
+        let title = if settings.draft {
+            draft_title(&settings.title, "Draft:")
+        } else {
+            settings.title
+        };
+
+        let mut mr_create = CreateMergeRequest::builder();
+        mr_create
             .project(project)
             .target_branch(&settings.default_branch)
             .source_branch(&settings.update_branch)
-            .title(settings.title)
+            .title(title)
             .description(body)
-            .build()
-            .map_err(|_| {
-                MergeRequestError::GitlabEndpointError("creating merge request".to_string())
-            })?;
+            .labels(settings.labels.iter().map(String::as_str));
+        if !assignees.is_empty() {
+            mr_create.assignees(assignees.into_iter());
+        }
+        if !reviewers.is_empty() {
+            mr_create.reviewers(reviewers.into_iter());
+        }
+        let mr_create = mr_create.build().map_err(|_| {
+            MergeRequestError::GitlabEndpointError("creating merge request".to_string())
+        })?;
 
         let mr: gitlab::types::MergeRequest = mr_create.query_async(&gitlab).await?;
 
         info!("Created MR {}", mr.web_url);
+        Ok(Some(mr.web_url))
+    } else {
+        Ok(None)
     }
+}
 
-    Ok(())
+/// Close the update merge requests, returning their URLs
+pub async fn close_merge_requests(
+    settings: &UpdateSettings,
+    base_url: Option<String>,
+    project: String,
+    token: String,
+) -> Result<Vec<String>, MergeRequestError> {
+    let gitlab = client(base_url, token).await?;
+
+    let mrs = requests_to_update(
+        settings,
+        find_update_merge_requests(&gitlab, settings, &project).await?,
+    )?;
+    let mut closed = Vec::new();
+    for mr in mrs {
+        let mr_close = EditMergeRequest::builder()
+            .project(mr.project_id.value())
+            .merge_request(mr.iid.value())
+            .state_event(MergeRequestStateEvent::Close)
+            .build()
+            .map_err(|_| {
+                MergeRequestError::GitlabEndpointError("closing merge request".to_string())
+            })?;
+
+        let mr: gitlab::types::MergeRequest = mr_close.query_async(&gitlab).await?;
+
+        closed.push(mr.web_url);
+    }
+    Ok(closed)
 }
 
 pub async fn submit_issue_or_merge_request_comment(
     settings: UpdateSettings,
     base_url: Option<String>,
     project: String,
-    token_env_var: Option<String>,
+    token: String,
     title: String,
     body: String,
 ) -> Result<(), MergeRequestError> {
-    let gitlab = gitlab::Gitlab::builder(
-        base_url.unwrap_or_else(|| "gitlab.com".to_string()),
-        std::env::var(token_env_var.unwrap_or_else(|| "GITLAB_TOKEN".to_string()))?,
-    )
-    .build_async()
-    .await?;
-
-    let mr_search = MergeRequests::builder()
-        .project(project.clone())
-        .state(MergeRequestState::Opened)
-        .target_branch(&settings.default_branch)
-        .source_branch(&settings.update_branch)
-        .build()
-        .map_err(|_| {
-            MergeRequestError::GitlabEndpointError("building merge request".to_string())
-        })?;
-
-    let mut mr_page: Vec<gitlab::types::MergeRequest> = mr_search.query_async(&gitlab).await?;
+    let gitlab = client(base_url, token).await?;
 
     // If there is a MR already, comment on it
-    if let Some(mr) = mr_page.pop() {
+    if let Some(mr) = find_update_merge_request(&gitlab, &settings, &project).await? {
         let mr_note_create = notes::CreateMergeRequestNote::builder()
             .project(mr.project_id.value())
             .merge_request(mr.iid.value())
@@ -134,22 +346,27 @@ pub async fn submit_issue_or_merge_request_comment(
 
         let me: gitlab::types::User = me_query.query_async(&gitlab).await?;
 
-        // FIXME: technically this might match unrelated issues if the user is not uniquely used by this bot
-
         let issue_search = projects::issues::Issues::builder()
             .project(project.clone())
             .state(projects::issues::IssueState::Opened)
             .author(me.id.value())
+            .search(ERROR_REPORT_SEARCH)
+            .search_in(projects::issues::IssueSearchScope::Description)
             .build()
             .map_err(|_| MergeRequestError::GitlabEndpointError("building issue".to_string()))?;
 
-        let mut issues: Vec<gitlab::types::Issue> = issue_search.query_async(&gitlab).await?;
-
-        if issues.len() > 1 {
-            warn!("More than one issue; picking the first one and hoping for the best");
+        let issues: Vec<gitlab::types::Issue> = paged(issue_search, Pagination::All)
+            .query_async(&gitlab)
+            .await?;
+        let mut reports = issues
+            .into_iter()
+            .filter(|issue| is_error_report(&issue.title, issue.description.as_deref()));
+        let report = reports.next();
+        if reports.next().is_some() {
+            warn!("More than one error report issue; picking the first one");
         }
 
-        if let Some(issue) = issues.pop() {
+        if let Some(issue) = report {
             let issue_note_create = projects::issues::notes::CreateIssueNote::builder()
                 .project(issue.project_id.value())
                 .issue(issue.iid.value())
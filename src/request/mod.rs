@@ -2,66 +2,404 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use super::flake_lock::LockDiff;
 use super::types::*;
 use log::warn;
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
+mod discord;
+mod gitea;
 mod github;
 mod gitlab;
+mod retry;
+mod webhook;
+
+#[cfg(test)]
+mod tests;
 
 const ERROR_REPORT_TITLE: &str = "Failed to automatically update flake.lock";
 
+/// Marks the error reports, hidden in the issue body
+const ERROR_REPORT_MARKER: &str = "<!-- update-daemon error report -->";
+
+/// The words of `ERROR_REPORT_MARKER`, to search for the error reports with
+const ERROR_REPORT_SEARCH: &str = "update-daemon error report";
+
+/// Whether the issue with `title` and `body` is an error report: one with the marker,
+/// or one with the title of the error reports, as filed before the marker was added
+fn is_error_report(title: &str, body: Option<&str>) -> bool {
+    body.is_some_and(|body| body.contains(ERROR_REPORT_MARKER)) || title == ERROR_REPORT_TITLE
+}
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("Couldn't read the token file {0:?}: {1}")]
+    File(PathBuf, std::io::Error),
+    #[error("Couldn't get the token from env var {0}: {1}")]
+    EnvVar(String, std::env::VarError),
+    #[error("Couldn't read the token file {0:?} ({1}), nor get the token from env var {2} ({3})")]
+    FileAndEnvVar(PathBuf, std::io::Error, String, std::env::VarError),
+}
+
+/// The API token, read from `token_file` if it is set, falling back to `token_env_var`
+/// (`default_env_var` if neither is set)
+fn token(
+    token_env_var: Option<String>,
+    token_file: Option<PathBuf>,
+    default_env_var: &str,
+) -> Result<String, TokenError> {
+    match (token_file, token_env_var) {
+        (Some(file), env_var) => match std::fs::read_to_string(&file) {
+            Ok(token) => Ok(token.trim_end().to_string()),
+            Err(file_e) => match env_var {
+                Some(var) => std::env::var(&var)
+                    .map_err(|var_e| TokenError::FileAndEnvVar(file, file_e, var, var_e)),
+                None => Err(TokenError::File(file, file_e)),
+            },
+        },
+        (None, env_var) => {
+            let var = env_var.unwrap_or_else(|| default_env_var.to_string());
+            std::env::var(&var).map_err(|e| TokenError::EnvVar(var, e))
+        }
+    }
+}
+
+fn github_token(
+    token_env_var: Option<String>,
+    token_file: Option<PathBuf>,
+) -> Result<String, TokenError> {
+    token(token_env_var, token_file, "GITHUB_TOKEN")
+}
+
+fn gitlab_token(
+    token_env_var: Option<String>,
+    token_file: Option<PathBuf>,
+) -> Result<String, TokenError> {
+    token(token_env_var, token_file, "GITLAB_TOKEN")
+}
+
+fn gitea_token(
+    token_env_var: Option<String>,
+    token_file: Option<PathBuf>,
+) -> Result<String, TokenError> {
+    token(token_env_var, token_file, "GITEA_TOKEN")
+}
+
+/// The API token of `handle`, if it is on a forge
+fn api_token(handle: &RepoHandle) -> Result<Option<String>, TokenError> {
+    match handle.clone() {
+        RepoHandle::GitHub {
+            token_env_var,
+            token_file,
+            ..
+        } => github_token(token_env_var, token_file).map(Some),
+        RepoHandle::GitLab {
+            token_env_var,
+            token_file,
+            ..
+        } => gitlab_token(token_env_var, token_file).map(Some),
+        RepoHandle::Gitea {
+            token_env_var,
+            token_file,
+            ..
+        } => gitea_token(token_env_var, token_file).map(Some),
+        RepoHandle::GitNone { .. } => Ok(None),
+    }
+}
+
+/// Check that the API token of `handle` can be read, so that a missing one
+/// is reported before any work is done
+pub fn check_token(handle: &RepoHandle) -> Result<(), TokenError> {
+    api_token(handle).map(drop)
+}
+
+/// The GitHub token to check the releases of `release_inputs` with: the token of `handle`
+/// if it is on github.com, `GITHUB_TOKEN` otherwise. The token of a GitHub Enterprise
+/// repository isn't sent to github.com.
+fn release_token(handle: &RepoHandle) -> Result<String, TokenError> {
+    match handle.clone() {
+        RepoHandle::GitHub {
+            base_url: None,
+            token_env_var,
+            token_file,
+            ..
+        } => github_token(token_env_var, token_file),
+        _ => github_token(None, None),
+    }
+}
+
+/// Check that the token to check the releases of `release_inputs` with can be read
+pub fn check_release_token(handle: &RepoHandle) -> Result<(), TokenError> {
+    release_token(handle).map(drop)
+}
+
+/// Credentials for git over HTTP(S): the API token, if git uses HTTP(S) to reach a forge
+pub fn git_credentials(
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+) -> Result<Option<GitCredentials>, TokenError> {
+    let url = handle.git_url(settings.git_auth);
+    if settings.git_auth == Some(GitAuth::Ssh)
+        || !(url.starts_with("https://") || url.starts_with("http://"))
+    {
+        return Ok(None);
+    }
+    Ok(api_token(handle)?.map(|token| GitCredentials {
+        username: handle.https_username().to_string(),
+        password: token,
+    }))
+}
+
+/// The user the API token of `handle` authenticates as, if it is on a forge
+pub async fn authenticated_user(handle: &RepoHandle) -> Result<Option<String>, RequestError> {
+    Ok(match handle.clone() {
+        RepoHandle::GitHub {
+            base_url,
+            token_env_var,
+            token_file,
+            ..
+        } => Some(github::current_user(base_url, github_token(token_env_var, token_file)?).await?),
+        RepoHandle::GitLab {
+            base_url,
+            token_env_var,
+            token_file,
+            ..
+        } => Some(gitlab::current_user(base_url, gitlab_token(token_env_var, token_file)?).await?),
+        RepoHandle::Gitea {
+            base_url,
+            token_env_var,
+            token_file,
+            ..
+        } => Some(gitea::current_user(base_url, gitea_token(token_env_var, token_file)?).await?),
+        RepoHandle::GitNone { .. } => None,
+    })
+}
+
 #[derive(Debug, Error)]
 pub enum RequestError {
     #[error("An error during github operation: {0}")]
     GithubError(#[from] github::PullRequestError),
     #[error("An error during gitlab operation: {0}")]
     GitlabError(#[from] gitlab::MergeRequestError),
+    #[error("An error during gitea operation: {0}")]
+    GiteaError(#[from] gitea::PullRequestError),
+    #[error("Couldn't get the API token: {0}")]
+    Token(#[from] TokenError),
+    #[error("{0}")]
+    HostNotAllowed(#[from] HostNotAllowed),
 }
 
+/// Estimate of the API requests made to update a repository
+const REQUESTS_PER_UPDATE: usize = 10;
+
+/// What is left of the API rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateBudget {
+    pub remaining: usize,
+    /// When the limit is reset, in seconds since the epoch
+    pub reset: u64,
+}
+
+impl RateBudget {
+    /// The time to leave between submissions to update `repos` repositories at `now`
+    /// (in seconds since the epoch) without running out of the budget, if it is low:
+    /// the time until the reset, spread over the repositories
+    pub fn cooldown(&self, repos: usize, now: u64) -> Option<Duration> {
+        if repos == 0 || self.remaining >= repos * REQUESTS_PER_UPDATE {
+            return None;
+        }
+        Some(Duration::from_secs(self.reset.saturating_sub(now)) / repos as u32)
+    }
+}
+
+/// The rate limit budget of the API token of `handle`, if it is on GitHub
+pub async fn github_rate_budget(handle: &RepoHandle) -> Result<Option<RateBudget>, RequestError> {
+    match handle.clone() {
+        RepoHandle::GitHub {
+            base_url,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(Some(
+            github::rate_budget(base_url, github_token(token_env_var, token_file)?).await?,
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// A repository of a GitHub organization
+#[derive(Debug, Clone)]
+pub struct OrgRepository {
+    pub name: String,
+    pub archived: bool,
+    pub fork: bool,
+}
+
+/// The repositories of the GitHub organization `owner`
+pub async fn github_org_repositories(
+    base_url: Option<String>,
+    owner: &str,
+    token_env_var: Option<String>,
+    token_file: Option<PathBuf>,
+) -> Result<Vec<OrgRepository>, RequestError> {
+    Ok(github::org_repositories(
+        base_url,
+        owner.to_string(),
+        github_token(token_env_var, token_file)?,
+    )
+    .await?)
+}
+
+/// Whether `new_rev` of the GitHub repository `owner/repo` includes a release published
+/// after `old_rev`, or `None` if it has no releases. Uses the token of `handle` if it is
+/// on github.com, `GITHUB_TOKEN` otherwise.
+pub async fn includes_new_release(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+    owner: &str,
+    repo: &str,
+    old_rev: &str,
+    new_rev: &str,
+) -> Result<Option<bool>, RequestError> {
+    state.check_host(url_host(github::GITHUB_BASE_URL))?;
+    let token = release_token(handle)?;
+    Ok(github::retry_rate_limit(settings, || {
+        github::includes_new_release(
+            token.clone(),
+            owner.to_string(),
+            repo.to_string(),
+            old_rev.to_string(),
+            new_rev.to_string(),
+        )
+    })
+    .await?)
+}
+
+/// Describe the API calls made by `submit_or_update_request`, for `--explain-commands`
+pub fn describe_submission(settings: &UpdateSettings, handle: &RepoHandle) -> String {
+    match handle {
+        RepoHandle::GitHub { owner, repo, .. } => match &settings.discussion_category {
+            Some(category) => format!(
+                "GitHub API: update the open discussion \"{}\" in the {} category of {}/{}, or start one",
+                settings.title, category, owner, repo
+            ),
+            None => format!(
+                "GitHub API: update the open pull request from {} to {} in {}/{}, or create one",
+                settings.update_branch, settings.default_branch, owner, repo
+            ),
+        },
+        RepoHandle::GitLab { project, .. } => format!(
+            "GitLab API: update the open merge request from {} to {} in {}, or create one",
+            settings.update_branch, settings.default_branch, project
+        ),
+        RepoHandle::Gitea { owner, repo, .. } => format!(
+            "Gitea API: update the open pull request from {} to {} in {}/{}, or create one",
+            settings.update_branch, settings.default_branch, owner, repo
+        ),
+        RepoHandle::GitNone { .. } => "No pull request is submitted for git+none".to_string(),
+    }
+}
+
+/// `title` marked as a draft with `prefix`, as GitLab and Gitea do, unless it already is
+fn draft_title(title: &str, prefix: &str) -> String {
+    if title.starts_with(prefix) {
+        title.to_string()
+    } else {
+        format!("{} {}", prefix, title)
+    }
+}
+
+/// Title to update a GitLab or Gitea "pull request" titled `current` with. With the `draft`
+/// setting, a draft stays one, while one marked as ready isn't made a draft again.
+fn updated_title(settings: &UpdateSettings, current: &str, prefix: &str) -> String {
+    if settings.draft && current.starts_with(prefix) {
+        draft_title(&settings.title, prefix)
+    } else {
+        settings.title.clone()
+    }
+}
+
+/// Submit or update the "pull request", returning its URL. With `draft`, new GitHub pull
+/// requests are drafts, and GitLab and Gitea ones are marked as drafts by their title.
+/// With the `draft` setting, only new ones are.
 pub async fn submit_or_update_request(
-    settings: UpdateSettings,
+    mut settings: UpdateSettings,
     handle: RepoHandle,
     diff: String,
     submit: bool,
-) -> Result<(), RequestError> {
+    draft: bool,
+) -> Result<Option<String>, RequestError> {
+    if draft {
+        match handle {
+            RepoHandle::GitLab { .. } => settings.title = draft_title(&settings.title, "Draft:"),
+            RepoHandle::Gitea { .. } => settings.title = draft_title(&settings.title, "WIP:"),
+            _ => {}
+        }
+    }
     match handle {
         RepoHandle::GitHub {
             base_url,
             owner,
             repo,
             token_env_var,
+            token_file,
             ..
         } => {
-            let res = github::submit_or_update_pull_request(
-                settings,
-                base_url,
-                owner,
-                repo,
-                token_env_var,
-                diff,
-                submit,
-            )
+            let token = github_token(token_env_var, token_file)?;
+            let res = github::retry_rate_limit(&settings, || {
+                github::submit_or_update_pull_request(
+                    settings.clone(),
+                    base_url.clone(),
+                    owner.clone(),
+                    repo.clone(),
+                    token.clone(),
+                    diff.clone(),
+                    submit,
+                    draft,
+                )
+            })
             .await;
             match res {
                 Err(e @ github::PullRequestError::ReadOnlyRepo) => {
                     warn!("{}", e);
-                    Ok(())
+                    Ok(None)
                 }
                 Err(e) => Err(e.into()),
-                Ok(_) => Ok(()),
+                Ok(url) => Ok(url),
             }
         }
         RepoHandle::GitLab {
             base_url,
             project,
             token_env_var,
+            token_file,
             ..
         } => gitlab::submit_or_update_merge_request(
             settings,
             base_url,
             project,
+            gitlab_token(token_env_var, token_file)?,
+            diff,
+            submit,
+        )
+        .await
+        .map_err(|e| e.into()),
+        RepoHandle::Gitea {
+            base_url,
+            owner,
+            repo,
             token_env_var,
+            token_file,
+            ..
+        } => gitea::submit_or_update_pull_request(
+            settings,
+            base_url,
+            owner,
+            repo,
+            gitea_token(token_env_var, token_file)?,
             diff,
             submit,
         )
@@ -69,6 +407,285 @@ pub async fn submit_or_update_request(
         .map_err(|e| e.into()),
         RepoHandle::GitNone { url } => {
             warn!("Not sending a pull request for {}", url);
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{0} open pull requests match the update one, and multiple_requests is error")]
+pub struct AmbiguousRequests(usize);
+
+/// The update "pull request" among the `found` ones: the last one, unless there are
+/// several and `multiple_requests` is `error`
+fn select_request<T>(
+    settings: &UpdateSettings,
+    mut found: Vec<T>,
+) -> Result<Option<T>, AmbiguousRequests> {
+    if found.len() > 1 && settings.multiple_requests == MultipleRequests::Error {
+        return Err(AmbiguousRequests(found.len()));
+    }
+    Ok(found.pop())
+}
+
+/// The update "pull requests" among the `found` ones that get updated:
+/// all of them with `update_all`, otherwise the one of `select_request`
+fn requests_to_update<T>(
+    settings: &UpdateSettings,
+    found: Vec<T>,
+) -> Result<Vec<T>, AmbiguousRequests> {
+    match settings.multiple_requests {
+        MultipleRequests::UpdateAll => Ok(found),
+        _ => Ok(select_request(settings, found)?.into_iter().collect()),
+    }
+}
+
+/// The open update "pull request"
+#[derive(Debug, Clone)]
+pub struct ExistingRequest {
+    pub url: String,
+    pub body: Option<String>,
+}
+
+/// Find the open update "pull request", if there is one
+pub async fn find_request(
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+) -> Result<Option<ExistingRequest>, RequestError> {
+    match handle.clone() {
+        RepoHandle::GitHub {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(github::find_pull_request(
+            settings,
+            base_url,
+            owner,
+            repo,
+            github_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::GitLab {
+            base_url,
+            project,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitlab::find_merge_request(
+            settings,
+            base_url,
+            project,
+            gitlab_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::Gitea {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitea::find_pull_request(
+            settings,
+            base_url,
+            owner,
+            repo,
+            gitea_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::GitNone { .. } => Ok(None),
+    }
+}
+
+/// The update "pull request" and the branch it is submitted from
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub number: u64,
+    /// The branch it is submitted from
+    pub branch: String,
+    pub url: String,
+}
+
+/// Find the update "pull request" (in `comment_only` mode, the one to comment on)
+/// and the branch it is submitted from, if there is one
+pub async fn find_request_head(
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+) -> Result<Option<RequestHead>, RequestError> {
+    match handle.clone() {
+        RepoHandle::GitHub {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(github::find_request_head(
+            settings,
+            base_url,
+            owner,
+            repo,
+            github_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::GitLab {
+            base_url,
+            project,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitlab::find_request_head(
+            settings,
+            base_url,
+            project,
+            gitlab_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::Gitea {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitea::find_request_head(
+            settings,
+            base_url,
+            owner,
+            repo,
+            gitea_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::GitNone { url } => {
+            warn!("Not looking for a pull request for {}", url);
+            Ok(None)
+        }
+    }
+}
+
+/// Describe the API calls made by `close_requests`, for `--explain-commands`
+pub fn describe_closing(settings: &UpdateSettings, handle: &RepoHandle) -> String {
+    format!(
+        "API: close the open pull requests from {} on {}",
+        settings.update_branch, handle
+    )
+}
+
+/// Close the open update "pull requests", returning their URLs
+pub async fn close_requests(
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+) -> Result<Vec<String>, RequestError> {
+    match handle.clone() {
+        RepoHandle::GitHub {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(github::close_pull_requests(
+            settings,
+            base_url,
+            owner,
+            repo,
+            github_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::GitLab {
+            base_url,
+            project,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitlab::close_merge_requests(
+            settings,
+            base_url,
+            project,
+            gitlab_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::Gitea {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitea::close_pull_requests(
+            settings,
+            base_url,
+            owner,
+            repo,
+            gitea_token(token_env_var, token_file)?,
+        )
+        .await?),
+        RepoHandle::GitNone { .. } => Ok(Vec::new()),
+    }
+}
+
+/// Describe the API call made by `comment_on_request`, for `--explain-commands`
+pub fn describe_comment(target: &RequestHead) -> String {
+    format!("API: comment on {}", target.url)
+}
+
+pub async fn comment_on_request(
+    handle: RepoHandle,
+    target: &RequestHead,
+    body: String,
+) -> Result<(), RequestError> {
+    match handle {
+        RepoHandle::GitHub {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(github::comment_on_pull_request(
+            base_url,
+            owner,
+            repo,
+            github_token(token_env_var, token_file)?,
+            target.number,
+            body,
+        )
+        .await?),
+        RepoHandle::GitLab {
+            base_url,
+            project,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitlab::comment_on_merge_request(
+            base_url,
+            project,
+            gitlab_token(token_env_var, token_file)?,
+            target.number,
+            body,
+        )
+        .await?),
+        RepoHandle::Gitea {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => Ok(gitea::comment_on_pull_request(
+            base_url,
+            owner,
+            repo,
+            gitea_token(token_env_var, token_file)?,
+            target.number,
+            body,
+        )
+        .await?),
+        RepoHandle::GitNone { url } => {
+            warn!("Not commenting on a pull request for {}", url);
             Ok(())
         }
     }
@@ -80,6 +697,10 @@ pub enum ErrorReportError {
     GithubError(#[from] github::PullRequestError),
     #[error("An error during gitlab operation: {0}")]
     GitlabError(#[from] gitlab::MergeRequestError),
+    #[error("An error during gitea operation: {0}")]
+    GiteaError(#[from] gitea::PullRequestError),
+    #[error("Couldn't get the API token: {0}")]
+    Token(#[from] TokenError),
 }
 
 pub async fn submit_error_report(
@@ -87,23 +708,28 @@ pub async fn submit_error_report(
     handle: RepoHandle,
     report: String,
 ) -> Result<(), ErrorReportError> {
+    let report = format!("{}\n\n{}", report, ERROR_REPORT_MARKER);
     match handle {
         RepoHandle::GitHub {
             base_url,
             owner,
             repo,
             token_env_var,
+            token_file,
             ..
         } => {
-            let res = github::submit_issue_or_pull_request_comment(
-                settings,
-                base_url,
-                owner,
-                repo,
-                token_env_var,
-                ERROR_REPORT_TITLE.to_string(),
-                report,
-            )
+            let token = github_token(token_env_var, token_file)?;
+            let res = github::retry_rate_limit(&settings, || {
+                github::submit_issue_or_pull_request_comment(
+                    settings.clone(),
+                    base_url.clone(),
+                    owner.clone(),
+                    repo.clone(),
+                    token.clone(),
+                    ERROR_REPORT_TITLE.to_string(),
+                    report.clone(),
+                )
+            })
             .await;
 
             match res {
@@ -118,13 +744,33 @@ pub async fn submit_error_report(
             base_url,
             project,
             token_env_var,
+            token_file,
             ..
         } => {
             gitlab::submit_issue_or_merge_request_comment(
                 settings,
                 base_url,
                 project,
-                token_env_var,
+                gitlab_token(token_env_var, token_file)?,
+                ERROR_REPORT_TITLE.to_string(),
+                report,
+            )
+            .await?;
+        }
+        RepoHandle::Gitea {
+            base_url,
+            owner,
+            repo,
+            token_env_var,
+            token_file,
+            ..
+        } => {
+            gitea::submit_issue_or_pull_request_comment(
+                settings,
+                base_url,
+                owner,
+                repo,
+                gitea_token(token_env_var, token_file)?,
                 ERROR_REPORT_TITLE.to_string(),
                 report,
             )
@@ -136,3 +782,53 @@ pub async fn submit_error_report(
     }
     Ok(())
 }
+
+/// Notify the chats and webhooks configured for the repository about a submitted
+/// update. Failures are only logged.
+pub async fn notify_update(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+    summary: &str,
+    diff: &LockDiff,
+    url: Option<&str>,
+) {
+    if let Some(var) = &settings.discord_webhook_env_var {
+        if let Err(e) = discord::notify_update(state, var, handle, summary, url).await {
+            warn!("{}: Couldn't notify Discord: {}", handle, e);
+        }
+    }
+    if let Some(webhook_url) = &settings.webhook_url {
+        if let Err(e) = state.check_host(url_host(webhook_url)) {
+            warn!("{}: Not notifying the webhook: {}", handle, e);
+        } else if let Err(e) =
+            webhook::notify_update(webhook_url, &settings.webhook_headers, handle, diff, url).await
+        {
+            warn!("{}: Couldn't notify the webhook: {}", handle, e);
+        }
+    }
+}
+
+/// Notify the chats and webhooks configured for the repository about a failed update.
+/// Failures are only logged.
+pub async fn notify_error(
+    state: &UpdateState,
+    settings: &UpdateSettings,
+    handle: &RepoHandle,
+    error: &str,
+) {
+    if let Some(var) = &settings.discord_webhook_env_var {
+        if let Err(e) = discord::notify_error(state, var, handle, error).await {
+            warn!("{}: Couldn't notify Discord: {}", handle, e);
+        }
+    }
+    if let Some(webhook_url) = &settings.webhook_url {
+        if let Err(e) = state.check_host(url_host(webhook_url)) {
+            warn!("{}: Not notifying the webhook: {}", handle, e);
+        } else if let Err(e) =
+            webhook::notify_error(webhook_url, &settings.webhook_headers, handle, error).await
+        {
+            warn!("{}: Couldn't notify the webhook: {}", handle, e);
+        }
+    }
+}
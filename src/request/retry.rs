@@ -0,0 +1,68 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Retrying of API calls that hit rate limits
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use super::super::types::UpdateSettings;
+
+/// How API calls that hit a rate limit are retried
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay before the first retry, doubled for every next one
+    pub delay: Duration,
+    pub retries: usize,
+}
+
+impl Backoff {
+    pub fn from_settings(settings: &UpdateSettings) -> Self {
+        Backoff {
+            delay: settings.secondary_rate_limit_delay,
+            retries: settings.rate_limit_retries,
+        }
+    }
+
+    /// Delay before retry number `retry` (counting from 0), with up to 50% of jitter
+    /// so that the repositories hitting the limit together don't retry together
+    fn delay(&self, retry: usize) -> Duration {
+        let delay = self.delay * 2u32.saturating_pow(retry as u32);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or(0);
+        delay + (delay / 2).mul_f64(f64::from(nanos) / 1e9)
+    }
+}
+
+/// Run `operation`, retrying it as long as it fails with an error that is `rate_limited`.
+///
+/// NB: the API clients don't expose the response headers, so `Retry-After` and
+/// `X-RateLimit-Reset` can't be respected.
+pub async fn retry_rate_limited<T, E, F, Fut>(
+    backoff: &Backoff,
+    rate_limited: fn(&E) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    for retry in 0..backoff.retries {
+        match operation().await {
+            Err(e) if rate_limited(&e) => {
+                let delay = backoff.delay(retry);
+                warn!("{}, retrying in {:.0?}", e, delay);
+                tokio::time::sleep(delay).await;
+            }
+            res => return res,
+        }
+    }
+    operation().await
+}
@@ -0,0 +1,33 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+#[test]
+fn cooldown_spreads_a_low_budget_until_the_reset() {
+    let budget = RateBudget {
+        remaining: 50,
+        reset: 1_000_600,
+    };
+    assert_eq!(budget.cooldown(5, 1_000_000), None);
+    assert_eq!(
+        budget.cooldown(6, 1_000_000),
+        Some(Duration::from_secs(100))
+    );
+    // Past the reset, there's nothing to wait for
+    assert_eq!(budget.cooldown(6, 1_000_700), Some(Duration::ZERO));
+    assert_eq!(budget.cooldown(0, 1_000_000), None);
+}
+
+#[test]
+fn error_reports_are_recognized_by_marker_or_title() {
+    let body = format!("I tried updating flake.lock\n\n{}", ERROR_REPORT_MARKER);
+    assert!(is_error_report("Renamed by a maintainer", Some(&body)));
+    assert!(is_error_report(ERROR_REPORT_TITLE, None));
+    assert!(!is_error_report(
+        "Flake inputs are outdated",
+        Some("Please update flake.lock")
+    ));
+    assert!(!is_error_report("Flake inputs are outdated", None));
+}
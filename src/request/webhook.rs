@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Notifications to any webhook receiver, with a JSON payload
+
+use std::collections::HashMap;
+
+use super::super::flake_lock::LockDiff;
+use super::super::types::{EnvValue, RepoHandle};
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("Couldn't get the value of header {0} from env var {1}: {2}")]
+    Header(String, String, std::env::VarError),
+    #[error("Error posting to the webhook: {0}")]
+    Post(#[from] reqwest::Error),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Updated,
+    Error,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    repo: String,
+    outcome: Outcome,
+    /// The changed inputs, in the format of `diff-locks --format json`
+    changes: Option<&'a LockDiff>,
+    url: Option<&'a str>,
+    error: Option<&'a str>,
+    timestamp: String,
+}
+
+async fn post(
+    url: &str,
+    headers: &HashMap<String, EnvValue>,
+    payload: Payload<'_>,
+) -> Result<(), WebhookError> {
+    let mut request = reqwest::Client::new().post(url).json(&payload);
+    for (name, value) in headers {
+        let value = match value {
+            EnvValue::Value(value) => value.clone(),
+            EnvValue::FromEnv { from_env } => std::env::var(from_env)
+                .map_err(|e| WebhookError::Header(name.clone(), from_env.clone(), e))?,
+        };
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+pub async fn notify_update(
+    url: &str,
+    headers: &HashMap<String, EnvValue>,
+    handle: &RepoHandle,
+    diff: &LockDiff,
+    request_url: Option<&str>,
+) -> Result<(), WebhookError> {
+    let payload = Payload {
+        repo: handle.to_string(),
+        outcome: Outcome::Updated,
+        changes: Some(diff),
+        url: request_url,
+        error: None,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+    post(url, headers, payload).await
+}
+
+pub async fn notify_error(
+    url: &str,
+    headers: &HashMap<String, EnvValue>,
+    handle: &RepoHandle,
+    error: &str,
+) -> Result<(), WebhookError> {
+    let payload = Payload {
+        repo: handle.to_string(),
+        outcome: Outcome::Error,
+        changes: None,
+        url: None,
+        error: Some(error),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+    post(url, headers, payload).await
+}
@@ -0,0 +1,209 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveTime, TimeZone, Utc};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("Invalid interval {0:?}: expected a number followed by s, m, h or d")]
+    Interval(String),
+    #[error("Invalid cron expression {0:?}: expected 5 fields (minute, hour, day of month, month, day of week), found {1}")]
+    FieldCount(String, usize),
+    #[error("Invalid {1} field {0:?} in the cron expression")]
+    Field(String, &'static str),
+    #[error("The cron expression {0:?} never matches")]
+    Never(String),
+}
+
+/// When to run the updates in daemon mode
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Schedule {
+    /// The time between the end of a run and the start of the next one
+    Interval(Duration),
+    /// The times (in UTC) at which to start a run, skipping those during a run
+    Cron(Cron),
+}
+
+/// A cron expression, each field as the set of the values it matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    /// Sunday is 0
+    days_of_week: Vec<bool>,
+    /// As in cron, a day matches either restricted day field if both are
+    days_of_month_any: bool,
+    days_of_week_any: bool,
+}
+
+/// Parse a field of a cron expression: a list of `*`, values or ranges of them,
+/// each with an optional step
+fn parse_field(
+    field: &str,
+    name: &'static str,
+    min: usize,
+    max: usize,
+) -> Result<Vec<bool>, ScheduleError> {
+    let error = || ScheduleError::Field(field.to_string(), name);
+    let mut matches = vec![false; max + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| error())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (
+                start.parse().map_err(|_| error())?,
+                end.parse().map_err(|_| error())?,
+            ),
+            // A single value with a step runs up to the maximum
+            None if part.contains('/') => (range.parse().map_err(|_| error())?, max),
+            None => {
+                let value = range.parse().map_err(|_| error())?;
+                (value, value)
+            }
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(error());
+        }
+        for value in (start..=end).step_by(step) {
+            matches[value] = true;
+        }
+    }
+    Ok(matches)
+}
+
+impl FromStr for Cron {
+    type Err = ScheduleError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(ScheduleError::FieldCount(
+                expression.to_string(),
+                fields.len(),
+            ));
+        };
+        let mut cron = Cron {
+            minutes: parse_field(minutes, "minute", 0, 59)?,
+            hours: parse_field(hours, "hour", 0, 23)?,
+            days_of_month: parse_field(days_of_month, "day of month", 1, 31)?,
+            months: parse_field(months, "month", 1, 12)?,
+            days_of_week: parse_field(days_of_week, "day of week", 0, 7)?,
+            days_of_month_any: days_of_month == "*",
+            days_of_week_any: days_of_week == "*",
+        };
+        // 7 is Sunday too
+        cron.days_of_week[0] |= cron.days_of_week[7];
+        if cron
+            .next_after(Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap())
+            .is_none()
+        {
+            return Err(ScheduleError::Never(expression.to_string()));
+        }
+        Ok(cron)
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, date: chrono::NaiveDate) -> bool {
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+        self.months[date.month() as usize]
+            && match (self.days_of_month_any, self.days_of_week_any) {
+                (false, false) => day_of_month || day_of_week,
+                _ => day_of_month && day_of_week,
+            }
+    }
+
+    /// The first time the expression matches strictly after `time`, if it does within
+    /// the next leap-year cycle
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = time.date_naive();
+        for days in 0..=4 * 366 {
+            let date = start + ChronoDuration::days(days);
+            if !self.matches_day(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours[*hour as usize]) {
+                for minute in (0..60).filter(|minute| self.minutes[*minute as usize]) {
+                    let candidate = date
+                        .and_time(NaiveTime::from_hms_opt(hour, minute, 0).unwrap())
+                        .and_utc();
+                    if candidate > time {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parse an interval such as `30m` or `6h`
+fn parse_interval(interval: &str) -> Result<Duration, ScheduleError> {
+    let error = || ScheduleError::Interval(interval.to_string());
+    let unit_start = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(error)?;
+    let (number, unit) = interval.split_at(unit_start);
+    let number: u64 = number.parse().map_err(|_| error())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+    match number {
+        0 => Err(error()),
+        _ => Ok(Duration::from_secs(number * seconds)),
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ScheduleError;
+
+    fn from_str(schedule: &str) -> Result<Self, Self::Err> {
+        let schedule = schedule.trim();
+        if schedule.contains(char::is_whitespace) {
+            schedule.parse().map(Schedule::Cron)
+        } else {
+            parse_interval(schedule).map(Schedule::Interval)
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = ScheduleError;
+
+    fn try_from(schedule: String) -> Result<Self, Self::Error> {
+        schedule.parse()
+    }
+}
+
+impl Schedule {
+    /// How long to wait at `now`, at the end of a run, before starting the next one
+    pub fn until_next(&self, now: DateTime<Utc>) -> Duration {
+        match self {
+            Schedule::Interval(interval) => *interval,
+            Schedule::Cron(cron) => cron
+                .next_after(now)
+                .and_then(|next| (next - now).to_std().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
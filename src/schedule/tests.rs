@@ -0,0 +1,72 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+        .unwrap()
+}
+
+fn next(expression: &str, time: DateTime<Utc>) -> DateTime<Utc> {
+    expression
+        .parse::<Cron>()
+        .unwrap()
+        .next_after(time)
+        .unwrap()
+}
+
+#[test]
+fn intervals_are_parsed() {
+    assert_eq!(
+        "90s".parse::<Schedule>().unwrap(),
+        Schedule::Interval(Duration::from_secs(90))
+    );
+    assert_eq!(
+        "6h".parse::<Schedule>().unwrap(),
+        Schedule::Interval(Duration::from_secs(6 * 60 * 60))
+    );
+    for invalid in ["", "6", "h", "0m", "6w", "-6h"] {
+        assert!(invalid.parse::<Schedule>().is_err(), "{:?}", invalid);
+    }
+}
+
+#[test]
+fn cron_expressions_match_the_next_time() {
+    // 2024-03-15 is a Friday
+    let now = at(2024, 3, 15, 10, 30);
+    assert_eq!(next("* * * * *", now), at(2024, 3, 15, 10, 31));
+    assert_eq!(next("0 4 * * *", now), at(2024, 3, 16, 4, 0));
+    assert_eq!(next("*/20 10-11 * * *", now), at(2024, 3, 15, 10, 40));
+    assert_eq!(next("0 0 * * 1", now), at(2024, 3, 18, 0, 0));
+    assert_eq!(next("0 0 * * 7", now), at(2024, 3, 17, 0, 0));
+    assert_eq!(next("0 0 29 2 *", now), at(2028, 2, 29, 0, 0));
+    // Either day field matches when both are restricted
+    assert_eq!(next("0 0 1 * 6", now), at(2024, 3, 16, 0, 0));
+    assert_eq!(next("0 0 1,20 * 3", now), at(2024, 3, 20, 0, 0));
+}
+
+#[test]
+fn invalid_cron_expressions_are_rejected() {
+    for invalid in [
+        "* * * *",
+        "60 * * * *",
+        "* * 0 * *",
+        "*/0 * * * *",
+        "5-1 * * * *",
+        "a * * * *",
+        "0 0 31 2 *",
+    ] {
+        assert!(invalid.parse::<Schedule>().is_err(), "{:?}", invalid);
+    }
+}
+
+#[test]
+fn cron_schedules_wait_until_the_next_time() {
+    let schedule: Schedule = "0 * * * *".parse().unwrap();
+    assert_eq!(
+        schedule.until_next(at(2024, 3, 15, 10, 30)),
+        Duration::from_secs(30 * 60)
+    );
+}
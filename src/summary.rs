@@ -0,0 +1,87 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The report of a run written to `--summary-file`.
+
+use serde::Serialize;
+use std::path::Path;
+
+#[cfg(test)]
+mod tests;
+
+/// What happened to a repository during the run
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RepoStatus {
+    /// The update was submitted (or, in a dry run, would have been)
+    Updated {
+        /// The "pull request", if it was submitted
+        url: Option<String>,
+        inputs_changed: usize,
+        /// The changes, as the markdown table of the "pull request"
+        diff: String,
+    },
+    /// There was nothing to update
+    UpToDate,
+    Failed {
+        error: String,
+    },
+    /// Not updated due to a shutdown
+    Skipped,
+    /// Cancelled, past the overall timeout or the shutdown grace period
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoSummary {
+    pub repo: String,
+    #[serde(flatten)]
+    pub status: RepoStatus,
+}
+
+/// Render the report as markdown: a list of the repositories and their status,
+/// followed by the changes to each updated repository
+pub fn markdown(repos: &[RepoSummary]) -> String {
+    let mut report = String::from("# update-daemon run\n\n");
+    for summary in repos {
+        let status = match &summary.status {
+            RepoStatus::Updated {
+                url: Some(url),
+                inputs_changed,
+                ..
+            } => format!("updated {} inputs in {}", inputs_changed, url),
+            RepoStatus::Updated { inputs_changed, .. } => {
+                format!("updated {} inputs", inputs_changed)
+            }
+            RepoStatus::UpToDate => "up to date".to_string(),
+            RepoStatus::Failed { error } => {
+                format!("failed: {}", error.lines().next().unwrap_or_default())
+            }
+            RepoStatus::Skipped => "skipped due to the shutdown".to_string(),
+            RepoStatus::Cancelled => "cancelled, it didn't finish in time".to_string(),
+        };
+        report.push_str(&format!("- {}: {}\n", summary.repo, status));
+    }
+    for summary in repos {
+        match &summary.status {
+            RepoStatus::Updated { diff, .. } => {
+                report.push_str(&format!("\n## {}\n\n{}", summary.repo, diff))
+            }
+            RepoStatus::Failed { error } if error.contains('\n') => {
+                report.push_str(&format!("\n## {}\n\n```\n{}\n```\n", summary.repo, error))
+            }
+            _ => {}
+        }
+    }
+    report
+}
+
+/// Write the report to `path`: as JSON if it ends with `.json`, as markdown otherwise
+pub fn write(path: &Path, repos: &[RepoSummary]) -> std::io::Result<()> {
+    let report = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::to_string_pretty(repos)?,
+        _ => markdown(repos),
+    };
+    std::fs::write(path, report)
+}
@@ -0,0 +1,54 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+fn repos() -> Vec<RepoSummary> {
+    vec![
+        RepoSummary {
+            repo: "serokell/update-daemon".to_string(),
+            status: RepoStatus::Updated {
+                url: Some("https://github.com/serokell/update-daemon/pull/1".to_string()),
+                inputs_changed: 1,
+                diff: "| input |\n".to_string(),
+            },
+        },
+        RepoSummary {
+            repo: "serokell/deploy-rs".to_string(),
+            status: RepoStatus::UpToDate,
+        },
+        RepoSummary {
+            repo: "serokell/nix-npm-buildpackage".to_string(),
+            status: RepoStatus::Failed {
+                error: "Error during flake update: timed out\noutput".to_string(),
+            },
+        },
+    ]
+}
+
+#[test]
+fn markdown_lists_repositories_then_details() {
+    assert_eq!(
+        markdown(&repos()),
+        "# update-daemon run\n\n\
+         - serokell/update-daemon: updated 1 inputs in https://github.com/serokell/update-daemon/pull/1\n\
+         - serokell/deploy-rs: up to date\n\
+         - serokell/nix-npm-buildpackage: failed: Error during flake update: timed out\n\
+         \n## serokell/update-daemon\n\n| input |\n\
+         \n## serokell/nix-npm-buildpackage\n\n```\nError during flake update: timed out\noutput\n```\n"
+    );
+}
+
+#[test]
+fn json_report_is_written_by_extension() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("summary.json");
+    write(&path, &repos()).unwrap();
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(report[0]["status"], "updated");
+    assert_eq!(report[0]["inputs_changed"], 1);
+    assert_eq!(report[1]["status"], "up_to_date");
+    assert_eq!(report[2]["repo"], "serokell/nix-npm-buildpackage");
+}
@@ -0,0 +1,55 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Templates of the texts update-daemon writes, with `{{name}}` placeholders
+
+#[cfg(test)]
+mod tests;
+
+/// Placeholders of `body_template`
+pub const BODY_PLACEHOLDERS: [&str; 5] =
+    ["diff_table", "checks", "timestamp", "extra_body", "repo"];
+/// Placeholders of `commit_message_template`
+pub const COMMIT_MESSAGE_PLACEHOLDERS: [&str; 1] = ["diff"];
+/// Placeholders of `update_branch`
+pub const UPDATE_BRANCH_PLACEHOLDERS: [&str; 2] = ["date", "short_date"];
+/// Placeholders of `input_label_template`
+pub const INPUT_LABEL_PLACEHOLDERS: [&str; 1] = ["input"];
+
+/// Names of the placeholders in `template`, in order
+pub fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        let start = rest.find("{{")? + 2;
+        let end = start + rest[start..].find("}}")?;
+        let name = rest[start..end].trim();
+        rest = &rest[end + 2..];
+        Some(name)
+    })
+}
+
+/// The first placeholder of `template` that isn't one of `known`, if there is one
+pub fn unknown_placeholder<'t>(template: &'t str, known: &[&str]) -> Option<&'t str> {
+    placeholders(template).find(|name| !known.contains(name))
+}
+
+/// Fill in the placeholders of `template` with `values`, given as (name, value)
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end) else {
+            break;
+        };
+        let name = rest[start + 2..end].trim();
+        rendered.push_str(&rest[..start]);
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
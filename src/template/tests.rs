@@ -0,0 +1,42 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+#[test]
+fn placeholders_are_filled_in() {
+    let template = "## Update of {{ repo }}\n\n{{extra_body}}\n{{diff_table}}{{diff_table}}";
+    assert_eq!(
+        render(
+            template,
+            &[
+                ("repo", "owner/repo"),
+                ("diff_table", "|a|"),
+                ("extra_body", "")
+            ]
+        ),
+        "## Update of owner/repo\n\n\n|a||a|"
+    );
+    // Unknown and unterminated placeholders are left alone
+    assert_eq!(
+        render("{{other}} {{repo", &[("repo", "owner/repo")]),
+        "{{other}} {{repo"
+    );
+}
+
+#[test]
+fn unknown_placeholders_are_found() {
+    assert_eq!(
+        placeholders("{{a}} b {{ c }}").collect::<Vec<_>>(),
+        vec!["a", "c"]
+    );
+    assert_eq!(
+        unknown_placeholder("{{repo}} {{diff}}", &BODY_PLACEHOLDERS),
+        Some("diff")
+    );
+    assert_eq!(
+        unknown_placeholder("{{ repo }} {{timestamp}}", &BODY_PLACEHOLDERS),
+        None
+    );
+}
@@ -2,35 +2,274 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use log::warn;
 use merge::Merge;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 use ssh2_config::SshConfig;
+use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
 
-#[derive(Debug, Clone, Deserialize)]
+use super::flake_lock::{DiffDisplayFilter, DisplayOptions, Lock, LockDiff, LockDiffError};
+use super::template::{
+    render, unknown_placeholder, BODY_PLACEHOLDERS, COMMIT_MESSAGE_PLACEHOLDERS,
+    INPUT_LABEL_PLACEHOLDERS, UPDATE_BRANCH_PLACEHOLDERS,
+};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UpdateSettings {
     pub author: Author,
     pub update_branch: String,
     pub default_branch: String,
     pub title: String,
     pub extra_body: String,
+    #[serde(serialize_with = "serialize_millis")]
     pub cooldown: Duration,
     pub inputs: Vec<String>,
     pub allow_missing_inputs: bool,
     pub sign_commits: bool,
     pub signing_key: Option<String>,
+    pub incremental_diff: bool,
+    pub env: HashMap<String, EnvValue>,
+    pub comment_only: bool,
+    pub comment_label: Option<String>,
+    pub rev_display_length: usize,
+    pub discord_webhook_env_var: Option<String>,
+    pub sequential_inputs: Vec<String>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub pre_pr_delay: Duration,
+    pub lock_filename: String,
+    #[serde(serialize_with = "serialize_secs")]
+    pub secondary_rate_limit_delay: Duration,
+    pub rate_limit_retries: usize,
+    pub managed_pr_number: Option<u64>,
+    pub eval_attr: Option<String>,
+    pub search_head_prefix: bool,
+    pub search_any_base: bool,
+    pub extra_body_file: Option<PathBuf>,
+    pub sandbox_command: Vec<String>,
+    pub report_transitive_changes: bool,
+    pub own_commit_emails: Vec<String>,
+    pub discussion_category: Option<String>,
+    pub include_transitive: bool,
+    pub commit_trailer: Option<String>,
+    pub release_inputs: Vec<String>,
+    pub run_flake_check: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    pub flake_check_timeout: Duration,
+    pub flake_check_args: Vec<String>,
+    pub draft_on_failed_check: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    pub flake_update_timeout: Duration,
+    pub default_ref: Option<String>,
+    pub extra_nix_args: Vec<String>,
+    pub min_lock_age_days: Option<u64>,
+    pub nix_update_syntax: NixUpdateSyntax,
+    pub nix_options: HashMap<String, String>,
+    pub skip_if_no_lock: bool,
+    pub flake_subdir: Option<String>,
+    pub webhook_url: Option<String>,
+    pub webhook_headers: HashMap<String, EnvValue>,
+    /// Whether `default_branch` wasn't configured, so it is detected from the remote,
+    /// falling back to the configured value
+    pub detect_default_branch: bool,
+    pub git_auth: Option<GitAuth>,
+    pub max_repo_size: Option<u64>,
+    pub ssh_key_path: Option<PathBuf>,
+    pub ssh_key_passphrase_file: Option<PathBuf>,
+    pub remote_name: String,
+    pub fork: Option<Fork>,
+    pub labels: Vec<String>,
+    pub multiple_requests: MultipleRequests,
+    pub reviewers: Vec<String>,
+    pub assignees: Vec<String>,
+    pub draft: bool,
+    pub report_only_inputs: Vec<String>,
+    pub close_when_empty: bool,
+    pub delete_branch_when_empty: bool,
+    pub auto_merge: Option<MergeMethod>,
+    pub body_template: Option<String>,
+    pub commit_message_template: Option<String>,
+    pub signing_method: SigningMethod,
+    pub signing_passphrase_file: Option<PathBuf>,
+    pub report_errors: bool,
+    pub report_errors_after: u32,
+    pub rebase_on_human_commits: bool,
+    pub min_input_age: Option<u64>,
+    pub diff_display_filter: Option<DiffDisplayFilter>,
+    pub diff_collapse_threshold: usize,
+    pub squash_history: bool,
+    pub input_label_template: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+// Print durations back in the units they are configured in
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+impl UpdateSettings {
+    /// Fill in the `{{date}}` (YYYY-MM-DD) and `{{short_date}}` (YYYYMMDD) placeholders
+    /// of `update_branch` with `date`, the day the run started
+    pub fn resolve_update_branch(&mut self, date: chrono::NaiveDate) {
+        self.update_branch = render(
+            &self.update_branch,
+            &[
+                ("date", &date.format("%Y-%m-%d").to_string()),
+                ("short_date", &date.format("%Y%m%d").to_string()),
+            ],
+        );
+    }
+
+    pub fn display_options(&self) -> DisplayOptions {
+        DisplayOptions {
+            rev_length: self.rev_display_length,
+            filter: self.diff_display_filter.clone(),
+            collapse_threshold: self.diff_collapse_threshold,
+        }
+    }
+
+    /// The changes from `old` to `new`: of the root inputs only, or of all the
+    /// inputs with `include_transitive`
+    pub fn diff_locks(&self, old: &Lock, new: &Lock) -> Result<LockDiff, LockDiffError> {
+        if self.include_transitive {
+            old.diff_all(new)
+        } else {
+            old.diff(new)
+        }
+    }
+
+    /// The age in days of the newest input of `lock`, if it is within `min_lock_age_days`
+    pub fn recent_lock_age(&self, lock: &Lock) -> Option<i64> {
+        let days = self.min_lock_age_days? as i64;
+        let age = (chrono::Utc::now().timestamp() - lock.newest_last_modified()?) / (24 * 60 * 60);
+        (age < days).then_some(age)
+    }
+
+    /// Whether commits authored with `email` are update-daemon's own, rather than human ones
+    pub fn is_own_commit_email(&self, email: &str) -> bool {
+        email == self.author.email || self.own_commit_emails.iter().any(|own| own == email)
+    }
+
+    /// Directory of the flake in the repository checked out to `workdir`
+    pub fn flake_dir(&self, workdir: &Path) -> PathBuf {
+        match &self.flake_subdir {
+            Some(subdir) => workdir.join(subdir),
+            None => workdir.to_path_buf(),
+        }
+    }
+
+    /// Path to the lock file in the repository checked out to `workdir`
+    pub fn lock_path(&self, workdir: &Path) -> PathBuf {
+        self.flake_dir(workdir).join(&self.lock_filename)
+    }
+
+    /// Path to the lock file relative to the repository root
+    pub fn lock_repo_path(&self) -> PathBuf {
+        self.flake_dir(Path::new("")).join(&self.lock_filename)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Author {
     pub name: String,
     pub email: String,
 }
 
+/// Value of an environment variable set for the commands run in a repository
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Value(String),
+    /// Take the value from update-daemon's own environment,
+    /// so that secrets don't have to be put in the configuration
+    FromEnv {
+        from_env: String,
+    },
+}
+
+/// How the inputs to update are passed to `nix flake update`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NixUpdateSyntax {
+    /// `nix flake update <input>...`, since Nix 2.19
+    New,
+    /// `nix flake lock --update-input <input>...`, deprecated since Nix 2.19
+    Old,
+}
+
+/// How git fetches from and pushes to the repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GitAuth {
+    /// Over ssh, with the keys of the ssh agent
+    Ssh,
+    /// Over HTTPS, with the API token
+    #[serde(alias = "https-token")]
+    Https,
+}
+
+/// What to do when several open "pull requests" match the update one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultipleRequests {
+    /// Fail, reporting the ambiguity
+    Error,
+    /// Update only one of them
+    UpdateFirst,
+    /// Update all of them
+    UpdateAll,
+}
+
+/// How update commits are signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningMethod {
+    /// With gpg-agent, like `gpg.format = openpgp`
+    Gpg,
+    /// With `ssh-keygen`, like `gpg.format = ssh`
+    Ssh,
+}
+
+/// How a pull request is merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeMethod {
+    Merge,
+    Squash,
+    Rebase,
+}
+
+/// Repository the update branch is pushed to, with the pull request submitted from it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Fork {
+    pub owner: String,
+    /// The name of the updated repository by default
+    pub repo: Option<String>,
+    /// The `ssh_url` of the updated repository by default
+    pub ssh_url: Option<String>,
+}
+
+/// Username and password for git over HTTPS
+#[derive(Debug, Clone)]
+pub struct GitCredentials {
+    pub username: String,
+    pub password: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Default, Merge)]
 pub struct UpdateSettingsOptional {
     pub author: Option<Author>,
@@ -43,41 +282,267 @@ pub struct UpdateSettingsOptional {
     pub allow_missing_inputs: Option<bool>,
     pub sign_commits: Option<bool>,
     pub signing_key: Option<String>,
+    pub incremental_diff: Option<bool>,
+    pub env: Option<HashMap<String, EnvValue>>,
+    pub comment_only: Option<bool>,
+    pub comment_label: Option<String>,
+    pub rev_display_length: Option<usize>,
+    pub discord_webhook_env_var: Option<String>,
+    pub sequential_inputs: Option<Vec<String>>,
+    pub pre_pr_delay: Option<u64>,
+    pub lock_filename: Option<String>,
+    pub secondary_rate_limit_delay: Option<u64>,
+    pub rate_limit_retries: Option<usize>,
+    pub managed_pr_number: Option<u64>,
+    pub eval_attr: Option<String>,
+    pub search_head_prefix: Option<bool>,
+    pub search_any_base: Option<bool>,
+    pub extra_body_file: Option<PathBuf>,
+    pub sandbox_command: Option<Vec<String>>,
+    pub report_transitive_changes: Option<bool>,
+    pub own_commit_emails: Option<Vec<String>>,
+    pub discussion_category: Option<String>,
+    pub include_transitive: Option<bool>,
+    pub commit_trailer: Option<String>,
+    pub release_inputs: Option<Vec<String>>,
+    pub run_flake_check: Option<bool>,
+    pub flake_check_timeout: Option<u64>,
+    pub flake_check_args: Option<Vec<String>>,
+    pub draft_on_failed_check: Option<bool>,
+    pub flake_update_timeout: Option<u64>,
+    pub default_ref: Option<String>,
+    pub extra_nix_args: Option<Vec<String>>,
+    pub min_lock_age_days: Option<u64>,
+    pub nix_update_syntax: Option<NixUpdateSyntax>,
+    pub nix_options: Option<HashMap<String, String>>,
+    pub skip_if_no_lock: Option<bool>,
+    pub flake_subdir: Option<String>,
+    pub webhook_url: Option<String>,
+    pub webhook_headers: Option<HashMap<String, EnvValue>>,
+    pub git_auth: Option<GitAuth>,
+    pub max_repo_size: Option<u64>,
+    pub ssh_key_path: Option<PathBuf>,
+    pub ssh_key_passphrase_file: Option<PathBuf>,
+    pub remote_name: Option<String>,
+    pub fork: Option<Fork>,
+    pub labels: Option<Vec<String>>,
+    pub multiple_requests: Option<MultipleRequests>,
+    pub reviewers: Option<Vec<String>>,
+    pub assignees: Option<Vec<String>>,
+    pub draft: Option<bool>,
+    pub report_only_inputs: Option<Vec<String>>,
+    pub close_when_empty: Option<bool>,
+    pub delete_branch_when_empty: Option<bool>,
+    pub auto_merge: Option<MergeMethod>,
+    pub body_template: Option<String>,
+    pub commit_message_template: Option<String>,
+    pub signing_method: Option<SigningMethod>,
+    pub signing_passphrase_file: Option<PathBuf>,
+    pub report_errors: Option<bool>,
+    pub report_errors_after: Option<u32>,
+    pub rebase_on_human_commits: Option<bool>,
+    pub min_input_age: Option<u64>,
+    pub diff_display_filter: Option<DiffDisplayFilter>,
+    pub diff_collapse_threshold: Option<usize>,
+    pub squash_history: Option<bool>,
+    pub input_label_template: Option<String>,
 }
 
 #[derive(Debug, Error)]
-pub struct UpdateSettingsMissingField(String);
+pub enum UpdateSettingsError {
+    #[error("Settings missing field {0}")]
+    MissingField(String),
+    #[error("Invalid value of {0}: {1}")]
+    InvalidField(String, String),
+}
+
+fn unoption<T>(opt: Option<T>, name: &'static str) -> Result<T, UpdateSettingsError> {
+    opt.ok_or_else(|| UpdateSettingsError::MissingField(name.to_string()))
+}
 
-impl std::fmt::Display for UpdateSettingsMissingField {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f, "Settings missing field {}", self.0)
+/// Cooldowns below this (in milliseconds) risk tripping the forges' abuse detection
+const MIN_SAFE_COOLDOWN: u64 = 1000;
+/// Cooldowns above this (in milliseconds) would stall the run for more than a day
+const MAX_COOLDOWN: u64 = 24 * 60 * 60 * 1000;
+
+fn validate_cooldown(cooldown: u64) -> Result<Duration, UpdateSettingsError> {
+    if cooldown == 0 {
+        return Err(UpdateSettingsError::InvalidField(
+            "cooldown".to_string(),
+            "it must be positive".to_string(),
+        ));
+    }
+    if cooldown > MAX_COOLDOWN {
+        return Err(UpdateSettingsError::InvalidField(
+            "cooldown".to_string(),
+            format!(
+                "{}ms is more than a day, note that it is in milliseconds",
+                cooldown
+            ),
+        ));
     }
+    if cooldown < MIN_SAFE_COOLDOWN {
+        warn!(
+            "A cooldown of {}ms between requests may trip the abuse detection of GitHub or GitLab",
+            cooldown
+        );
+    }
+    Ok(Duration::from_millis(cooldown))
 }
 
-fn unoption<T>(opt: Option<T>, name: &'static str) -> Result<T, UpdateSettingsMissingField> {
-    opt.ok_or_else(|| UpdateSettingsMissingField(name.to_string()))
+fn validate_commit_trailer(key: String) -> Result<String, UpdateSettingsError> {
+    if key.is_empty() || key.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(UpdateSettingsError::InvalidField(
+            "commit_trailer".to_string(),
+            format!(
+                "{:?} is not a trailer key, which must be a single word",
+                key
+            ),
+        ));
+    }
+    Ok(key)
+}
+
+fn validate_report_errors_after(failures: u32) -> Result<u32, UpdateSettingsError> {
+    if failures == 0 {
+        return Err(UpdateSettingsError::InvalidField(
+            "report_errors_after".to_string(),
+            "must be at least 1, use report_errors to never report errors".to_string(),
+        ));
+    }
+    Ok(failures)
+}
+
+fn validate_template(
+    name: &str,
+    template: String,
+    known: &[&str],
+) -> Result<String, UpdateSettingsError> {
+    if let Some(unknown) = unknown_placeholder(&template, known) {
+        return Err(UpdateSettingsError::InvalidField(
+            name.to_string(),
+            format!(
+                "unknown placeholder {{{{{}}}}}, the known ones are {}",
+                unknown,
+                known.join(", ")
+            ),
+        ));
+    }
+    Ok(template)
 }
 
 impl std::convert::TryInto<UpdateSettings> for UpdateSettingsOptional {
-    type Error = UpdateSettingsMissingField;
+    type Error = UpdateSettingsError;
 
     fn try_into(self) -> Result<UpdateSettings, Self::Error> {
         Ok(UpdateSettings {
             author: unoption(self.author, "author")?,
-            update_branch: self
-                .update_branch
-                .unwrap_or_else(|| "automatic-update".to_string()),
-            default_branch: self.default_branch.unwrap_or_else(|| "master".to_string()),
+            update_branch: validate_template(
+                "update_branch",
+                self.update_branch
+                    .unwrap_or_else(|| "automatic-update".to_string()),
+                &UPDATE_BRANCH_PLACEHOLDERS,
+            )?,
+            default_branch: self
+                .default_branch
+                .clone()
+                .unwrap_or_else(|| "master".to_string()),
             title: self
                 .title
                 .unwrap_or_else(|| "Automatically update flake.lock".to_string()),
             extra_body: self.extra_body.unwrap_or_default(),
-            // what if negative number in config?
-            cooldown: Duration::from_millis(unoption(self.cooldown, "cooldown")?),
+            cooldown: validate_cooldown(unoption(self.cooldown, "cooldown")?)?,
             inputs: self.inputs.unwrap_or_default(),
             allow_missing_inputs: self.allow_missing_inputs.unwrap_or(false),
             sign_commits: self.sign_commits.unwrap_or(false),
             signing_key: self.signing_key,
+            incremental_diff: self.incremental_diff.unwrap_or(false),
+            env: self.env.unwrap_or_default(),
+            comment_only: self.comment_only.unwrap_or(false),
+            comment_label: self.comment_label,
+            rev_display_length: self.rev_display_length.unwrap_or(10),
+            discord_webhook_env_var: self.discord_webhook_env_var,
+            sequential_inputs: self.sequential_inputs.unwrap_or_default(),
+            pre_pr_delay: Duration::from_secs(self.pre_pr_delay.unwrap_or(0)),
+            lock_filename: self
+                .lock_filename
+                .unwrap_or_else(|| "flake.lock".to_string()),
+            secondary_rate_limit_delay: Duration::from_secs(
+                self.secondary_rate_limit_delay.unwrap_or(60),
+            ),
+            rate_limit_retries: self.rate_limit_retries.unwrap_or(2),
+            managed_pr_number: self.managed_pr_number,
+            eval_attr: self.eval_attr,
+            search_head_prefix: self.search_head_prefix.unwrap_or(false),
+            search_any_base: self.search_any_base.unwrap_or(false),
+            extra_body_file: self.extra_body_file,
+            sandbox_command: self.sandbox_command.unwrap_or_default(),
+            report_transitive_changes: self.report_transitive_changes.unwrap_or(false),
+            own_commit_emails: self.own_commit_emails.unwrap_or_default(),
+            discussion_category: self.discussion_category,
+            include_transitive: self.include_transitive.unwrap_or(false),
+            commit_trailer: self
+                .commit_trailer
+                .map(validate_commit_trailer)
+                .transpose()?,
+            release_inputs: self.release_inputs.unwrap_or_default(),
+            run_flake_check: self.run_flake_check.unwrap_or(false),
+            flake_check_timeout: Duration::from_secs(self.flake_check_timeout.unwrap_or(3600)),
+            flake_check_args: self.flake_check_args.unwrap_or_default(),
+            draft_on_failed_check: self.draft_on_failed_check.unwrap_or(false),
+            flake_update_timeout: Duration::from_secs(self.flake_update_timeout.unwrap_or(3600)),
+            default_ref: self.default_ref,
+            extra_nix_args: self.extra_nix_args.unwrap_or_default(),
+            min_lock_age_days: self.min_lock_age_days,
+            nix_update_syntax: self.nix_update_syntax.unwrap_or(NixUpdateSyntax::New),
+            nix_options: self.nix_options.unwrap_or_default(),
+            skip_if_no_lock: self.skip_if_no_lock.unwrap_or(false),
+            flake_subdir: self.flake_subdir,
+            webhook_url: self.webhook_url,
+            webhook_headers: self.webhook_headers.unwrap_or_default(),
+            detect_default_branch: self.default_branch.is_none(),
+            git_auth: self.git_auth,
+            max_repo_size: self.max_repo_size,
+            ssh_key_path: self.ssh_key_path,
+            ssh_key_passphrase_file: self.ssh_key_passphrase_file,
+            remote_name: self.remote_name.unwrap_or_else(|| "origin".to_string()),
+            fork: self.fork,
+            labels: self.labels.unwrap_or_default(),
+            multiple_requests: self
+                .multiple_requests
+                .unwrap_or(MultipleRequests::UpdateFirst),
+            reviewers: self.reviewers.unwrap_or_default(),
+            assignees: self.assignees.unwrap_or_default(),
+            draft: self.draft.unwrap_or(false),
+            report_only_inputs: self.report_only_inputs.unwrap_or_default(),
+            close_when_empty: self.close_when_empty.unwrap_or(false),
+            delete_branch_when_empty: self.delete_branch_when_empty.unwrap_or(false),
+            auto_merge: self.auto_merge,
+            body_template: self
+                .body_template
+                .map(|t| validate_template("body_template", t, &BODY_PLACEHOLDERS))
+                .transpose()?,
+            commit_message_template: self
+                .commit_message_template
+                .map(|t| {
+                    validate_template("commit_message_template", t, &COMMIT_MESSAGE_PLACEHOLDERS)
+                })
+                .transpose()?,
+            signing_method: self.signing_method.unwrap_or(SigningMethod::Gpg),
+            signing_passphrase_file: self.signing_passphrase_file,
+            report_errors: self.report_errors.unwrap_or(true),
+            report_errors_after: validate_report_errors_after(
+                self.report_errors_after.unwrap_or(1),
+            )?,
+            rebase_on_human_commits: self.rebase_on_human_commits.unwrap_or(false),
+            min_input_age: self.min_input_age,
+            diff_display_filter: self.diff_display_filter,
+            diff_collapse_threshold: self.diff_collapse_threshold.unwrap_or(10),
+            squash_history: self.squash_history.unwrap_or(false),
+            input_label_template: self
+                .input_label_template
+                .map(|t| validate_template("input_label_template", t, &INPUT_LABEL_PLACEHOLDERS))
+                .transpose()?,
         })
     }
 }
@@ -87,31 +552,118 @@ pub struct UpdateState {
     pub cache_dir: PathBuf,
     pub global_ssh_config: Option<SshConfig>,
     pub local_ssh_config: Option<SshConfig>,
+    /// Limits the number of simultaneous git network operations
+    pub git_semaphore: Option<Arc<Semaphore>>,
+    /// Don't push anything or submit any requests
+    pub dry_run: bool,
+    /// Log the commands and API calls equivalent to what is being done
+    pub explain_commands: bool,
+    /// The only hosts that may be contacted, if set
+    pub allowed_hosts: Option<Vec<String>>,
+    pub shutdown: Arc<Shutdown>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Requested on SIGTERM or SIGINT: no more updates are started,
+/// and those in progress are given a grace period to finish
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the shutdown is requested
+    pub async fn requested(&self) {
+        // Created before checking, so that a request in between isn't missed
+        let notified = self.notify.notified();
+        if !self.is_requested() {
+            notified.await;
+        }
+    }
+}
+
+impl UpdateState {
+    /// Wait until a git network operation is allowed to run.
+    /// The operation may run for as long as the returned permit is alive.
+    pub async fn git_permit(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.git_semaphore {
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Make sure `host` (if any) may be contacted
+    pub fn check_host(&self, host: Option<String>) -> Result<(), HostNotAllowed> {
+        check_host(self.allowed_hosts.as_deref(), host)
+    }
+}
+
+/// Make sure `host` (if any) is one of `allowed_hosts`, if they are set
+pub fn check_host(
+    allowed_hosts: Option<&[String]>,
+    host: Option<String>,
+) -> Result<(), HostNotAllowed> {
+    match (allowed_hosts, host) {
+        (Some(allowed), Some(host)) if !allowed.iter().any(|a| a.eq_ignore_ascii_case(&host)) => {
+            Err(HostNotAllowed(host))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Host {0} is not in allowed_hosts")]
+pub struct HostNotAllowed(pub String);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(clippy::enum_variant_names)]
 #[serde(tag = "type")]
 /// Supported repository types.
 /// All repositories are fetched and pushed using git, but pull requests are submitted differently.
 pub enum RepoHandle {
     #[serde(rename = "github")]
-    /// GitHub: fetches with ssh, submits pull requests using GitHub API.
+    /// GitHub: fetches with ssh (or HTTPS), submits pull requests using GitHub API.
     GitHub {
         base_url: Option<String>,
         ssh_url: Option<String>,
         token_env_var: Option<String>,
+        /// File to read the API token from, instead of the environment variable
+        token_file: Option<PathBuf>,
         owner: String,
         repo: String,
     },
     #[serde(rename = "gitlab")]
-    /// GitLab: fetches with ssh, submits merge requests using GitLab API.
+    /// GitLab: fetches with ssh (or HTTPS), submits merge requests using GitLab API.
     GitLab {
         base_url: Option<String>,
         ssh_url: Option<String>,
         token_env_var: Option<String>,
+        /// File to read the API token from, instead of the environment variable
+        token_file: Option<PathBuf>,
         project: String,
     },
+    #[serde(rename = "gitea")]
+    /// Gitea or Forgejo: fetches with ssh (or HTTPS), submits pull requests using their API.
+    Gitea {
+        /// The web URL of the instance, e.g. `https://codeberg.org`
+        base_url: String,
+        /// `git@<host of base_url>` by default
+        ssh_url: Option<String>,
+        token_env_var: Option<String>,
+        /// File to read the API token from, instead of the environment variable
+        token_file: Option<PathBuf>,
+        owner: String,
+        repo: String,
+    },
     #[serde(rename = "git+none")]
     /// Pure git with **no pull request support**.
     /// Useful for debugging.
@@ -123,6 +675,14 @@ pub struct Repo {
     pub settings: Option<UpdateSettingsOptional>,
     #[serde(flatten)]
     pub handle: RepoHandle,
+    /// Whether the repository is updated, true if not set
+    pub enabled: Option<bool>,
+}
+
+impl Repo {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
 }
 
 impl Display for RepoHandle {
@@ -152,6 +712,22 @@ impl Display for RepoHandle {
                     project
                 )?;
             }
+            RepoHandle::Gitea {
+                owner,
+                repo,
+                ssh_url,
+                ..
+            } => {
+                write!(
+                    f,
+                    "ssh://{}/{}/{}",
+                    ssh_url
+                        .clone()
+                        .unwrap_or_else(|| format!("git@{}", self.api_host().unwrap_or_default())),
+                    owner,
+                    repo
+                )?;
+            }
             RepoHandle::GitNone { url, .. } => {
                 write!(f, "{}", url)?;
             }
@@ -159,3 +735,121 @@ impl Display for RepoHandle {
         Ok(())
     }
 }
+
+/// Host part of a URL, an scp-like `user@host:path` git remote or a bare `[user@]host[:port]`.
+/// `None` for local paths.
+pub fn url_host(url: &str) -> Option<String> {
+    let (remote, rest) = match url.split_once("://") {
+        Some((_scheme, rest)) => (true, rest),
+        None => (url.contains('@') || !url.contains('/'), url),
+    };
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    if remote && !host.is_empty() {
+        Some(host.to_lowercase())
+    } else {
+        None
+    }
+}
+
+impl RepoHandle {
+    /// Name of the repository: `owner/repo` on GitHub and Gitea, the project on GitLab,
+    /// and the URL otherwise
+    pub fn name(&self) -> String {
+        match self {
+            RepoHandle::GitHub { owner, repo, .. } | RepoHandle::Gitea { owner, repo, .. } => {
+                format!("{}/{}", owner, repo)
+            }
+            RepoHandle::GitLab { project, .. } => project.clone(),
+            RepoHandle::GitNone { url } => url.clone(),
+        }
+    }
+
+    /// URL of the repository over HTTPS, if it is on a forge or already an HTTPS URL
+    fn https_url(&self) -> Option<String> {
+        match self {
+            RepoHandle::GitHub { owner, repo, .. } => {
+                let host = match self.api_host()?.as_str() {
+                    "api.github.com" => "github.com".to_string(),
+                    host => host.to_string(),
+                };
+                Some(format!("https://{}/{}/{}.git", host, owner, repo))
+            }
+            RepoHandle::GitLab { project, .. } => {
+                Some(format!("https://{}/{}.git", self.api_host()?, project))
+            }
+            RepoHandle::Gitea {
+                base_url,
+                owner,
+                repo,
+                ..
+            } => Some(format!(
+                "{}/{}/{}.git",
+                base_url.trim_end_matches('/'),
+                owner,
+                repo
+            )),
+            RepoHandle::GitNone { url } => {
+                Some(url.clone()).filter(|url| url.starts_with("https://"))
+            }
+        }
+    }
+
+    /// URL git fetches from and pushes to: the HTTPS one with `GitAuth::Https`,
+    /// the configured (ssh, for forges) one otherwise
+    pub fn git_url(&self, auth: Option<GitAuth>) -> String {
+        match auth {
+            Some(GitAuth::Https) => self.https_url().unwrap_or_else(|| self.to_string()),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Host contacted with git
+    pub fn git_host(&self, auth: Option<GitAuth>) -> Option<String> {
+        url_host(&self.git_url(auth))
+    }
+
+    /// The repository `fork` describes, if forks are supported for this kind of repository
+    pub fn fork(&self, fork: &Fork) -> Option<RepoHandle> {
+        match self.clone() {
+            RepoHandle::GitHub {
+                base_url,
+                ssh_url,
+                token_env_var,
+                token_file,
+                repo,
+                ..
+            } => Some(RepoHandle::GitHub {
+                base_url,
+                ssh_url: fork.ssh_url.clone().or(ssh_url),
+                token_env_var,
+                token_file,
+                owner: fork.owner.clone(),
+                repo: fork.repo.clone().unwrap_or(repo),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Username that goes with the API token when authenticating over HTTPS
+    pub fn https_username(&self) -> &'static str {
+        match self {
+            RepoHandle::GitHub { .. } => "x-access-token",
+            _ => "oauth2",
+        }
+    }
+
+    /// Host of the forge API, if any
+    pub fn api_host(&self) -> Option<String> {
+        match self {
+            RepoHandle::GitHub { base_url, .. } => {
+                url_host(base_url.as_deref().unwrap_or("https://api.github.com"))
+            }
+            RepoHandle::GitLab { base_url, .. } => {
+                url_host(base_url.as_deref().unwrap_or("gitlab.com"))
+            }
+            RepoHandle::Gitea { base_url, .. } => url_host(base_url),
+            RepoHandle::GitNone { .. } => None,
+        }
+    }
+}
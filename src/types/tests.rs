@@ -0,0 +1,19 @@
+// SPDX-FileCopyrightText: 2021 Serokell <https://serokell.io>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+#[test]
+fn zero_cooldown_is_rejected() {
+    assert!(matches!(
+        validate_cooldown(0),
+        Err(UpdateSettingsError::InvalidField(field, _)) if field == "cooldown"
+    ));
+}
+
+#[test]
+fn short_cooldown_is_accepted() {
+    assert_eq!(validate_cooldown(1).unwrap(), Duration::from_millis(1));
+    assert_eq!(validate_cooldown(999).unwrap(), Duration::from_millis(999));
+}